@@ -0,0 +1,603 @@
+use mysql::{
+    from_row_opt,
+    prelude::{FromRow, Queryable},
+    Conn, Opts, OptsBuilder, Row,
+};
+use std::{thread, time::Duration};
+
+use crate::{config, error::SchedulerError, messages, retries};
+
+/// Connection to the ProxySQL admin interface opened the first time it is used, see `AdminConnection::connect_later`.
+pub struct LazyConnection {
+    opener: Option<BackendOpener>,
+    conn: Option<AdminConnection>,
+}
+
+impl LazyConnection {
+    /// Gets the connection, opening it on the first call. It is only tried once, a failure is logged as a warning.
+    ///
+    /// # Returns
+    ///
+    /// The connection to ProxySQL, None if it failed to open.
+    pub fn get(&mut self) -> Option<&mut AdminConnection> {
+        if let Some(opener) = self.opener.take() {
+            match opener() {
+                Ok(backend) => self.conn = Some(AdminConnection { backend }),
+                Err(err) => messages::print_warning(
+                    format!("Failed to open another connection to ProxySQL: {}", err).as_str(),
+                ),
+            }
+        }
+        self.conn.as_mut()
+    }
+}
+
+/// Client errors of a connection to a server that went away, or was lost during a statement.
+const CR_SERVER_GONE_ERROR: u16 = 2006;
+const CR_SERVER_LOST: u16 = 2013;
+
+/// Statements on the ProxySQL admin interface, run by a MySQL connection, or by a fake in the tests.
+pub trait AdminBackend {
+    /// Runs a query and returns all its rows.
+    fn query_rows(&mut self, query: &str) -> mysql::Result<Vec<Row>>;
+
+    /// Runs a statement and drops its result.
+    fn query_drop(&mut self, statement: &str) -> mysql::Result<()>;
+
+    /// Runs a query and returns its rows as they are read, without holding them all in memory.
+    /// No other statement can run until the rows are dropped.
+    fn query_stream(
+        &mut self,
+        query: &str,
+    ) -> mysql::Result<Box<dyn Iterator<Item = mysql::Result<Row>> + '_>>;
+
+    /// Runs a query and returns the names of its columns, without reading its rows.
+    fn column_names(&mut self, query: &str) -> mysql::Result<Vec<String>>;
+
+    /// Replaces the connection, after it was lost.
+    fn reconnect(&mut self) -> mysql::Result<()>;
+
+    /// Returns how to open another connection to the same admin interface, once it is needed.
+    fn opener(&self) -> BackendOpener;
+}
+
+/// Opens a connection to the ProxySQL admin interface, see `AdminBackend::opener`.
+pub type BackendOpener = Box<dyn FnOnce() -> mysql::Result<Box<dyn AdminBackend>>>;
+
+/// MySQL connection to the ProxySQL admin interface, with the options to open it again.
+struct ConnBackend {
+    conn: Conn,
+    opts: Opts,
+}
+
+impl AdminBackend for ConnBackend {
+    fn query_rows(&mut self, query: &str) -> mysql::Result<Vec<Row>> {
+        self.conn.query(query)
+    }
+
+    fn query_drop(&mut self, statement: &str) -> mysql::Result<()> {
+        self.conn.query_drop(statement)
+    }
+
+    fn query_stream(
+        &mut self,
+        query: &str,
+    ) -> mysql::Result<Box<dyn Iterator<Item = mysql::Result<Row>> + '_>> {
+        Ok(Box::new(self.conn.query_iter(query)?))
+    }
+
+    fn column_names(&mut self, query: &str) -> mysql::Result<Vec<String>> {
+        let result = self.conn.query_iter(query)?;
+        let columns = result
+            .columns()
+            .as_ref()
+            .iter()
+            .map(|column| column.name_str().to_string())
+            .collect();
+        Ok(columns)
+    }
+
+    fn reconnect(&mut self) -> mysql::Result<()> {
+        self.conn = Conn::new(self.opts.clone())?;
+        Ok(())
+    }
+
+    fn opener(&self) -> BackendOpener {
+        let opts = self.opts.clone();
+        Box::new(move || {
+            let backend: Box<dyn AdminBackend> = Box::new(ConnBackend {
+                conn: Conn::new(opts.clone())?,
+                opts,
+            });
+            Ok(backend)
+        })
+    }
+}
+
+/// Connection to the ProxySQL admin interface that reconnects once when the connection was dropped,
+/// such as by an idle timeout during a long run, and retries the statement on the new connection.
+/// Only reads and statements that can be applied twice are retried, see `query_drop_once` for the others.
+pub struct AdminConnection {
+    backend: Box<dyn AdminBackend>,
+}
+
+impl AdminConnection {
+    /// Connects to the ProxySQL admin interface.
+    /// Failed attempts are retried up to proxysql_connect_attempts times, waiting proxysql_connect_retry_delay_s
+    /// between them, so a ProxySQL restart doesn't fail the run. Each retry is taken from the max_total_retries budget.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - A reference to a config::Config containing the configuration for the ProxySQL connection.
+    ///
+    /// # Returns
+    ///
+    /// The connection to ProxySQL, or the error of the last attempt.
+    pub fn connect(config: &config::Config) -> Result<Self, SchedulerError> {
+        let opts = connection_opts(config);
+        let attempts = config.proxysql_connect_attempts.unwrap_or(1).max(1);
+        let delay = Duration::from_secs(config.proxysql_connect_retry_delay_s.unwrap_or(1));
+        let mut attempt = 1;
+        loop {
+            match Conn::new(opts.clone()) {
+                Ok(conn) => return Ok(AdminConnection::new(ConnBackend { conn, opts })),
+                Err(err) if attempt < attempts && retries::try_consume() => {
+                    messages::print_warning(
+                        format!(
+                            "Failed to connect to ProxySQL, attempt {} of {}: {}",
+                            attempt, attempts, err
+                        )
+                        .as_str(),
+                    );
+                    attempt += 1;
+                    thread::sleep(delay);
+                }
+                Err(err) => return Err(SchedulerError::Connection(err)),
+            }
+        }
+    }
+
+    /// This function is used to create a connection running its statements on the given backend.
+    ///
+    /// # Arguments
+    ///
+    /// * `backend` - The backend running the statements.
+    ///
+    /// # Returns
+    ///
+    /// The connection to ProxySQL.
+    pub fn new(backend: impl AdminBackend + 'static) -> Self {
+        AdminConnection {
+            backend: Box::new(backend),
+        }
+    }
+
+    /// Runs a statement, reconnecting and running it again if the connection was lost.
+    /// The reconnection is taken from the max_total_retries budget.
+    ///
+    /// # Arguments
+    ///
+    /// * `statement` - A function running the statement on a backend.
+    ///
+    /// # Returns
+    ///
+    /// The result of the statement.
+    fn run<T>(
+        &mut self,
+        statement: impl Fn(&mut dyn AdminBackend) -> mysql::Result<T>,
+    ) -> Result<T, SchedulerError> {
+        match statement(self.backend.as_mut()) {
+            Err(err) if is_connection_lost(&err) && retries::try_consume() => {
+                messages::print_warning(
+                    format!("Lost the connection to ProxySQL, reconnecting: {}", err).as_str(),
+                );
+                self.backend
+                    .reconnect()
+                    .map_err(SchedulerError::Connection)?;
+                statement(self.backend.as_mut()).map_err(SchedulerError::ProxySQL)
+            }
+            result => result.map_err(SchedulerError::ProxySQL),
+        }
+    }
+
+    pub fn query_drop(&mut self, statement: impl AsRef<str>) -> Result<(), SchedulerError> {
+        self.run(|backend| backend.query_drop(statement.as_ref()))
+    }
+
+    /// Runs a statement that must not be applied twice, such as an INSERT, without retrying it if the
+    /// connection was lost. The caller checks if it was applied before running it again.
+    ///
+    /// # Arguments
+    ///
+    /// * `statement` - The statement to run.
+    pub fn query_drop_once(&mut self, statement: impl AsRef<str>) -> Result<(), SchedulerError> {
+        self.backend
+            .query_drop(statement.as_ref())
+            .map_err(SchedulerError::ProxySQL)
+    }
+
+    pub fn query<T: FromRow>(&mut self, query: impl AsRef<str>) -> Result<Vec<T>, SchedulerError> {
+        let rows = self.run(|backend| backend.query_rows(query.as_ref()))?;
+        rows.into_iter()
+            .map(|row| {
+                from_row_opt(row)
+                    .map_err(|err| SchedulerError::ProxySQL(mysql::Error::FromRowError(err.0)))
+            })
+            .collect()
+    }
+
+    /// Runs a query and returns its rows one at a time as they are read, so a large result is not held in memory.
+    /// The connection can't run other statements until the rows are dropped, see `connect_later`.
+    /// Rows already read can't be read again, so the query is not retried: the connection is checked first,
+    /// reconnecting if it was lost.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The query to run.
+    ///
+    /// # Returns
+    ///
+    /// The rows of the query.
+    pub fn query_iter<T: FromRow>(
+        &mut self,
+        query: impl AsRef<str>,
+    ) -> Result<impl Iterator<Item = Result<T, SchedulerError>> + '_, SchedulerError> {
+        self.query_drop("SELECT 1")?;
+        let rows = self
+            .backend
+            .query_stream(query.as_ref())
+            .map_err(SchedulerError::ProxySQL)?;
+        Ok(rows.map(|row| {
+            row.map_err(SchedulerError::ProxySQL).and_then(|row| {
+                from_row_opt(row)
+                    .map_err(|err| SchedulerError::ProxySQL(mysql::Error::FromRowError(err.0)))
+            })
+        }))
+    }
+
+    /// Prepares another connection to the same ProxySQL admin interface, such as to run statements while the
+    /// rows of `query_iter` are read. It is only opened the first time it is used.
+    ///
+    /// # Returns
+    ///
+    /// The connection to ProxySQL, not opened yet.
+    pub fn connect_later(&self) -> LazyConnection {
+        LazyConnection {
+            opener: Some(self.backend.opener()),
+            conn: None,
+        }
+    }
+
+    pub fn query_first<T: FromRow>(
+        &mut self,
+        query: impl AsRef<str>,
+    ) -> Result<Option<T>, SchedulerError> {
+        Ok(self.query(query)?.into_iter().next())
+    }
+
+    pub fn column_names(&mut self, query: impl AsRef<str>) -> Result<Vec<String>, SchedulerError> {
+        self.run(|backend| backend.column_names(query.as_ref()))
+    }
+}
+
+/// Builds the connection options of the ProxySQL admin interface.
+///
+/// # Arguments
+///
+/// * `config` - A reference to a config::Config containing the configuration for the ProxySQL connection.
+///
+/// # Returns
+///
+/// The connection options of ProxySQL.
+fn connection_opts(config: &config::Config) -> Opts {
+    OptsBuilder::new()
+        .ip_or_hostname(Some(config.proxysql_host.as_str()))
+        .tcp_port(config.proxysql_port)
+        .user(Some(config.proxysql_user.as_str()))
+        .pass(Some(config.proxysql_password.as_str()))
+        .prefer_socket(false)
+        .connect_attrs(Some(config.connect_attrs()))
+        .into()
+}
+
+/// Checks if an error means the connection was dropped, such as when ProxySQL closes an idle admin connection
+/// or restarts during a long run.
+///
+/// # Arguments
+///
+/// * `err` - The error of a statement.
+///
+/// # Returns
+///
+/// true if the connection is lost and the statement can be retried on a new connection.
+pub fn is_connection_lost(err: &mysql::Error) -> bool {
+    match err {
+        mysql::Error::IoError(_) | mysql::Error::CodecError(_) => true,
+        mysql::Error::MySqlError(err) => {
+            err.code == CR_SERVER_GONE_ERROR || err.code == CR_SERVER_LOST
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+pub mod fake {
+    use super::AdminBackend;
+    use mysql::{consts::ColumnType, Column, Row, Value};
+    use std::sync::{Arc, Mutex};
+
+    /// Canned response of the fake backend to the statements containing a pattern.
+    #[derive(Clone)]
+    struct Response {
+        pattern: String,
+        columns: Vec<String>,
+        rows: Vec<Vec<Value>>,
+    }
+
+    #[derive(Default)]
+    struct State {
+        responses: Vec<Response>,
+        /// Patterns of the statements that fail once with a lost connection, after being applied
+        failures: Vec<String>,
+        /// Patterns of the statements that always fail with a server error, and its message
+        errors: Vec<(String, String)>,
+        statements: Vec<String>,
+        reconnects: u32,
+        /// Connections opened with `AdminBackend::opener`
+        opened: u32,
+        /// Whether `AdminBackend::opener` fails to open a connection
+        refuse_connections: bool,
+        /// Actions run after the statements containing their pattern
+        actions: Vec<(String, Arc<dyn Fn() + Send + Sync>)>,
+    }
+
+    /// Fake ProxySQL admin interface, answering statements with canned rows and recording them.
+    /// Clones share their state, so a test keeps a clone to check the statements run.
+    #[derive(Clone, Default)]
+    pub struct FakeBackend {
+        state: Arc<Mutex<State>>,
+    }
+
+    impl FakeBackend {
+        /// Answers the statements containing `pattern` with `rows`. When several responses match a statement,
+        /// the first one is used and removed, unless it is the last one for its pattern.
+        pub fn respond(&self, pattern: &str, columns: &[&str], rows: Vec<Vec<Value>>) -> &Self {
+            self.state.lock().unwrap().responses.push(Response {
+                pattern: pattern.to_string(),
+                columns: columns.iter().map(|column| column.to_string()).collect(),
+                rows,
+            });
+            self
+        }
+
+        /// Fails the next statement containing `pattern` with a lost connection, after recording it,
+        /// as when the connection drops before the result of an applied statement is read.
+        pub fn lose_connection_on(&self, pattern: &str) -> &Self {
+            self.state
+                .lock()
+                .unwrap()
+                .failures
+                .push(pattern.to_string());
+            self
+        }
+
+        /// Fails every statement containing `pattern` with a server error, such as a rejected statement.
+        pub fn fail_on(&self, pattern: &str, message: &str) -> &Self {
+            self.state
+                .lock()
+                .unwrap()
+                .errors
+                .push((pattern.to_string(), message.to_string()));
+            self
+        }
+
+        /// Runs `action` after each statement containing `pattern`, such as to change the state of the scheduler mid-run.
+        pub fn on_statement(
+            &self,
+            pattern: &str,
+            action: impl Fn() + Send + Sync + 'static,
+        ) -> &Self {
+            self.state
+                .lock()
+                .unwrap()
+                .actions
+                .push((pattern.to_string(), Arc::new(action)));
+            self
+        }
+
+        /// Fails to open the connections prepared with `AdminBackend::opener`, as when ProxySQL is briefly unreachable.
+        pub fn refuse_connections(&self) -> &Self {
+            self.state.lock().unwrap().refuse_connections = true;
+            self
+        }
+
+        /// Gets the number of connections opened with `AdminBackend::opener`.
+        pub fn connections_opened(&self) -> u32 {
+            self.state.lock().unwrap().opened
+        }
+
+        /// Gets the number of reconnections.
+        pub fn reconnects(&self) -> u32 {
+            self.state.lock().unwrap().reconnects
+        }
+
+        /// Gets the statements run, in order, including the failed ones.
+        pub fn statements(&self) -> Vec<String> {
+            self.state.lock().unwrap().statements.clone()
+        }
+
+        /// Gets the statements run containing `pattern`.
+        pub fn statements_containing(&self, pattern: &str) -> Vec<String> {
+            self.statements()
+                .into_iter()
+                .filter(|statement| statement.contains(pattern))
+                .collect()
+        }
+
+        fn run(&mut self, statement: &str) -> mysql::Result<Option<Response>> {
+            let result = self.respond_to(statement);
+            let actions: Vec<Arc<dyn Fn() + Send + Sync>> = self
+                .state
+                .lock()
+                .unwrap()
+                .actions
+                .iter()
+                .filter(|(pattern, _)| statement.contains(pattern.as_str()))
+                .map(|(_, action)| action.clone())
+                .collect();
+            for action in actions {
+                action();
+            }
+            result
+        }
+
+        fn respond_to(&mut self, statement: &str) -> mysql::Result<Option<Response>> {
+            let mut state = self.state.lock().unwrap();
+            state.statements.push(statement.to_string());
+            if let Some(failure) = state
+                .failures
+                .iter()
+                .position(|pattern| statement.contains(pattern.as_str()))
+            {
+                state.failures.remove(failure);
+                return Err(mysql::Error::IoError(std::io::Error::new(
+                    std::io::ErrorKind::ConnectionReset,
+                    "connection reset by fake ProxySQL",
+                )));
+            }
+            if let Some((_, message)) = state
+                .errors
+                .iter()
+                .find(|(pattern, _)| statement.contains(pattern.as_str()))
+            {
+                return Err(mysql::Error::MySqlError(mysql::MySqlError {
+                    state: "HY000".to_string(),
+                    message: message.clone(),
+                    code: 1105,
+                }));
+            }
+            let Some(index) = state
+                .responses
+                .iter()
+                .position(|response| statement.contains(response.pattern.as_str()))
+            else {
+                return Ok(None);
+            };
+            let pattern = state.responses[index].pattern.clone();
+            let remaining = state
+                .responses
+                .iter()
+                .filter(|response| response.pattern == pattern)
+                .count();
+            Ok(Some(match remaining {
+                1 => state.responses[index].clone(),
+                _ => state.responses.remove(index),
+            }))
+        }
+    }
+
+    impl AdminBackend for FakeBackend {
+        fn query_rows(&mut self, query: &str) -> mysql::Result<Vec<Row>> {
+            let Some(response) = self.run(query)? else {
+                return Ok(Vec::new());
+            };
+            let columns: Arc<[Column]> = response
+                .columns
+                .iter()
+                .map(|name| {
+                    Column::new(ColumnType::MYSQL_TYPE_VAR_STRING).with_name(name.as_bytes())
+                })
+                .collect();
+            Ok(response
+                .rows
+                .into_iter()
+                .map(|values| mysql_common::row::new_row(values, columns.clone()))
+                .collect())
+        }
+
+        fn query_drop(&mut self, statement: &str) -> mysql::Result<()> {
+            self.run(statement).map(|_| ())
+        }
+
+        fn query_stream(
+            &mut self,
+            query: &str,
+        ) -> mysql::Result<Box<dyn Iterator<Item = mysql::Result<Row>> + '_>> {
+            Ok(Box::new(self.query_rows(query)?.into_iter().map(Ok)))
+        }
+
+        fn column_names(&mut self, query: &str) -> mysql::Result<Vec<String>> {
+            Ok(self
+                .run(query)?
+                .map(|response| response.columns)
+                .unwrap_or_default())
+        }
+
+        fn reconnect(&mut self) -> mysql::Result<()> {
+            self.state.lock().unwrap().reconnects += 1;
+            Ok(())
+        }
+
+        fn opener(&self) -> super::BackendOpener {
+            let fake = self.clone();
+            Box::new(move || {
+                let mut state = fake.state.lock().unwrap();
+                if state.refuse_connections {
+                    return Err(mysql::Error::IoError(std::io::Error::new(
+                        std::io::ErrorKind::ConnectionRefused,
+                        "connection refused by fake ProxySQL",
+                    )));
+                }
+                state.opened += 1;
+                drop(state);
+                let backend: Box<dyn AdminBackend> = Box::new(fake);
+                Ok(backend)
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fake::FakeBackend, AdminConnection};
+    use mysql::Value;
+
+    #[test]
+    fn query_converts_rows() {
+        let fake = FakeBackend::default();
+        fake.respond(
+            "FROM mysql_query_rules",
+            &["rule_id", "digest"],
+            vec![vec![Value::from("7"), Value::from("0x1234")]],
+        );
+        let mut conn = AdminConnection::new(fake.clone());
+        let rows: Vec<(u32, String)> = conn
+            .query("SELECT rule_id, digest FROM mysql_query_rules")
+            .unwrap();
+        assert_eq!(rows, vec![(7, "0x1234".to_string())]);
+    }
+
+    #[test]
+    fn lost_connection_is_reconnected_and_read_retried() {
+        let fake = FakeBackend::default();
+        fake.respond("SELECT 1", &["1"], vec![vec![Value::from("1")]])
+            .lose_connection_on("SELECT 1");
+        let mut conn = AdminConnection::new(fake.clone());
+        let one: Option<u32> = conn.query_first("SELECT 1").unwrap();
+        assert_eq!(one, Some(1));
+        assert_eq!(fake.reconnects(), 1);
+        assert_eq!(fake.statements_containing("SELECT 1").len(), 2);
+    }
+
+    #[test]
+    fn query_drop_once_is_not_retried() {
+        let fake = FakeBackend::default();
+        fake.lose_connection_on("INSERT");
+        let mut conn = AdminConnection::new(fake.clone());
+        assert!(conn
+            .query_drop_once("INSERT INTO mysql_query_rules (rule_id) VALUES (1)")
+            .is_err());
+        assert_eq!(fake.reconnects(), 0);
+        assert_eq!(fake.statements_containing("INSERT").len(), 1);
+    }
+}
@@ -1,10 +1,53 @@
 use std::{
+    collections::HashMap,
     fmt::{Display, Formatter},
     fs::File,
     io::Read,
+    path::Path,
 };
 
-use crate::messages::MessageType;
+use crate::{error::SchedulerError, messages::MessageType};
+
+/// Prefix of the environment variables that override values from the config file.
+/// For example, `READYSET_SCHEDULER_PROXYSQL_PASSWORD` overrides `proxysql_password`.
+const ENV_OVERRIDE_PREFIX: &str = "READYSET_SCHEDULER_";
+
+/// `Config` fields read from a string, the `String` and enum fields, so their environment variable overrides
+/// are kept as is. A numeric looking password stays a string.
+const STRING_FIELDS: [&str; 23] = [
+    "scheduler_id",
+    "proxysql_user",
+    "proxysql_password",
+    "proxysql_host",
+    "proxysql_dataplane_host",
+    "support_check_host",
+    "readyset_user",
+    "readyset_password",
+    "promotion_mode",
+    "status_change_webhook",
+    "support_consensus",
+    "hostgroup_check",
+    "mirror_comment_token",
+    "destination_comment_token",
+    "lock_file",
+    "operation_mode",
+    "query_discovery_mode",
+    "query_discovery_digest_file",
+    "reconcile_action",
+    "log_verbosity",
+    "log_file",
+    "otel_endpoint",
+    "query_rule_comment",
+];
+
+/// Credentials that can be read from a file with the `<field>_file` option instead of set inline.
+const PASSWORD_FILE_FIELDS: [&str; 2] = ["proxysql_password", "readyset_password"];
+
+/// Name of the scheduler reported in the connection attributes.
+const PROGRAM_NAME: &str = "readyset_proxysql_scheduler";
+
+/// Lock file used when `lock_file` is not configured.
+pub const DEFAULT_LOCK_FILE: &str = "/tmp/readyset_scheduler.lock";
 
 #[derive(serde::Deserialize, Clone, Copy, PartialEq, PartialOrd, Default, Debug)]
 pub enum OperationMode {
@@ -25,6 +68,24 @@ impl From<String> for OperationMode {
     }
 }
 
+impl OperationMode {
+    /// Checks if the mode runs the health check of the Readyset hosts.
+    pub fn runs_health_check(&self) -> bool {
+        match self {
+            OperationMode::HealthCheck | OperationMode::All => true,
+            OperationMode::QueryDiscovery => false,
+        }
+    }
+
+    /// Checks if the mode runs the query discovery.
+    pub fn runs_query_discovery(&self) -> bool {
+        match self {
+            OperationMode::QueryDiscovery | OperationMode::All => true,
+            OperationMode::HealthCheck => false,
+        }
+    }
+}
+
 impl Display for OperationMode {
     fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
         match self {
@@ -48,6 +109,7 @@ pub enum QueryDiscoveryMode {
     WorstWorstCase,
     DistanceMeanMax,
     External,
+    Composite,
 }
 
 impl From<String> for QueryDiscoveryMode {
@@ -63,39 +125,577 @@ impl From<String> for QueryDiscoveryMode {
             "worst_worst_case" => QueryDiscoveryMode::WorstWorstCase,
             "distance_mean_max" => QueryDiscoveryMode::DistanceMeanMax,
             "external" => QueryDiscoveryMode::External,
+            "composite" => QueryDiscoveryMode::Composite,
             _ => QueryDiscoveryMode::CountStar,
         }
     }
 }
 
+/// What to do with a destination rule whose cache is missing on an online Readyset host.
+#[derive(serde::Deserialize, Clone, Copy, PartialEq, Default, Debug)]
+pub enum ReconcileAction {
+    /// Create the cache again on the hosts missing it
+    Recreate,
+    /// Remove the rule, so the query is discovered and cached again
+    RemoveRule,
+    /// Only report the drift
+    #[default]
+    ReportOnly,
+}
+
+impl Display for ReconcileAction {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            ReconcileAction::Recreate => write!(f, "Recreate"),
+            ReconcileAction::RemoveRule => write!(f, "RemoveRule"),
+            ReconcileAction::ReportOnly => write!(f, "ReportOnly"),
+        }
+    }
+}
+
+/// How the support checks of the online Readyset hosts of a hostgroup are combined to decide if a query is supported.
+#[derive(serde::Deserialize, Clone, Copy, PartialEq, Default, Debug)]
+pub enum SupportConsensus {
+    /// Only the support check host, or the first online host, is asked
+    #[default]
+    Any,
+    /// Every online host has to support the query
+    All,
+    /// More than half of the online hosts have to support the query
+    Majority,
+}
+
+/// Who promotes the mirror query rules to destination once their warmup is done.
+#[derive(serde::Deserialize, Clone, Copy, PartialEq, Default, Debug)]
+pub enum PromotionMode {
+    /// The scheduler promotes the rules
+    #[default]
+    Auto,
+    /// The scheduler only adds mirror rules and reports the ones ready to be promoted by an operator
+    Manual,
+}
+
+/// What to do when the source hostgroup or a Readyset hostgroup has no server in ProxySQL.
+#[derive(serde::Deserialize, Clone, Copy, PartialEq, Default, Debug)]
+pub enum HostgroupCheck {
+    /// Don't check the hostgroups
+    Off,
+    /// Print a warning and run anyway
+    #[default]
+    Warn,
+    /// Fail the run with a config error
+    Error,
+}
+
+/// Replacement applied to the digest text of a query to turn ProxySQL digest artifacts into valid SQL.
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct PlaceholderRule {
+    pub from: String,
+    pub to: String,
+    /// Whether `from` is a regular expression, otherwise it is matched literally
+    #[serde(default)]
+    pub regex: bool,
+}
+
 #[derive(serde::Deserialize, Clone, Debug)]
 pub struct Config {
+    /// Path of the config file, set once the file is parsed
+    #[serde(skip)]
+    pub config_file: String,
+    /// Log each placeholder rule applied to a digest text, set by --trace-normalization
+    #[serde(skip)]
+    pub trace_normalization: bool,
+    pub scheduler_id: Option<String>,
     pub proxysql_user: String,
     pub proxysql_password: String,
     pub proxysql_host: String,
     pub proxysql_port: u16,
+    pub proxysql_connect_attempts: Option<u32>,
+    pub proxysql_connect_retry_delay_s: Option<u64>,
+    pub max_total_retries: Option<u32>,
+    pub proxysql_dataplane_host: Option<String>,
+    pub proxysql_dataplane_port: Option<u16>,
+    pub support_check_host: Option<String>,
+    pub support_check_port: Option<u16>,
     pub readyset_user: String,
     pub readyset_password: String,
+    pub connect_timeout_s: Option<u64>,
+    pub read_timeout_s: Option<u64>,
+    pub write_timeout_s: Option<u64>,
+    pub cache_creation_timeout_s: Option<u64>,
+    pub readyset_pool_size: Option<usize>,
     pub source_hostgroup: u16,
     pub readyset_hostgroup: u16,
+    pub readyset_hostgroups: Option<Vec<u16>>,
+    pub readyset_hostgroup_schemas: Option<HashMap<String, u16>>,
     pub warmup_time_s: Option<u16>,
+    pub promotion_mode: Option<PromotionMode>,
+    pub cache_offline_soft_hosts: Option<bool>,
+    pub respect_manual_offline: Option<bool>,
+    pub mark_managed_servers: Option<bool>,
+    pub max_flaps_per_hour: Option<u32>,
+    pub status_change_webhook: Option<String>,
+    pub support_consensus: Option<SupportConsensus>,
+    pub hostgroup_check: Option<HostgroupCheck>,
+    pub recache_cooldown_s: Option<u64>,
+    pub readyset_admin_port: Option<u16>,
+    pub mirror_comment_token: Option<String>,
+    pub destination_comment_token: Option<String>,
     pub lock_file: Option<String>,
+    pub state_compression: Option<bool>,
     pub operation_mode: Option<OperationMode>,
     pub number_of_queries: u16,
     pub query_discovery_mode: Option<QueryDiscoveryMode>,
     pub query_discovery_min_execution: Option<u64>,
     pub query_discovery_min_row_sent: Option<u64>,
+    pub query_discovery_max_rows_sent: Option<u64>,
+    pub query_discovery_min_age_s: Option<u64>,
+    pub query_discovery_max_idle_s: Option<u64>,
+    pub query_discovery_digest_text_filter: Option<Vec<String>>,
+    pub query_discovery_include_cte: Option<bool>,
+    pub discovery_usernames: Option<Vec<String>>,
+    pub query_discovery_digest_file: Option<String>,
+    pub query_discovery_max_complexity: Option<u32>,
+    pub query_discovery_min_tables: Option<u32>,
+    pub cache_concrete_queries: Option<bool>,
+    pub cache_batch_size: Option<u32>,
+    pub cache_batch_sleep_ms: Option<u64>,
+    pub max_total_caches: Option<u32>,
+    pub max_total_caches_evict: Option<bool>,
+    pub query_discovery_persist_offset: Option<bool>,
+    pub query_discovery_placeholder_rules: Option<Vec<PlaceholderRule>>,
+    pub query_discovery_composite_weights: Option<HashMap<String, f64>>,
+    pub unsupported_recheck_s: Option<u64>,
+    pub reconcile_action: Option<ReconcileAction>,
     pub log_verbosity: Option<MessageType>,
+    pub log_file: Option<String>,
+    pub log_max_size_mb: Option<u64>,
+    pub log_keep_files: Option<u32>,
+    pub log_file_only: Option<bool>,
+    pub log_aggregate_repeated: Option<bool>,
+    pub otel_endpoint: Option<String>,
+    pub verify_servers_load_timeout_s: Option<u16>,
+    pub max_replication_lag_s: Option<u64>,
+    pub drain_time_s: Option<u64>,
+    pub readyset_startup_grace_s: Option<u64>,
+    pub max_runtime_s: Option<u64>,
+    pub startup_jitter_max_ms: Option<u64>,
+    pub remove_dropped_schema_queries: Option<bool>,
+    pub observe_first_runs: Option<u64>,
+    pub query_rule_flag_in: Option<u32>,
+    pub query_rule_flag_out: Option<u32>,
+    pub query_rule_apply: Option<bool>,
+    pub query_rule_comment: Option<String>,
+    pub query_rule_comment_max_length: Option<usize>,
+    pub query_rule_base_id: Option<u32>,
+    pub reset_stats_after_run: Option<bool>,
+    pub stats_reset_confirm: Option<bool>,
+    pub verify_cache_before_promote: Option<bool>,
+    pub promote_when_cache_ready: Option<bool>,
+    pub min_mirror_hits: Option<u64>,
+    pub persist_to_disk: Option<bool>,
 }
 
-pub fn read_config_file(path: &str) -> Result<String, std::io::Error> {
-    let mut file =
-        File::open(path).unwrap_or_else(|_| panic!("Failed to open config file at path {}", path));
+impl Config {
+    /// Describes this scheduler instance by its config file and scheduler_id, if set.
+    ///
+    /// # Returns
+    ///
+    /// A description such as `config=/etc/readyset_proxysql_scheduler.cnf scheduler_id=main`.
+    pub fn instance_name(&self) -> String {
+        match &self.scheduler_id {
+            Some(scheduler_id) => {
+                format!("config={} scheduler_id={}", self.config_file, scheduler_id)
+            }
+            None => format!("config={}", self.config_file),
+        }
+    }
+
+    /// Builds the attributes sent when connecting to ProxySQL and Readyset, so a connection can be traced
+    /// back to this scheduler instance.
+    ///
+    /// # Returns
+    ///
+    /// The connection attributes, with the instance name in `program_name`.
+    pub fn connect_attrs(&self) -> HashMap<String, String> {
+        HashMap::from([(
+            "program_name".to_string(),
+            format!("{} {}", PROGRAM_NAME, self.instance_name()),
+        )])
+    }
+}
+
+pub fn read_config_file(path: &str) -> Result<String, SchedulerError> {
     let mut contents = String::new();
-    file.read_to_string(&mut contents)?;
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut contents))
+        .map_err(|err| SchedulerError::Config(err.to_string()))?;
     Ok(contents)
 }
 
-pub fn parse_config_file(contents: &str) -> Result<Config, toml::de::Error> {
-    toml::from_str(contents)
+/// Format of the config file.
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq, Default, Debug)]
+pub enum ConfigFormat {
+    #[default]
+    Toml,
+    Json,
+    Yaml,
+}
+
+impl ConfigFormat {
+    /// Detects the format of the config file from its extension: `.json` for JSON, `.yaml` or `.yml`
+    /// for YAML and TOML for any other extension.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the config file.
+    ///
+    /// # Returns
+    ///
+    /// The format of the config file.
+    pub fn from_path(path: &str) -> ConfigFormat {
+        match Path::new(path)
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_lowercase())
+            .as_deref()
+        {
+            Some("json") => ConfigFormat::Json,
+            Some("yaml") | Some("yml") => ConfigFormat::Yaml,
+            _ => ConfigFormat::Toml,
+        }
+    }
+}
+
+/// Parses the config file contents and applies any `READYSET_SCHEDULER_<FIELD>` environment
+/// variable overrides on top of it. Environment variables take precedence over the file.
+/// JSON and YAML files are read into the same table as TOML files, so the fields and overrides are the same.
+/// Their null values, such as `null` in JSON or `~` in YAML, leave the field unset.
+pub fn parse_config_file(contents: &str, format: ConfigFormat) -> Result<Config, SchedulerError> {
+    let mut table: toml::Table = match format {
+        ConfigFormat::Toml => toml::from_str(contents).map_err(|err| err.to_string()),
+        ConfigFormat::Json => serde_json::from_str::<serde_json::Value>(contents)
+            .map_err(|err| err.to_string())
+            .and_then(table_without_nulls),
+        ConfigFormat::Yaml => serde_yaml::from_str::<serde_yaml::Value>(contents)
+            .map_err(|err| err.to_string())
+            .and_then(|value| serde_json::to_value(value).map_err(|err| err.to_string()))
+            .and_then(table_without_nulls),
+    }
+    .map_err(SchedulerError::Config)?;
+    apply_env_overrides(&mut table, std::env::vars());
+    apply_password_files(&mut table)?;
+    toml::Value::Table(table)
+        .try_into()
+        .map_err(|err: toml::de::Error| SchedulerError::Config(err.to_string()))
+}
+
+/// Converts a JSON or YAML config into a TOML table, dropping the null values as TOML has none.
+///
+/// # Arguments
+///
+/// * `value` - The parsed config file.
+///
+/// # Returns
+///
+/// The config as a TOML table, an error if it is not a map of fields.
+fn table_without_nulls(mut value: serde_json::Value) -> Result<toml::Table, String> {
+    fn drop_nulls(value: &mut serde_json::Value) {
+        match value {
+            serde_json::Value::Object(fields) => {
+                fields.retain(|_, value| !value.is_null());
+                fields.values_mut().for_each(drop_nulls);
+            }
+            serde_json::Value::Array(values) => values.iter_mut().for_each(drop_nulls),
+            _ => (),
+        }
+    }
+    drop_nulls(&mut value);
+    match toml::Value::try_from(value).map_err(|err| err.to_string())? {
+        toml::Value::Table(table) => Ok(table),
+        _ => Err("the config must be a map of fields".to_string()),
+    }
+}
+
+/// Overrides the values in `table` with the environment variables starting with `READYSET_SCHEDULER_`.
+/// The remainder of the variable name, lowercased, is the config field to override.
+///
+/// # Arguments
+///
+/// * `table` - The parsed config file.
+/// * `vars` - The environment variables as (name, value) pairs.
+fn apply_env_overrides(table: &mut toml::Table, vars: impl Iterator<Item = (String, String)>) {
+    for (name, raw) in vars {
+        let field = match name.strip_prefix(ENV_OVERRIDE_PREFIX) {
+            Some(field) if !field.is_empty() => field.to_lowercase(),
+            _ => continue,
+        };
+        let is_string = STRING_FIELDS.contains(&field.as_str())
+            || field
+                .strip_suffix("_file")
+                .is_some_and(|field| PASSWORD_FILE_FIELDS.contains(&field));
+        let value = env_override_value(&raw, is_string);
+        table.insert(field, value);
+    }
+}
+
+/// Reads the passwords set with `proxysql_password_file` and `readyset_password_file` into
+/// `proxysql_password` and `readyset_password`, trimming the trailing newline.
+/// Setting both the password and its file is an error.
+///
+/// # Arguments
+///
+/// * `table` - The parsed config file, with the environment variable overrides applied.
+fn apply_password_files(table: &mut toml::Table) -> Result<(), SchedulerError> {
+    for field in PASSWORD_FILE_FIELDS {
+        let file_field = format!("{}_file", field);
+        let path = match table.get(&file_field) {
+            Some(toml::Value::String(path)) => path.clone(),
+            Some(_) => {
+                return Err(SchedulerError::Config(format!(
+                    "{} must be a path",
+                    file_field
+                )))
+            }
+            None => continue,
+        };
+        if table.contains_key(field) {
+            return Err(SchedulerError::Config(format!(
+                "{} and {} can't be both set",
+                field, file_field
+            )));
+        }
+        let password = std::fs::read_to_string(&path).map_err(|err| {
+            SchedulerError::Config(format!("Failed to read {} {}: {}", file_field, path, err))
+        })?;
+        let password = password.trim_end_matches(['\n', '\r']).to_string();
+        table.insert(field.to_string(), toml::Value::String(password));
+    }
+    Ok(())
+}
+
+/// Converts the raw value of an environment variable into a TOML value.
+/// The value of a string field is kept as is, so a numeric looking password stays a string.
+/// Otherwise the value is parsed as a TOML literal (e.g. `6032`, `true`, `['a', 'b']`), falling back
+/// to a string so the config error names the expected type.
+///
+/// # Arguments
+///
+/// * `raw` - The value of the environment variable.
+/// * `is_string` - Whether the overridden field is a string.
+fn env_override_value(raw: &str, is_string: bool) -> toml::Value {
+    if is_string {
+        return toml::Value::String(raw.to_string());
+    }
+    format!("value = {}", raw)
+        .parse::<toml::Table>()
+        .ok()
+        .and_then(|mut parsed| parsed.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(raw.to_string()))
+}
+
+/// Minimal config used by the tests, with the required fields only.
+#[cfg(test)]
+const TEST_CONFIG: &str = "proxysql_user = 'admin'
+proxysql_password = 'admin'
+proxysql_host = '127.0.0.1'
+proxysql_port = 6032
+readyset_user = 'root'
+readyset_password = 'root'
+source_hostgroup = 11
+readyset_hostgroup = 99
+number_of_queries = 10
+";
+
+/// Builds a config for the tests from the required fields and the given TOML lines.
+///
+/// # Arguments
+///
+/// * `extra` - TOML lines with the other fields to set.
+///
+/// # Returns
+///
+/// The config.
+#[cfg(test)]
+pub fn test_config(extra: &str) -> Config {
+    toml::from_str(&format!("{}{}", TEST_CONFIG, extra)).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Held by the tests reading the environment variables, as they are shared by the tests running in parallel.
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    /// Parses the test config with the given environment variables applied.
+    fn config_with_env(vars: &[(&str, &str)]) -> Config {
+        let mut table: toml::Table = toml::from_str(TEST_CONFIG).unwrap();
+        let vars = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()));
+        apply_env_overrides(&mut table, vars);
+        toml::Value::Table(table).try_into().unwrap()
+    }
+
+    #[test]
+    fn string_fields_are_set_from_the_environment() {
+        for field in STRING_FIELDS {
+            let mut table: toml::Table = toml::from_str(TEST_CONFIG).unwrap();
+            let var = format!("{}{}", ENV_OVERRIDE_PREFIX, field.to_uppercase());
+            apply_env_overrides(&mut table, [(var, "1234".to_string())].into_iter());
+            let config: Result<Config, toml::de::Error> = toml::Value::Table(table).try_into();
+            // enum fields reject the value, but only as an unknown variant of a string
+            if let Err(err) = config {
+                assert!(
+                    err.to_string().contains("unknown variant `1234`"),
+                    "{}: {}",
+                    field,
+                    err
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn env_override_wins_over_config_file() {
+        let config = config_with_env(&[
+            ("READYSET_SCHEDULER_PROXYSQL_PORT", "6033"),
+            ("READYSET_SCHEDULER_PROXYSQL_PASSWORD", "secret"),
+            ("OTHER_PROXYSQL_USER", "ignored"),
+        ]);
+        assert_eq!(config.proxysql_port, 6033);
+        assert_eq!(config.proxysql_password, "secret");
+        assert_eq!(config.proxysql_user, "admin");
+    }
+
+    #[test]
+    fn env_override_uses_the_field_type() {
+        let config = config_with_env(&[
+            ("READYSET_SCHEDULER_READYSET_PASSWORD", "1234"),
+            ("READYSET_SCHEDULER_SCHEDULER_ID", "true"),
+            ("READYSET_SCHEDULER_OPERATION_MODE", "HealthCheck"),
+            ("READYSET_SCHEDULER_WARMUP_TIME_S", "60"),
+        ]);
+        assert_eq!(config.readyset_password, "1234");
+        assert_eq!(config.scheduler_id.as_deref(), Some("true"));
+        assert_eq!(config.operation_mode, Some(OperationMode::HealthCheck));
+        assert_eq!(config.warmup_time_s, Some(60));
+    }
+
+    #[test]
+    fn env_override_is_read_by_parse_config_file() {
+        let _env = ENV_LOCK.lock().unwrap();
+        std::env::set_var("READYSET_SCHEDULER_READYSET_USER", "4321");
+        let config = parse_config_file(TEST_CONFIG, ConfigFormat::Toml);
+        std::env::remove_var("READYSET_SCHEDULER_READYSET_USER");
+        assert_eq!(config.unwrap().readyset_user, "4321");
+    }
+
+    #[test]
+    fn config_formats_parse_to_the_same_config() {
+        let toml = "proxysql_user = 'admin'
+proxysql_password = 'admin'
+proxysql_host = '127.0.0.1'
+proxysql_port = 6032
+readyset_user = 'root'
+readyset_password = 'root'
+source_hostgroup = 11
+readyset_hostgroup = 99
+number_of_queries = 10
+warmup_time_s = 60
+operation_mode = 'QueryDiscovery'
+discovery_usernames = ['app', 'reports']
+";
+        let json = r#"{
+    "proxysql_user": "admin",
+    "proxysql_password": "admin",
+    "proxysql_host": "127.0.0.1",
+    "proxysql_port": 6032,
+    "readyset_user": "root",
+    "readyset_password": "root",
+    "source_hostgroup": 11,
+    "readyset_hostgroup": 99,
+    "number_of_queries": 10,
+    "warmup_time_s": 60,
+    "operation_mode": "QueryDiscovery",
+    "discovery_usernames": ["app", "reports"]
+}"#;
+        let yaml = "proxysql_user: admin
+proxysql_password: admin
+proxysql_host: 127.0.0.1
+proxysql_port: 6032
+readyset_user: root
+readyset_password: root
+source_hostgroup: 11
+readyset_hostgroup: 99
+number_of_queries: 10
+warmup_time_s: 60
+operation_mode: QueryDiscovery
+discovery_usernames:
+  - app
+  - reports
+";
+        let _env = ENV_LOCK.lock().unwrap();
+        let toml = format!("{:?}", parse_config_file(toml, ConfigFormat::Toml).unwrap());
+        let json = format!("{:?}", parse_config_file(json, ConfigFormat::Json).unwrap());
+        let yaml = format!("{:?}", parse_config_file(yaml, ConfigFormat::Yaml).unwrap());
+        assert_eq!(toml, json);
+        assert_eq!(toml, yaml);
+        assert!(toml.contains("warmup_time_s: Some(60)"));
+    }
+
+    #[test]
+    fn null_fields_are_left_unset() {
+        let json = r#"{
+    "proxysql_user": "admin",
+    "proxysql_password": "admin",
+    "proxysql_host": "127.0.0.1",
+    "proxysql_port": 6032,
+    "readyset_user": "root",
+    "readyset_password": "root",
+    "source_hostgroup": 11,
+    "readyset_hostgroup": 99,
+    "number_of_queries": 10,
+    "log_file": null,
+    "readyset_hostgroup_schemas": { "sales": 100, "inventory": null }
+}"#;
+        let yaml = "proxysql_user: admin
+proxysql_password: admin
+proxysql_host: 127.0.0.1
+proxysql_port: 6032
+readyset_user: root
+readyset_password: root
+source_hostgroup: 11
+readyset_hostgroup: 99
+number_of_queries: 10
+log_file:
+warmup_time_s: ~
+";
+        let _env = ENV_LOCK.lock().unwrap();
+        let json = parse_config_file(json, ConfigFormat::Json).unwrap();
+        assert_eq!(json.log_file, None);
+        assert_eq!(
+            json.readyset_hostgroup_schemas,
+            Some(HashMap::from([("sales".to_string(), 100)]))
+        );
+        let yaml = parse_config_file(yaml, ConfigFormat::Yaml).unwrap();
+        assert_eq!(yaml.log_file, None);
+        assert_eq!(yaml.warmup_time_s, None);
+    }
+
+    #[test]
+    fn config_format_is_detected_from_the_extension() {
+        assert_eq!(
+            ConfigFormat::from_path("/etc/scheduler.json"),
+            ConfigFormat::Json
+        );
+        assert_eq!(ConfigFormat::from_path("scheduler.YML"), ConfigFormat::Yaml);
+        assert_eq!(
+            ConfigFormat::from_path("scheduler.yaml"),
+            ConfigFormat::Yaml
+        );
+        assert_eq!(ConfigFormat::from_path("scheduler.cnf"), ConfigFormat::Toml);
+    }
 }
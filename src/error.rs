@@ -0,0 +1,47 @@
+use std::fmt;
+
+/// Errors of the scheduler, by the component that failed, so callers can decide to continue or abort.
+#[derive(Debug)]
+pub enum SchedulerError {
+    /// The config file can't be read or parsed
+    Config(String),
+    /// ProxySQL can't be reached
+    Connection(mysql::Error),
+    /// A query or query rule has unexpected contents, such as a rule comment without a valid timestamp
+    Query(String),
+    /// A statement on the ProxySQL admin interface failed
+    ProxySQL(mysql::Error),
+    /// A command on a Readyset host failed, or the host is not connected
+    Readyset(mysql::Error),
+}
+
+impl SchedulerError {
+    /// Builds the error of a Readyset host without a connection.
+    pub fn readyset_not_connected() -> SchedulerError {
+        SchedulerError::Readyset(mysql::Error::IoError(std::io::Error::other(
+            "Connection to Readyset host is not established",
+        )))
+    }
+}
+
+impl fmt::Display for SchedulerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SchedulerError::Config(err) | SchedulerError::Query(err) => write!(f, "{}", err),
+            SchedulerError::Connection(err)
+            | SchedulerError::ProxySQL(err)
+            | SchedulerError::Readyset(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for SchedulerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SchedulerError::Config(_) | SchedulerError::Query(_) => None,
+            SchedulerError::Connection(err)
+            | SchedulerError::ProxySQL(err)
+            | SchedulerError::Readyset(err) => Some(err),
+        }
+    }
+}
@@ -1,6 +1,6 @@
-use crate::{config::Config, queries::Query};
+use crate::{config::Config, error::SchedulerError, messages, queries::Query, retries};
 use core::fmt;
-use mysql::{prelude::Queryable, Conn, OptsBuilder};
+use mysql::{prelude::Queryable, Conn, Opts, OptsBuilder, Pool, PoolConstraints, PoolOpts};
 use std::time::Duration;
 
 #[allow(dead_code)]
@@ -40,58 +40,324 @@ impl From<String> for HostStatus {
     }
 }
 
+/// Timeout in seconds used for Readyset connections when not configured.
+const DEFAULT_TIMEOUT_S: u64 = 5;
+
+/// Converts a configured timeout in seconds into the timeout of a connection.
+/// Unset timeouts default to `DEFAULT_TIMEOUT_S`, and zero means no timeout.
+///
+/// # Arguments
+///
+/// * `timeout_s` - The configured timeout in seconds.
+///
+/// # Returns
+///
+/// The timeout of the connection, None for no timeout.
+fn connection_timeout(timeout_s: Option<u64>) -> Option<Duration> {
+    match timeout_s.unwrap_or(DEFAULT_TIMEOUT_S) {
+        0 => None,
+        timeout_s => Some(Duration::from_secs(timeout_s)),
+    }
+}
+
+/// Field of SHOW READYSET STATUS reporting the snapshot of the upstream database, and its ready value.
+const SNAPSHOT_STATUS_FIELD: (&str, &str) = ("Snapshot Status", "Completed");
+/// Field of SHOW READYSET STATUS reporting the status of Readyset versions without `Snapshot Status`,
+/// and its ready value.
+const STATUS_FIELD: (&str, &str) = ("Status", "Online");
+
+/// Represents the version of a Readyset host, as reported by SHOW READYSET VERSION.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReadysetVersion {
+    version: String,
+}
+
+impl ReadysetVersion {
+    /// Parses the version reported by Readyset.
+    ///
+    /// # Arguments
+    ///
+    /// * `version` - The version string, e.g. `stable-240117`.
+    ///
+    /// # Returns
+    ///
+    /// The parsed version.
+    pub fn parse(version: &str) -> ReadysetVersion {
+        ReadysetVersion {
+            version: version.trim().to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ReadysetVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.version)
+    }
+}
+
+/// Reads the readiness of a Readyset host from the rows of SHOW READYSET STATUS.
+/// The host is ready once `Snapshot Status` is `Completed`. Readyset versions reporting `Status`
+/// instead of `Snapshot Status` are ready once it is `Online`.
+///
+/// # Arguments
+///
+/// * `rows` - The (field, value) rows of SHOW READYSET STATUS.
+///
+/// # Returns
+///
+/// Whether the host is ready. None if neither field is reported.
+fn is_ready_status(rows: &[(String, String)]) -> Option<bool> {
+    [SNAPSHOT_STATUS_FIELD, STATUS_FIELD]
+        .iter()
+        .find_map(|(ready_field, ready_value)| {
+            rows.iter()
+                .find(|(field, _)| field == ready_field)
+                .map(|(_, value)| value == ready_value)
+        })
+}
+
+/// Parses the replication lag reported by SHOW READYSET STATUS, such as `3`, `3s` or `1.5 seconds`.
+///
+/// # Arguments
+///
+/// * `value` - The value of the lag field.
+///
+/// # Returns
+///
+/// The lag in seconds, rounded up. None if the value doesn't start with a number.
+fn parse_lag_s(value: &str) -> Option<u64> {
+    let number = value
+        .trim()
+        .split(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .next()
+        .unwrap_or("");
+    number.parse::<f64>().ok().map(|lag_s| lag_s.ceil() as u64)
+}
+
+/// MySQL error code of a syntax error.
+const ER_PARSE_ERROR: u16 = 1064;
+
+/// Start of the message of the error returned by Readyset for a query it can't parse.
+const PARSE_ERROR_MESSAGE: &str = "Query failed to parse";
+
+/// Checks if an error returned by Readyset is caused by a query it failed to parse,
+/// rather than a query it doesn't support. Readyset reports it as a MySQL syntax error,
+/// or with a message starting with `Query failed to parse`.
+///
+/// # Arguments
+///
+/// * `err` - The error returned by Readyset.
+///
+/// # Returns
+///
+/// true if the query failed to parse, false otherwise.
+pub fn is_parse_error(err: &SchedulerError) -> bool {
+    match err {
+        SchedulerError::Readyset(mysql::Error::MySqlError(err)) => {
+            err.code == ER_PARSE_ERROR || err.message.starts_with(PARSE_ERROR_MESSAGE)
+        }
+        _ => false,
+    }
+}
+
+/// Builds the name of the Readyset cache created for the given query digest.
+/// ProxySQL digests are `0x` followed by 16 hex digits, so the name is always 20 characters long,
+/// well within the 64 characters of a MySQL identifier and the 63 bytes of a Postgres one.
+/// The name only depends on the digest, so it is found again when checking or dropping the cache.
+///
+/// # Arguments
+///
+/// * `digest` - The digest of the query.
+///
+/// # Returns
+///
+/// The name of the cache.
+pub fn cache_name(digest: &str) -> String {
+    format!("d_{}", digest)
+}
+
+/// Quotes a MySQL identifier, such as a schema name, with backticks, doubling the backticks it contains,
+/// so names with special characters or matching a reserved word can be used in a statement.
+///
+/// # Arguments
+///
+/// * `identifier` - The identifier to quote.
+///
+/// # Returns
+///
+/// The quoted identifier.
+pub fn quote_identifier(identifier: &str) -> String {
+    format!("`{}`", identifier.replace('`', "``"))
+}
+
+/// Creates the pool of connections to create caches on a host, if readyset_pool_size is set.
+/// Connections are opened when first needed, with the timeouts of cache_creation_timeout_s if set.
+///
+/// # Arguments
+///
+/// * `opts` - The connection options of the host.
+/// * `config` - The scheduler configuration.
+///
+/// # Returns
+///
+/// The pool, None if readyset_pool_size is not set or is 0.
+fn cache_pool(opts: &Opts, config: &Config) -> Option<Pool> {
+    let pool_size = config
+        .readyset_pool_size
+        .filter(|pool_size| *pool_size > 0)?;
+    let mut builder =
+        OptsBuilder::from_opts(opts.clone()).pool_opts(PoolOpts::default().with_constraints(
+            PoolConstraints::new(0, pool_size).expect("0 is not above pool size"),
+        ));
+    if let Some(timeout_s) = config.cache_creation_timeout_s {
+        builder = builder
+            .read_timeout(connection_timeout(Some(timeout_s)))
+            .write_timeout(connection_timeout(Some(timeout_s)));
+    }
+    match Pool::new(builder) {
+        Ok(pool) => Some(pool),
+        Err(err) => {
+            messages::print_warning(
+                format!("Failed to create Readyset connection pool: {}", err).as_str(),
+            );
+            None
+        }
+    }
+}
+
+/// Creates the cache for the given query using the given connection.
+///
+/// # Arguments
+///
+/// * `conn` - The connection to the Readyset host.
+/// * `query` - The query to be cached.
+///
+/// # Returns
+///
+/// true if the query was cached successfully.
+fn create_cache(conn: &mut impl Queryable, query: &Query) -> Result<bool, SchedulerError> {
+    conn.query_drop(format!("USE {}", quote_identifier(query.get_schema())))
+        .map_err(SchedulerError::Readyset)?;
+    conn.query_drop(format!(
+        "CREATE CACHE {} FROM {}",
+        cache_name(query.get_digest()),
+        query.get_cache_text()
+    ))
+    .map_err(SchedulerError::Readyset)?;
+    Ok(true)
+}
+
 /// Represents a Readyset host
 pub struct Host {
+    hostgroup: u16,
     hostname: String,
     port: u16,
     status: HostStatus,
+    comment: String,
     conn: Option<Conn>,
+    /// Whether the connection was retried after failing when the host was created
+    reconnected: bool,
+    opts: Opts,
+    /// Connections to create caches on, if readyset_pool_size is set
+    pool: Option<Pool>,
+    cache_creation_timeout_s: Option<u64>,
+    version: Option<ReadysetVersion>,
+    replication_lag_s: Option<u64>,
 }
 
 impl Host {
-    /// Creates a new `Host` instance with the given hostname and port.
-    /// The connection to the host is established during the creation of the instance.
-    /// If the connection fails, the `conn` field will be `None`.
+    /// Creates a new `Host` instance with the given hostgroup, hostname and port.
+    /// The connection to the host is established during the creation of the instance,
+    /// and the Readyset version is fetched from it.
+    /// If the connection fails, the `conn` field will be `None` and the connection is retried once when first used.
     /// If the connection is successful, the `conn` field will contain the connection.
     ///
     /// # Arguments
     ///
+    /// * `hostgroup` - The ProxySQL hostgroup of the host.
     /// * `hostname` - The hostname of the host.
     /// * `port` - The port number of the host.
+    /// * `admin_port` - The port to connect to for support checks, status and cache operations, if not `port`.
     ///
     /// # Returns
     ///
     /// A new `Host` instance.
-    pub fn new(hostname: String, port: u16, status: String, config: &Config) -> Host {
-        let conn = match Conn::new(
+    pub fn new(
+        hostgroup: u16,
+        hostname: String,
+        port: u16,
+        admin_port: Option<u16>,
+        status: String,
+        config: &Config,
+    ) -> Host {
+        let opts = Opts::from(
             OptsBuilder::new()
                 .ip_or_hostname(Some(hostname.clone()))
-                .tcp_port(port)
+                .tcp_port(admin_port.unwrap_or(port))
                 .user(Some(config.readyset_user.clone()))
                 .pass(Some(config.readyset_password.clone()))
                 .prefer_socket(false)
-                .read_timeout(Some(Duration::from_secs(5)))
-                .write_timeout(Some(Duration::from_secs(5)))
-                .tcp_connect_timeout(Some(Duration::from_secs(5))),
-        ) {
+                .read_timeout(connection_timeout(config.read_timeout_s))
+                .write_timeout(connection_timeout(config.write_timeout_s))
+                .tcp_connect_timeout(connection_timeout(config.connect_timeout_s))
+                .connect_attrs(Some(config.connect_attrs())),
+        );
+        let pool = cache_pool(&opts, config);
+        let conn = match Conn::new(opts.clone()) {
             Ok(conn) => conn,
             Err(err) => {
                 eprintln!("Failed to establish connection: {}", err);
                 return Host {
+                    hostgroup,
                     hostname,
                     port,
                     status: HostStatus::from(status),
+                    comment: String::new(),
                     conn: None,
+                    reconnected: false,
+                    opts,
+                    pool,
+                    cache_creation_timeout_s: config.cache_creation_timeout_s,
+                    version: None,
+                    replication_lag_s: None,
                 };
             }
         };
 
-        Host {
+        let mut host = Host {
+            hostgroup,
             hostname,
             port,
             status: HostStatus::from(status),
+            comment: String::new(),
             conn: Some(conn),
+            reconnected: false,
+            opts,
+            pool,
+            cache_creation_timeout_s: config.cache_creation_timeout_s,
+            version: None,
+            replication_lag_s: None,
+        };
+        match host.fetch_version() {
+            Ok(_) => {}
+            Err(err) => messages::print_warning(
+                format!(
+                    "Failed to fetch Readyset version of host {}:{}: {}",
+                    host.hostname, host.port, err
+                )
+                .as_str(),
+            ),
         }
+        host
+    }
+
+    /// Gets the ProxySQL hostgroup of the host.
+    ///
+    /// # Returns
+    ///
+    /// The hostgroup of the host.
+    pub fn get_hostgroup(&self) -> u16 {
+        self.hostgroup
     }
 
     /// Gets the hostname of the host.
@@ -130,6 +396,24 @@ impl Host {
         self.status = status;
     }
 
+    /// Gets the ProxySQL comment of the host.
+    ///
+    /// # Returns
+    ///
+    /// The comment of the host in mysql_servers.
+    pub fn get_comment(&self) -> &String {
+        &self.comment
+    }
+
+    /// Sets the ProxySQL comment of the host.
+    ///
+    /// # Arguments
+    ///
+    /// * `comment` - The comment of the host in mysql_servers.
+    pub fn set_comment(&mut self, comment: String) {
+        self.comment = comment;
+    }
+
     /// Checks if the host is online.
     ///
     /// # Returns
@@ -139,41 +423,142 @@ impl Host {
         self.status == HostStatus::Online
     }
 
+    /// Gets the Readyset version of the host.
+    ///
+    /// # Returns
+    ///
+    /// The version of the host, None if it couldn't be fetched.
+    pub fn get_version(&self) -> Option<&ReadysetVersion> {
+        self.version.as_ref()
+    }
+
+    /// Connects to the host if the connection failed when the host was created.
+    /// This is retried only once per run, when the connection is first needed, if the max_total_retries budget allows it.
+    /// Once connected, the Readyset version of the host is fetched.
+    fn ensure_connected(&mut self) {
+        if self.conn.is_some() || self.reconnected {
+            return;
+        }
+        if !retries::try_consume() {
+            self.reconnected = true;
+            return;
+        }
+        self.reconnected = true;
+        match Conn::new(self.opts.clone()) {
+            Ok(conn) => {
+                messages::print_note(
+                    format!(
+                        "Reconnected to Readyset host {}:{}",
+                        self.hostname, self.port
+                    )
+                    .as_str(),
+                );
+                self.conn = Some(conn);
+                // the version could not be fetched when the host was created
+                if let Err(err) = self.fetch_version() {
+                    messages::print_warning(
+                        format!(
+                            "Failed to fetch Readyset version of host {}:{}: {}",
+                            self.hostname, self.port, err
+                        )
+                        .as_str(),
+                    );
+                }
+            }
+            Err(err) => messages::print_warning(
+                format!(
+                    "Failed to reconnect to Readyset host {}:{}: {}",
+                    self.hostname, self.port, err
+                )
+                .as_str(),
+            ),
+        }
+    }
+
+    /// Fetches the Readyset version of the host and stores it in the host.
+    /// This is done by querying the SHOW READYSET VERSION command.
+    ///
+    /// # Returns
+    ///
+    /// The version reported by Readyset.
+    pub fn fetch_version(&mut self) -> Result<ReadysetVersion, SchedulerError> {
+        self.ensure_connected();
+        match &mut self.conn {
+            Some(conn) => {
+                let rows: Vec<(String, String)> = conn
+                    .query("SHOW READYSET VERSION")
+                    .map_err(SchedulerError::Readyset)?;
+                let version = rows
+                    .iter()
+                    .find(|(field, _)| field == "Readyset Version")
+                    .or(rows.first())
+                    .map(|(_, value)| ReadysetVersion::parse(value))
+                    .unwrap_or_else(|| ReadysetVersion::parse(""));
+                self.version = Some(version.clone());
+                Ok(version)
+            }
+            None => Err(SchedulerError::readyset_not_connected()),
+        }
+    }
+
+    /// Gets the replication lag of the host, as reported by the last SHOW READYSET STATUS.
+    ///
+    /// # Returns
+    ///
+    /// The replication lag in seconds. None if it wasn't checked or Readyset doesn't report it.
+    pub fn get_replication_lag_s(&self) -> Option<u64> {
+        self.replication_lag_s
+    }
+
     /// Checks if the Readyset host is ready to serve traffic.
-    /// This is done by querying the SHOW READYSET STATUS command.
+    /// This is done by querying the SHOW READYSET STATUS command, see `is_ready_status`.
+    /// If the readiness is not reported, the status rows are logged and the host is considered not ready.
+    /// The replication lag, if reported in a field containing `lag`, is stored in the host.
     ///
     /// # Returns
     ///
     /// true if the host is ready, false otherwise.
-    pub fn check_readyset_is_ready(&mut self) -> Result<bool, mysql::Error> {
+    pub fn check_readyset_is_ready(&mut self) -> Result<bool, SchedulerError> {
+        self.ensure_connected();
         match &mut self.conn {
             Some(conn) => {
                 let result = conn.query("SHOW READYSET STATUS");
                 match result {
                     Ok(rows) => {
                         let rows: Vec<(String, String)> = rows;
-                        for (field, value) in rows {
-                            if field == "Snapshot Status" {
-                                return Ok(value == "Completed");
+                        self.replication_lag_s = rows
+                            .iter()
+                            .find(|(field, _)| field.to_lowercase().contains("lag"))
+                            .and_then(|(_, value)| parse_lag_s(value));
+                        match is_ready_status(&rows) {
+                            Some(ready) => Ok(ready),
+                            None => {
+                                messages::print_warning(
+                                    format!(
+                                        "Readyset host {}:{} reported an unknown status, please report it: {}",
+                                        self.hostname,
+                                        self.port,
+                                        rows.iter()
+                                            .map(|(field, value)| format!("{}: {}", field, value))
+                                            .collect::<Vec<String>>()
+                                            .join(", ")
+                                    )
+                                    .as_str(),
+                                );
+                                Ok(false)
                             }
                         }
-                        Ok(false)
                     }
-                    Err(err) => Err(mysql::Error::IoError(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        format!("Failed to execute query: {}", err),
-                    ))),
+                    Err(err) => Err(SchedulerError::Readyset(err)),
                 }
             }
-            None => Err(mysql::Error::IoError(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Connection to Readyset host is not established",
-            ))),
+            None => Err(SchedulerError::readyset_not_connected()),
         }
     }
 
     /// Checks if the host supports the given query.
     /// This is done by querying the EXPLAIN CREATE CACHE FROM command.
+    /// The support column is looked up by its name, `readyset supported`, falling back to the third column.
     ///
     /// # Arguments
     ///
@@ -186,16 +571,27 @@ impl Host {
     pub fn check_query_support(
         &mut self,
         digest_text: &String,
-        schema: &String,
-    ) -> Result<bool, mysql::Error> {
+        schema: &str,
+    ) -> Result<bool, SchedulerError> {
+        self.ensure_connected();
         match &mut self.conn {
             Some(conn) => {
-                conn.query_drop(format!("USE {}", schema))
-                    .expect("Failed to use schema");
-                let row: Option<(String, String, String)> =
-                    conn.query_first(format!("EXPLAIN CREATE CACHE FROM {}", digest_text))?;
-                match row {
-                    Some((_, _, value)) => Ok(value == "yes" || value == "cached"),
+                conn.query_drop(format!("USE {}", quote_identifier(schema)))
+                    .map_err(SchedulerError::Readyset)?;
+                let row: Option<mysql::Row> = conn
+                    .query_first(format!("EXPLAIN CREATE CACHE FROM {}", digest_text))
+                    .map_err(SchedulerError::Readyset)?;
+                let value = row.and_then(|row| {
+                    let column = row
+                        .columns_ref()
+                        .iter()
+                        .position(|column| column.name_str() == "readyset supported")
+                        .unwrap_or(2);
+                    row.get_opt::<String, usize>(column)
+                        .and_then(|value| value.ok())
+                });
+                match value {
+                    Some(value) => Ok(value == "yes" || value == "cached"),
                     None => Ok(false),
                 }
             }
@@ -213,23 +609,517 @@ impl Host {
     /// # Returns
     ///
     /// true if the query was cached successfully, false otherwise.
-    pub fn cache_query(&mut self, query: &Query) -> Result<bool, mysql::Error> {
+    pub fn cache_query(&mut self, query: &Query) -> Result<bool, SchedulerError> {
+        // the pool replaces broken connections, instead of leaving the host without a connection for the run
+        if let Some(pool) = &self.pool {
+            let mut conn = pool.get_conn().map_err(SchedulerError::Readyset)?;
+            return create_cache(&mut conn, query);
+        }
+        // CREATE CACHE can take much longer than other queries, so it runs on a dedicated connection
+        // with cache_creation_timeout_s, leaving the timeouts of the host connection untouched
+        if let Some(timeout_s) = self.cache_creation_timeout_s {
+            let mut conn = Conn::new(
+                OptsBuilder::from_opts(self.opts.clone())
+                    .read_timeout(connection_timeout(Some(timeout_s)))
+                    .write_timeout(connection_timeout(Some(timeout_s))),
+            )
+            .map_err(SchedulerError::Readyset)?;
+            return create_cache(&mut conn, query);
+        }
+        self.ensure_connected();
+        match &mut self.conn {
+            None => Err(SchedulerError::readyset_not_connected()),
+            Some(conn) => create_cache(conn, query),
+        }
+    }
+
+    /// Checks if the cache for the given digest exists on the host.
+    /// This is done by querying the SHOW READYSET CACHES command and looking for the cache name in any column,
+    /// as the column layout differs between Readyset versions.
+    ///
+    /// # Arguments
+    ///
+    /// * `digest` - The digest of the query.
+    ///
+    /// # Returns
+    ///
+    /// true if the cache exists, false otherwise.
+    pub fn has_cache(&mut self, digest: &str) -> Result<bool, SchedulerError> {
+        let name = cache_name(digest);
+        self.ensure_connected();
+        match &mut self.conn {
+            Some(conn) => {
+                let rows: Vec<mysql::Row> = conn
+                    .query("SHOW READYSET CACHES")
+                    .map_err(SchedulerError::Readyset)?;
+                Ok(rows.iter().any(|row| {
+                    (0..row.len()).any(|i| {
+                        row.get_opt::<String, usize>(i)
+                            .and_then(|value| value.ok())
+                            .is_some_and(|value| value == name)
+                    })
+                }))
+            }
+            None => Err(SchedulerError::readyset_not_connected()),
+        }
+    }
+
+    /// Counts the caches on the host, as listed by the SHOW READYSET CACHES command.
+    ///
+    /// # Returns
+    ///
+    /// The number of caches.
+    pub fn count_caches(&mut self) -> Result<u32, SchedulerError> {
+        self.ensure_connected();
+        match &mut self.conn {
+            Some(conn) => {
+                let rows: Vec<mysql::Row> = conn
+                    .query("SHOW READYSET CACHES")
+                    .map_err(SchedulerError::Readyset)?;
+                Ok(rows.len() as u32)
+            }
+            None => Err(SchedulerError::readyset_not_connected()),
+        }
+    }
+
+    /// Drops the cache for the given digest from the host.
+    /// This is done by executing the DROP CACHE command.
+    ///
+    /// # Arguments
+    ///
+    /// * `digest` - The digest of the query.
+    ///
+    /// # Returns
+    ///
+    /// true if the cache was dropped successfully.
+    pub fn drop_cache(&mut self, digest: &str) -> Result<bool, SchedulerError> {
+        self.ensure_connected();
         match &mut self.conn {
-            None => {
-                return Err(mysql::Error::IoError(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "Connection to Readyset host is not established",
-                )))
+            Some(conn) => {
+                conn.query_drop(format!("DROP CACHE {}", cache_name(digest)))
+                    .map_err(SchedulerError::Readyset)?;
+                Ok(true)
             }
+            None => Err(SchedulerError::readyset_not_connected()),
+        }
+    }
+
+    /// Checks if the given schema exists.
+    /// This is done by querying information_schema.SCHEMATA, which Readyset proxies to the upstream database.
+    ///
+    /// # Arguments
+    ///
+    /// * `schema` - The schema name.
+    ///
+    /// # Returns
+    ///
+    /// true if the schema exists, false otherwise.
+    pub fn schema_exists(&mut self, schema: &str) -> Result<bool, SchedulerError> {
+        self.ensure_connected();
+        match &mut self.conn {
             Some(conn) => {
-                conn.query_drop(format!("USE {}", query.get_schema()))?;
-                conn.query_drop(format!(
-                    "CREATE CACHE d_{} FROM {}",
-                    query.get_digest(),
-                    query.get_digest_text()
-                ))?;
+                let found: Option<String> = conn
+                    .exec_first(
+                        "SELECT SCHEMA_NAME FROM information_schema.SCHEMATA WHERE SCHEMA_NAME = ?",
+                        (schema,),
+                    )
+                    .map_err(SchedulerError::Readyset)?;
+                Ok(found.is_some())
+            }
+            None => Err(SchedulerError::readyset_not_connected()),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod fake {
+    use std::{
+        io::{self, Read, Write},
+        net::{TcpListener, TcpStream},
+        sync::{Arc, Mutex},
+        thread,
+    };
+
+    /// Capabilities of the fake server: long password, protocol 4.1, transactions, secure connection and plugin auth.
+    const CAPABILITIES: u32 = 0x0000_0001 | 0x0000_0200 | 0x0000_2000 | 0x0000_8000 | 0x0008_0000;
+    const COM_QUIT: u8 = 0x01;
+    const COM_QUERY: u8 = 0x03;
+
+    /// Rows returned to the queries containing a pattern.
+    struct Response {
+        pattern: String,
+        columns: Vec<String>,
+        rows: Vec<Vec<String>>,
+    }
+
+    #[derive(Default)]
+    struct State {
+        responses: Vec<Response>,
+        /// Error messages returned to the statements containing a pattern
+        failures: Vec<(String, String)>,
+        statements: Vec<String>,
+        failed_connections: usize,
+    }
+
+    /// Readyset server speaking enough of the MySQL protocol for `Host`, in the tests.
+    /// Queries get the rows of the first response whose pattern they contain, other statements get an OK,
+    /// unless they contain the pattern of a failure.
+    #[derive(Clone, Default)]
+    pub struct FakeReadyset {
+        state: Arc<Mutex<State>>,
+    }
+
+    impl FakeReadyset {
+        /// Returns the given rows to the queries containing `pattern`.
+        pub fn respond(&self, pattern: &str, columns: &[&str], rows: Vec<Vec<&str>>) -> &Self {
+            self.state.lock().unwrap().responses.push(Response {
+                pattern: pattern.to_string(),
+                columns: columns.iter().map(|column| column.to_string()).collect(),
+                rows: rows
+                    .into_iter()
+                    .map(|row| row.into_iter().map(|value| value.to_string()).collect())
+                    .collect(),
+            });
+            self
+        }
+
+        /// Returns an error with the given message to the statements containing `pattern`.
+        pub fn fail_on(&self, pattern: &str, message: &str) -> &Self {
+            self.state
+                .lock()
+                .unwrap()
+                .failures
+                .push((pattern.to_string(), message.to_string()));
+            self
+        }
+
+        /// Closes the next `count` connections before the handshake, as an unreachable host.
+        pub fn fail_connections(&self, count: usize) -> &Self {
+            self.state.lock().unwrap().failed_connections = count;
+            self
+        }
+
+        /// Gets the statements received so far that contain `pattern`.
+        pub fn statements_containing(&self, pattern: &str) -> Vec<String> {
+            self.state
+                .lock()
+                .unwrap()
+                .statements
+                .iter()
+                .filter(|statement| statement.contains(pattern))
+                .cloned()
+                .collect()
+        }
+
+        /// Starts listening on a local port, serving each connection on its own thread.
+        ///
+        /// # Returns
+        ///
+        /// The port the server listens on.
+        pub fn start(&self) -> u16 {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let fake = self.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    let mut state = fake.state.lock().unwrap();
+                    if state.failed_connections > 0 {
+                        state.failed_connections -= 1;
+                        continue;
+                    }
+                    drop(state);
+                    let fake = fake.clone();
+                    thread::spawn(move || fake.serve(stream));
+                }
+            });
+            port
+        }
+
+        fn serve(&self, mut stream: TcpStream) -> io::Result<()> {
+            write_packet(&mut stream, 0, &handshake())?;
+            read_packet(&mut stream)?;
+            write_packet(&mut stream, 2, &ok())?;
+            loop {
+                let payload = read_packet(&mut stream)?;
+                match payload.first() {
+                    Some(&COM_QUERY) => {
+                        // recorded before answering, so the client sees it once it has the answer
+                        let statement = String::from_utf8_lossy(&payload[1..]).to_string();
+                        self.state
+                            .lock()
+                            .unwrap()
+                            .statements
+                            .push(statement.clone());
+                        self.answer(&mut stream, &statement)?;
+                    }
+                    Some(&COM_QUIT) | None => return Ok(()),
+                    Some(_) => write_packet(&mut stream, 1, &ok())?,
+                }
+            }
+        }
+
+        fn answer(&self, stream: &mut TcpStream, statement: &str) -> io::Result<()> {
+            let (columns, rows) = if statement.contains("@@max_allowed_packet") {
+                (
+                    vec!["@@max_allowed_packet".to_string()],
+                    vec![vec!["16777216".to_string()]],
+                )
+            } else {
+                let state = self.state.lock().unwrap();
+                if let Some((_, message)) = state
+                    .failures
+                    .iter()
+                    .find(|(pattern, _)| statement.contains(pattern.as_str()))
+                {
+                    return write_packet(stream, 1, &err(message));
+                }
+                match state
+                    .responses
+                    .iter()
+                    .find(|response| statement.contains(&response.pattern))
+                {
+                    Some(response) => (response.columns.clone(), response.rows.clone()),
+                    None => return write_packet(stream, 1, &ok()),
+                }
+            };
+            let mut sequence = 1;
+            let mut packets = vec![vec![columns.len() as u8]];
+            packets.extend(columns.iter().map(|column| column_definition(column)));
+            packets.push(eof());
+            packets.extend(rows.iter().map(|row| {
+                row.iter()
+                    .flat_map(|value| length_encoded(value.as_bytes()))
+                    .collect()
+            }));
+            packets.push(eof());
+            for packet in packets {
+                write_packet(stream, sequence, &packet)?;
+                sequence = sequence.wrapping_add(1);
             }
+            Ok(())
+        }
+    }
+
+    fn read_packet(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+        let mut header = [0; 4];
+        stream.read_exact(&mut header)?;
+        let mut payload =
+            vec![0; u32::from_le_bytes([header[0], header[1], header[2], 0]) as usize];
+        stream.read_exact(&mut payload)?;
+        Ok(payload)
+    }
+
+    fn write_packet(stream: &mut TcpStream, sequence: u8, payload: &[u8]) -> io::Result<()> {
+        let mut packet = (payload.len() as u32).to_le_bytes()[..3].to_vec();
+        packet.push(sequence);
+        packet.extend_from_slice(payload);
+        stream.write_all(&packet)
+    }
+
+    fn handshake() -> Vec<u8> {
+        let mut payload = vec![10];
+        payload.extend_from_slice(b"8.0.26-readyset\0");
+        payload.extend_from_slice(&1u32.to_le_bytes());
+        payload.extend_from_slice(b"abcdefgh\0");
+        payload.extend_from_slice(&CAPABILITIES.to_le_bytes()[..2]);
+        payload.push(0x21);
+        payload.extend_from_slice(&2u16.to_le_bytes());
+        payload.extend_from_slice(&CAPABILITIES.to_le_bytes()[2..]);
+        payload.push(21);
+        payload.extend_from_slice(&[0; 10]);
+        payload.extend_from_slice(b"ijklmnopqrst\0");
+        payload.extend_from_slice(b"mysql_native_password\0");
+        payload
+    }
+
+    fn ok() -> Vec<u8> {
+        vec![0, 0, 0, 2, 0, 0, 0]
+    }
+
+    fn err(message: &str) -> Vec<u8> {
+        // ER_UNKNOWN_ERROR, SQLSTATE HY000
+        let mut payload = vec![0xff];
+        payload.extend_from_slice(&1105u16.to_le_bytes());
+        payload.extend_from_slice(b"#HY000");
+        payload.extend_from_slice(message.as_bytes());
+        payload
+    }
+
+    fn eof() -> Vec<u8> {
+        vec![0xfe, 0, 0, 2, 0]
+    }
+
+    fn length_encoded(value: &[u8]) -> Vec<u8> {
+        let mut encoded = vec![value.len() as u8];
+        encoded.extend_from_slice(value);
+        encoded
+    }
+
+    fn column_definition(name: &str) -> Vec<u8> {
+        let mut payload = length_encoded(b"def");
+        for part in ["", "", "", name, name] {
+            payload.extend(length_encoded(part.as_bytes()));
         }
-        Ok(true)
+        payload.push(0x0c);
+        payload.extend_from_slice(&0x21u16.to_le_bytes());
+        payload.extend_from_slice(&255u32.to_le_bytes());
+        // MYSQL_TYPE_VAR_STRING
+        payload.push(0xfd);
+        payload.extend_from_slice(&[0, 0, 0, 0, 0]);
+        payload
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+    use fake::FakeReadyset;
+
+    /// SHOW READYSET STATUS of a Readyset host reporting its snapshot in `Snapshot Status`.
+    const SNAPSHOT_STATUS_ROWS: [(&str, &str); 8] = [
+        ("Database Connection", "Connected"),
+        ("Connection Count", "2"),
+        ("Snapshot Status", "Completed"),
+        ("Maximum Replication Offset", "(mysql) binlog.000003:2714"),
+        ("Minimum Replication Offset", "(mysql) binlog.000003:2714"),
+        ("Last started Controller", "2024-01-17 13:12:42 UTC"),
+        ("Last completed snapshot", "2024-01-17 13:12:45 UTC"),
+        ("Last started replication", "2024-01-17 13:12:45 UTC"),
+    ];
+
+    /// SHOW READYSET STATUS of a Readyset host reporting `Status` instead of `Snapshot Status`.
+    const STATUS_ROWS: [(&str, &str); 6] = [
+        ("Status", "Online"),
+        ("Maximum Replication Offset", "(mysql) binlog.000003:2714"),
+        ("Minimum Replication Offset", "(mysql) binlog.000003:2714"),
+        ("Last started Controller", "2024-06-03 09:41:10 UTC"),
+        ("Last completed snapshot", "2024-06-03 09:41:12 UTC"),
+        ("Last started replication", "2024-06-03 09:41:12 UTC"),
+    ];
+
+    /// Builds the status rows from a fixture, replacing the value of `field`.
+    fn status_rows(fixture: &[(&str, &str)], field: &str, value: &str) -> Vec<(String, String)> {
+        fixture
+            .iter()
+            .map(|(name, current)| {
+                let current = if *name == field { value } else { current };
+                (name.to_string(), current.to_string())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn snapshot_status_is_ready_once_completed() {
+        let rows = status_rows(&SNAPSHOT_STATUS_ROWS, "", "");
+        assert_eq!(is_ready_status(&rows), Some(true));
+        let rows = status_rows(&SNAPSHOT_STATUS_ROWS, "Snapshot Status", "In Progress");
+        assert_eq!(is_ready_status(&rows), Some(false));
+    }
+
+    #[test]
+    fn status_is_ready_once_online() {
+        let rows = status_rows(&STATUS_ROWS, "", "");
+        assert_eq!(is_ready_status(&rows), Some(true));
+        let rows = status_rows(&STATUS_ROWS, "Status", "Snapshot In Progress");
+        assert_eq!(is_ready_status(&rows), Some(false));
+    }
+
+    #[test]
+    fn unknown_status_is_not_reported() {
+        let rows = status_rows(&STATUS_ROWS[1..], "", "");
+        assert_eq!(is_ready_status(&rows), None);
+    }
+
+    #[test]
+    fn host_unreachable_when_created_is_connected_when_first_used() {
+        let readyset = FakeReadyset::default();
+        readyset
+            .fail_connections(1)
+            .respond(
+                "SHOW READYSET VERSION",
+                &["name", "value"],
+                vec![vec!["Readyset Version", "stable-240117"]],
+            )
+            .respond(
+                "SHOW READYSET STATUS",
+                &["name", "value"],
+                vec![vec!["Snapshot Status", "Completed"]],
+            );
+        let port = readyset.start();
+        let config = test_config("");
+        let mut host = Host::new(
+            99,
+            "127.0.0.1".to_string(),
+            port,
+            None,
+            "ONLINE".to_string(),
+            &config,
+        );
+        assert!(host.get_version().is_none());
+        assert!(host.check_readyset_is_ready().unwrap());
+        assert_eq!(
+            host.get_version(),
+            Some(&ReadysetVersion::parse("stable-240117"))
+        );
+        assert_eq!(
+            readyset.statements_containing("SHOW READYSET STATUS").len(),
+            1
+        );
+    }
+
+    fn readyset_error(code: u16, message: &str) -> SchedulerError {
+        SchedulerError::Readyset(mysql::Error::MySqlError(mysql::MySqlError {
+            state: "HY000".to_string(),
+            message: message.to_string(),
+            code,
+        }))
+    }
+
+    #[test]
+    fn cache_name_of_the_longest_digest_fits_an_identifier() {
+        let name = cache_name("0xFFFFFFFFFFFFFFFF");
+        assert_eq!(name, "d_0xFFFFFFFFFFFFFFFF");
+        // Postgres identifiers are limited to 63 bytes, MySQL ones to 64 characters
+        assert!(name.len() <= 63);
+    }
+
+    #[test]
+    fn quote_identifier_quotes_hyphens_and_spaces() {
+        assert_eq!(quote_identifier("my-schema"), "`my-schema`");
+        assert_eq!(quote_identifier("my schema"), "`my schema`");
+    }
+
+    #[test]
+    fn quote_identifier_escapes_backticks() {
+        assert_eq!(quote_identifier("my`schema"), "`my``schema`");
+        assert_eq!(quote_identifier("`"), "````");
+    }
+
+    #[test]
+    fn parse_errors_are_recognized_by_code_or_message() {
+        assert!(is_parse_error(&readyset_error(
+            ER_PARSE_ERROR,
+            "You have an error in your SQL syntax"
+        )));
+        assert!(is_parse_error(&readyset_error(
+            1105,
+            "Query failed to parse: SELECT * FROM t WHERE id = ?"
+        )));
+    }
+
+    #[test]
+    fn other_errors_mentioning_parsing_are_not_parse_errors() {
+        assert!(!is_parse_error(&readyset_error(
+            1105,
+            "Failed to parse the response of the upstream database"
+        )));
+        assert!(!is_parse_error(&SchedulerError::ProxySQL(
+            mysql::Error::MySqlError(mysql::MySqlError {
+                state: "HY000".to_string(),
+                message: "Query failed to parse".to_string(),
+                code: ER_PARSE_ERROR,
+            })
+        )));
     }
 }
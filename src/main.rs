@@ -1,16 +1,77 @@
+mod admin;
 mod config;
+mod error;
 mod hosts;
 mod messages;
+mod plan;
 mod proxysql;
 mod queries;
+mod retries;
+mod run_state;
+mod signals;
+mod state_file;
+mod stats;
+mod support_cache;
+mod telemetry;
+mod webhook;
 
+use admin::AdminConnection;
 use clap::Parser;
 use config::read_config_file;
+use error::SchedulerError;
 use file_guard::Lock;
 use messages::MessageType;
-use mysql::{Conn, OptsBuilder};
+use plan::Plan;
 use proxysql::ProxySQL;
-use std::fs::OpenOptions;
+use run_state::RunState;
+use stats::RunStats;
+use std::{
+    collections::hash_map::RandomState,
+    fs::OpenOptions,
+    hash::{BuildHasher, Hasher},
+    panic::AssertUnwindSafe,
+    time::{Duration, Instant},
+};
+use telemetry::{Span, Telemetry};
+
+/// Exit status of the scheduler, so wrappers can tell failure classes apart and retry only the transient ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExitStatus {
+    Success = 0,
+    /// Failure not covered by the other classes, such as an unknown --check-host server or a --cache-digest
+    /// query that can't be cached
+    Failure = 1,
+    /// The config file can't be read or parsed, the lock file can't be opened, the run state can't be written
+    /// with observe_first_runs set, or a hostgroup has no server with hostgroup_check set to Error
+    ConfigError = 2,
+    /// Another scheduler run holds the lock
+    LockContention = 3,
+    /// ProxySQL can't be reached
+    ProxySQLUnreachable = 4,
+    /// None of the Readyset servers can be reached
+    ReadysetUnreachable = 5,
+    /// The run failed midway, or some of the Readyset servers can't be reached
+    PartialFailure = 6,
+}
+
+impl ExitStatus {
+    /// Exits the process with this status.
+    fn exit(self) -> ! {
+        std::process::exit(self as i32)
+    }
+}
+
+impl From<&SchedulerError> for ExitStatus {
+    fn from(err: &SchedulerError) -> Self {
+        match err {
+            SchedulerError::Config(_) => ExitStatus::ConfigError,
+            SchedulerError::Connection(_) => ExitStatus::ProxySQLUnreachable,
+            SchedulerError::Query(_)
+            | SchedulerError::ProxySQL(_)
+            | SchedulerError::Readyset(_) => ExitStatus::PartialFailure,
+        }
+    }
+}
 
 /// Readyset ProxySQL Scheduler
 /// This tool is used to query ProxySQL Stats tables to find queries that are not yet cached in Readyset and then cache them.
@@ -20,17 +81,149 @@ struct Args {
     /// path to the config file
     #[arg(long)]
     config: String,
+    /// Format of the config file. Detected from its extension by default, TOML if unknown
+    #[arg(long, value_enum)]
+    config_format: Option<config::ConfigFormat>,
     /// Dry run mode
     #[arg(long)]
     dry_run: bool,
+    /// Set all Readyset servers to OFFLINE_SOFT for maintenance and exit
+    #[arg(long, conflicts_with = "undrain")]
+    drain: bool,
+    /// Time in seconds to wait for connections to finish after --drain
+    #[arg(long, requires = "drain")]
+    drain_wait_s: Option<u64>,
+    /// Set all drained (OFFLINE_SOFT) Readyset servers back to ONLINE and exit
+    #[arg(long)]
+    undrain: bool,
+    /// Print the status of each Readyset server as JSON and exit, without changing ProxySQL
+    #[arg(long, conflicts_with_all = ["drain", "undrain"])]
+    status: bool,
+    /// Print the query rules added by the scheduler and their Readyset caches as JSON and exit
+    #[arg(long, conflicts_with_all = ["drain", "undrain", "status"])]
+    list_caches: bool,
+    /// Check a single Readyset server, given as hostname:port, print its status as JSON and exit
+    #[arg(long, value_parser = parse_host_port, conflicts_with_all = ["drain", "undrain", "status", "list_caches"])]
+    check_host: Option<(String, u16)>,
+    /// Write the query discovery candidates and whether Readyset supports them to a CSV file and exit,
+    /// without creating caches or query rules. Written as TSV if the path ends with .tsv
+    #[arg(long, conflicts_with_all = ["drain", "undrain", "status", "list_caches", "check_host"])]
+    export_candidates: Option<String>,
+    /// Cache the query with this digest and add its query rule, bypassing the ranking and thresholds of query
+    /// discovery, and exit. Only Readyset support is checked. Respects --dry-run
+    #[arg(long, conflicts_with_all = ["drain", "undrain", "status", "list_caches", "check_host", "export_candidates"])]
+    cache_digest: Option<String>,
+    /// Update the ProxySQL status of the server checked with --check-host
+    #[arg(long, requires = "check_host")]
+    update_status: bool,
+    /// Log at Info level, overriding log_verbosity of the config. Can be repeated, as -vv, but Info is the lowest level
+    #[arg(short, long, action = clap::ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+    /// Only log errors, overriding log_verbosity of the config
+    #[arg(short, long)]
+    quiet: bool,
+    /// Log each placeholder rule applied to the digest text of a query, to debug queries failing to cache
+    #[arg(long)]
+    trace_normalization: bool,
+    /// Run continuously, every N seconds, instead of once. Stops after the current run on SIGTERM or SIGINT
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), conflicts_with_all = ["drain", "undrain", "status", "list_caches", "check_host", "export_candidates", "cache_digest"])]
+    interval_s: Option<u64>,
+}
+
+/// Parses a Readyset server given as hostname:port.
+fn parse_host_port(value: &str) -> Result<(String, u16), String> {
+    let (hostname, port) = value
+        .rsplit_once(':')
+        .ok_or(format!("expected hostname:port, got {}", value))?;
+    let port = port
+        .parse::<u16>()
+        .map_err(|err| format!("invalid port {}: {}", port, err))?;
+    Ok((hostname.to_string(), port))
+}
+
+/// Gets the log verbosity set by --verbose or --quiet.
+fn cli_log_verbosity(args: &Args) -> Option<MessageType> {
+    // Info is the lowest level, so a repeated flag logs the same as a single one
+    match (args.verbose, args.quiet) {
+        (0, false) => None,
+        (0, true) => Some(MessageType::Error),
+        _ => Some(MessageType::Info),
+    }
 }
 
 fn main() {
     let args = Args::parse();
-    let config_file = read_config_file(&args.config).expect("Failed to read config file");
-    let config = config::parse_config_file(&config_file).expect("Failed to parse config file");
-    messages::set_log_verbosity(config.clone().log_verbosity.unwrap_or(MessageType::Note));
-    messages::print_info("Running readyset_scheduler");
+    // the flags also apply to the errors reading the config
+    let cli_log_verbosity = cli_log_verbosity(&args);
+    if let Some(log_verbosity) = cli_log_verbosity {
+        messages::set_log_verbosity(log_verbosity);
+    }
+    let config_file = match read_config_file(&args.config) {
+        Ok(config_file) => config_file,
+        Err(err) => {
+            messages::print_error(
+                format!("Failed to read config file {}: {}", args.config, err).as_str(),
+            );
+            ExitStatus::ConfigError.exit();
+        }
+    };
+    let config_format = args
+        .config_format
+        .unwrap_or_else(|| config::ConfigFormat::from_path(&args.config));
+    let mut config = match config::parse_config_file(&config_file, config_format) {
+        Ok(config) => config,
+        Err(err) => {
+            messages::print_error(format!("Failed to parse config file: {}", err).as_str());
+            ExitStatus::ConfigError.exit();
+        }
+    };
+    let log_verbosity = cli_log_verbosity
+        .or(config.log_verbosity)
+        .unwrap_or(MessageType::Note);
+    // keep stdout for the JSON output of --status and --list-caches, warnings and errors go to stderr
+    if (args.status || args.list_caches) && log_verbosity < MessageType::Warning {
+        messages::set_log_verbosity(MessageType::Warning);
+    } else {
+        messages::set_log_verbosity(log_verbosity);
+    }
+    if let Some(log_file) = &config.log_file {
+        if let Err(err) = messages::set_log_file(
+            log_file,
+            config.log_max_size_mb.unwrap_or(100),
+            config.log_keep_files.unwrap_or(5),
+            config.log_file_only.unwrap_or(false),
+        ) {
+            messages::print_warning(
+                format!(
+                    "Failed to open log file {}, logging to stdout and stderr: {}",
+                    log_file, err
+                )
+                .as_str(),
+            );
+        }
+    }
+    messages::set_aggregate_repeated(config.log_aggregate_repeated.unwrap_or(false));
+    config.config_file = args.config.clone();
+    config.trace_normalization = args.trace_normalization;
+    messages::print_info(
+        format!("Running readyset_scheduler ({})", config.instance_name()).as_str(),
+    );
+    // only runs that may change ProxySQL or Readyset are spread out, one-off commands answer right away
+    let startup_jitter_max_ms = config.startup_jitter_max_ms.unwrap_or(0);
+    if startup_jitter_max_ms > 0
+        && !(args.dry_run
+            || args.status
+            || args.list_caches
+            || args.check_host.is_some()
+            || args.export_candidates.is_some()
+            || args.cache_digest.is_some())
+    {
+        let jitter = Duration::from_millis(random_u64() % startup_jitter_max_ms.saturating_add(1));
+        messages::print_info(
+            format!("Sleeping {} ms of startup jitter", jitter.as_millis()).as_str(),
+        );
+        std::thread::sleep(jitter);
+    }
     let file = match OpenOptions::new()
         .read(true)
         .write(true)
@@ -40,7 +233,7 @@ fn main() {
             config
                 .clone()
                 .lock_file
-                .unwrap_or("/tmp/readyset_scheduler.lock".to_string()),
+                .unwrap_or(config::DEFAULT_LOCK_FILE.to_string()),
         ) {
         Ok(file) => file,
         Err(err) => {
@@ -49,12 +242,12 @@ fn main() {
                     "Failed to open lock file {}: {}",
                     config
                         .lock_file
-                        .unwrap_or("/tmp/readyset_scheduler.lock".to_string()),
+                        .unwrap_or(config::DEFAULT_LOCK_FILE.to_string()),
                     err
                 )
                 .as_str(),
             );
-            std::process::exit(1);
+            ExitStatus::ConfigError.exit();
         }
     };
 
@@ -62,40 +255,414 @@ fn main() {
         Ok(guard) => guard,
         Err(err) => {
             messages::print_error(format!("Failed to acquire lock: {}", err).as_str());
-            std::process::exit(1);
+            ExitStatus::LockContention.exit();
         }
     };
 
-    let mut proxysql = ProxySQL::new(&config, args.dry_run);
+    if let Err(err) = signals::handle_stop_signals() {
+        messages::print_error(format!("Failed to handle stop signals: {}", err).as_str());
+        ExitStatus::Failure.exit();
+    }
+
+    let interval_s = match args.interval_s {
+        Some(interval_s) => interval_s,
+        None => run_catching_panics(&args, config).exit(),
+    };
+
+    // the lock is held for the whole life of the process, so cron-started runs can't overlap
+    while !signals::stop_requested() {
+        let started_at = Instant::now();
+        let status = run_catching_panics(&args, config.clone());
+        if status != ExitStatus::Success {
+            messages::print_warning(
+                format!(
+                    "Run finished with {:?}, next run in {} seconds",
+                    status, interval_s
+                )
+                .as_str(),
+            );
+        }
+        let next_run_at = started_at + Duration::from_secs(interval_s);
+        while !signals::stop_requested() && Instant::now() < next_run_at {
+            std::thread::sleep(Duration::from_millis(100));
+        }
+    }
+    messages::print_info("Stopping readyset_scheduler");
+    ExitStatus::Success.exit();
+}
+
+/// Generates a random number, seeded by the hasher randomness of the standard library.
+///
+/// # Returns
+///
+/// A random number, different in each process.
+fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+/// Runs the scheduler once, turning a panic into a partial failure.
+///
+/// # Arguments
+///
+/// * `args` - The command line arguments.
+/// * `config` - The scheduler configuration.
+///
+/// # Returns
+///
+/// The exit status of the run.
+fn run_catching_panics(args: &Args, config: config::Config) -> ExitStatus {
+    // a panic leaves the run half done, the panic message is already printed by the default hook
+    std::panic::catch_unwind(AssertUnwindSafe(|| run(args, config)))
+        .unwrap_or(ExitStatus::PartialFailure)
+}
+
+/// Runs the scheduler once, while holding the lock.
+///
+/// # Arguments
+///
+/// * `args` - The command line arguments.
+/// * `config` - The scheduler configuration.
+///
+/// # Returns
+///
+/// The exit status of the run.
+fn run(args: &Args, config: config::Config) -> ExitStatus {
+    retries::set_max_total_retries(config.max_total_retries);
+    // the export only reads ProxySQL and checks support, it runs as a dry run in case anything else is reached
+    let mut dry_run = args.dry_run || args.export_candidates.is_some();
+    let observe_first_runs = config.observe_first_runs.unwrap_or(0);
+    if observe_first_runs > 0
+        && !(args.status
+            || args.list_caches
+            || args.drain
+            || args.undrain
+            || args.check_host.is_some())
+    {
+        let mut run_state = RunState::load(
+            format!(
+                "{}.state",
+                config
+                    .lock_file
+                    .clone()
+                    .unwrap_or(config::DEFAULT_LOCK_FILE.to_string())
+            )
+            .as_str(),
+            config.state_compression.unwrap_or(false),
+        );
+        // without the state, the run would not be counted and observation mode would never end
+        let run = match run_state.record_run() {
+            Ok(run) => run,
+            Err(err) => {
+                messages::print_error(err.as_str());
+                return ExitStatus::ConfigError;
+            }
+        };
+        if run <= observe_first_runs {
+            let first_run_at = chrono::DateTime::from_timestamp(run_state.get_first_run_at(), 0)
+                .map(|first_run_at| first_run_at.with_timezone(&chrono::Local))
+                .map_or("unknown".to_string(), |first_run_at| {
+                    first_run_at.format("%Y-%m-%d %H:%M:%S").to_string()
+                });
+            messages::print_note(
+                format!(
+                    "Observation mode, run {} of {} since {}: running as dry run, no changes will be made",
+                    run, observe_first_runs, first_run_at
+                )
+                .as_str(),
+            );
+            dry_run = true;
+        }
+    }
+
+    let mut proxysql = match ProxySQL::new(&config, dry_run, args.check_host.as_ref()) {
+        Ok(proxysql) => proxysql,
+        Err(err) => {
+            messages::print_error(format!("Failed to connect to ProxySQL: {}", err).as_str());
+            return ExitStatus::from(&err);
+        }
+    };
+
+    if let Some((hostname, port)) = &args.check_host {
+        let status = proxysql.server_status();
+        if status.is_empty() {
+            messages::print_error(
+                format!(
+                    "Readyset server {}:{} is not managed by the scheduler",
+                    hostname, port
+                )
+                .as_str(),
+            );
+            return ExitStatus::Failure;
+        }
+        println!(
+            "{}",
+            serde_json::to_string(&status).expect("Failed to serialize status")
+        );
+        let mut exit_status = ExitStatus::Success;
+        if args.update_status {
+            let mut stats = RunStats::default();
+            proxysql.health_check(&mut stats);
+            exit_status = health_check_status(&proxysql, &stats);
+        }
+        messages::print_info("Finished readyset_scheduler");
+        return exit_status;
+    }
+
+    if args.status {
+        let status = proxysql.server_status();
+        println!(
+            "{}",
+            serde_json::to_string(&status).expect("Failed to serialize status")
+        );
+        return ExitStatus::Success;
+    }
+
+    if args.list_caches {
+        let caches = match proxysql.list_caches() {
+            Ok(caches) => caches,
+            Err(err) => {
+                messages::print_error(format!("Failed to list query rules: {}", err).as_str());
+                return ExitStatus::Failure;
+            }
+        };
+        println!(
+            "{}",
+            serde_json::to_string(&caches).expect("Failed to serialize caches")
+        );
+        return ExitStatus::Success;
+    }
+
+    if let Some(path) = &args.export_candidates {
+        let mut conn = match AdminConnection::connect(&config) {
+            Ok(conn) => conn,
+            Err(err) => {
+                messages::print_error(format!("Failed to connect to ProxySQL: {}", err).as_str());
+                return ExitStatus::from(&err);
+            }
+        };
+        let mut query_discovery = queries::QueryDiscovery::new(config);
+        return match query_discovery.export_candidates(&mut proxysql, &mut conn, path) {
+            Ok(candidates) => {
+                messages::print_note(
+                    format!("Exported {} candidates to {}", candidates, path).as_str(),
+                );
+                ExitStatus::Success
+            }
+            Err(err) => {
+                messages::print_error(format!("Failed to export candidates: {}", err).as_str());
+                ExitStatus::from(&err)
+            }
+        };
+    }
+
+    if let Some(digest) = &args.cache_digest {
+        let mut conn = match AdminConnection::connect(&config) {
+            Ok(conn) => conn,
+            Err(err) => {
+                messages::print_error(format!("Failed to connect to ProxySQL: {}", err).as_str());
+                return ExitStatus::from(&err);
+            }
+        };
+        let mut stats = RunStats::default();
+        let mut plan = Plan::default();
+        let mut query_discovery = queries::QueryDiscovery::new(config);
+        return match query_discovery.cache_digest(
+            &mut proxysql,
+            &mut conn,
+            digest,
+            &mut stats,
+            &mut plan,
+        ) {
+            Ok(true) => {
+                if dry_run {
+                    messages::print_note(format!("Dry run, planned changes: {}", plan).as_str());
+                } else {
+                    messages::print_note(format!("Applied changes: {}", plan).as_str());
+                }
+                ExitStatus::Success
+            }
+            Ok(false) => ExitStatus::Failure,
+            Err(err) => {
+                messages::print_error(
+                    format!("Failed to cache digest {}: {}", digest, err).as_str(),
+                );
+                ExitStatus::from(&err)
+            }
+        };
+    }
+
+    if args.drain || args.undrain {
+        let result = match args.drain {
+            true => proxysql.drain(args.drain_wait_s),
+            false => proxysql.undrain(),
+        };
+        if let Err(err) = result {
+            messages::print_error(
+                format!(
+                    "Failed to change the status of the Readyset servers: {}",
+                    err
+                )
+                .as_str(),
+            );
+            return ExitStatus::from(&err);
+        }
+        messages::print_info("Finished readyset_scheduler");
+        return ExitStatus::Success;
+    }
+    let mut stats = RunStats::default();
+    let telemetry = Telemetry::init(&config);
+    let run_span = Span::start("scheduler_run");
+    let run_guard = run_span.enter();
+
+    let mut exit_status = ExitStatus::Success;
 
     let running_mode = match config.operation_mode {
         Some(mode) => mode,
         None => config::OperationMode::All,
     };
 
-    if running_mode == config::OperationMode::HealthCheck
-        || running_mode == config::OperationMode::All
+    if !running_mode.runs_health_check() && !running_mode.runs_query_discovery() {
+        messages::print_warning(
+            format!(
+                "operation_mode {} runs neither the health check nor query discovery, nothing to do",
+                running_mode
+            )
+            .as_str(),
+        );
+    }
+
+    let source_hostgroup = running_mode
+        .runs_query_discovery()
+        .then_some(config.source_hostgroup);
+    if let Err(err) =
+        proxysql.check_hostgroups(source_hostgroup, config.hostgroup_check.unwrap_or_default())
     {
-        proxysql.health_check();
+        messages::print_error(err.to_string().as_str());
+        return ExitStatus::from(&err);
+    }
+
+    if dry_run {
+        messages::print_note(
+            format!(
+                "Dry run, compatibility report:\n{}",
+                proxysql.compatibility_report()
+            )
+            .as_str(),
+        );
+    }
+
+    if running_mode.runs_health_check() {
+        stats.phases_run += 1;
+        let span = Span::start("health_check");
+        proxysql.health_check(&mut stats);
+        span.set_count("status_changes", stats.status_changes);
+        span.end();
+        exit_status = health_check_status(&proxysql, &stats);
     }
 
     // retain only healthy hosts
     //hosts.retain_online();
-    if running_mode == config::OperationMode::QueryDiscovery
-        || running_mode == config::OperationMode::All
-    {
-        let mut conn = Conn::new(
-            OptsBuilder::new()
-                .ip_or_hostname(Some(config.proxysql_host.as_str()))
-                .tcp_port(config.proxysql_port)
-                .user(Some(config.proxysql_user.as_str()))
-                .pass(Some(config.proxysql_password.clone().as_str()))
-                .prefer_socket(false),
-        )
-        .expect("Failed to create ProxySQL connection");
+    if running_mode.runs_query_discovery() {
+        stats.phases_run += 1;
+        let mut conn = match AdminConnection::connect(&config) {
+            Ok(conn) => conn,
+            Err(err) => {
+                messages::print_error(format!("Failed to connect to ProxySQL: {}", err).as_str());
+                return ExitStatus::from(&err);
+            }
+        };
+        let reset_stats_after_run = config.reset_stats_after_run.unwrap_or(false);
+        let mut plan = Plan::default();
+        if config.remove_dropped_schema_queries.unwrap_or(false) {
+            if let Err(err) = proxysql.remove_dropped_schema_queries(&mut plan) {
+                messages::print_error(
+                    format!("Failed to remove queries of dropped schemas: {}", err).as_str(),
+                );
+                exit_status = ExitStatus::from(&err);
+            }
+        }
+        let span = Span::start("query_discovery");
+        let guard = span.enter();
         let mut query_discovery = queries::QueryDiscovery::new(config);
-        query_discovery.run(&mut proxysql, &mut conn);
+        let discovery_result = query_discovery.run(&mut proxysql, &mut conn, &mut stats, &mut plan);
+        if let Err(err) = &discovery_result {
+            messages::print_error(format!("Query discovery failed: {}", err).as_str());
+            span.set_text("error", &err.to_string());
+            exit_status = ExitStatus::from(err);
+        }
+        guard.exit();
+        span.set_count("queries_evaluated", stats.queries_evaluated);
+        span.set_count("queries_supported", stats.queries_supported);
+        span.set_count("queries_cached", stats.queries_cached);
+        span.set_count("rules_promoted", stats.rules_promoted);
+        span.end();
+        if dry_run {
+            messages::print_note(format!("Dry run, planned changes: {}", plan).as_str());
+        } else if !plan.is_empty() {
+            messages::print_info(format!("Applied changes: {}", plan).as_str());
+        }
+        // the reset is destructive, a failed discovery keeps the stats for the next run to retry
+        if reset_stats_after_run && discovery_result.is_err() {
+            messages::print_warning("Query discovery failed, not resetting query digest stats");
+        } else if reset_stats_after_run {
+            if let Err(err) = proxysql.reset_query_digest_stats() {
+                messages::print_error(
+                    format!("Failed to reset query digest stats: {}", err).as_str(),
+                );
+                exit_status = ExitStatus::from(&err);
+            }
+        }
+    }
+
+    run_guard.exit();
+    run_span.end();
+    if let Some(telemetry) = telemetry {
+        telemetry.shutdown();
     }
 
+    messages::print_repeated_summary();
+    stats.retries_remaining = retries::get_remaining();
+    messages::print_note(stats.to_string().as_str());
     messages::print_info("Finished readyset_scheduler");
+    exit_status
+}
+
+/// Classifies the outcome of the health check.
+///
+/// # Arguments
+///
+/// * `proxysql` - The ProxySQL instance, after the health check.
+/// * `stats` - The run statistics, updated by the health check.
+///
+/// # Returns
+///
+/// ReadysetUnreachable if no Readyset server is online because some couldn't be reached,
+/// PartialFailure if only some of them couldn't be reached, Success otherwise.
+fn health_check_status(proxysql: &ProxySQL, stats: &RunStats) -> ExitStatus {
+    if stats.hosts_unreachable == 0 {
+        ExitStatus::Success
+    } else if proxysql.number_of_online_hosts() == 0 {
+        ExitStatus::ReadysetUnreachable
+    } else {
+        ExitStatus::PartialFailure
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cli_log_verbosity_is_capped_at_info() {
+        let parse = |flags: &[&str]| {
+            let command = ["readyset_proxysql_scheduler", "--config", "scheduler.cnf"];
+            Args::try_parse_from(command.iter().chain(flags.iter()))
+        };
+        let verbosity = |flags: &[&str]| cli_log_verbosity(&parse(flags).unwrap());
+        assert_eq!(verbosity(&[]), None);
+        assert_eq!(verbosity(&["-v"]), Some(MessageType::Info));
+        assert_eq!(verbosity(&["-vv"]), Some(MessageType::Info));
+        assert_eq!(verbosity(&["--quiet"]), Some(MessageType::Error));
+        assert!(parse(&["-vv", "-q"]).is_err());
+    }
 }
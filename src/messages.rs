@@ -1,4 +1,8 @@
-use std::process;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    process,
+};
 
 use chrono::{DateTime, Local};
 use once_cell::sync::Lazy;
@@ -28,7 +32,54 @@ impl std::fmt::Display for MessageType {
     }
 }
 
+/// File the messages are also written to, rotated by size.
+struct LogFile {
+    path: String,
+    file: File,
+    size: u64,
+    /// Size in bytes the file is rotated at, 0 to never rotate
+    max_size: u64,
+    /// Number of rotated files kept, as `<path>.1` to `<path>.<keep_files>`
+    keep_files: u32,
+    /// Only write to the file, not to stdout and stderr
+    only: bool,
+}
+
+impl LogFile {
+    /// Writes a line to the file, rotating it first if the line would take it over max_size.
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.max_size > 0 && self.size > 0 && self.size + len > self.max_size {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += len;
+        Ok(())
+    }
+
+    /// Shifts the rotated files, dropping the oldest one, and starts a new file.
+    fn rotate(&mut self) -> std::io::Result<()> {
+        if self.keep_files > 0 {
+            for index in (1..self.keep_files).rev() {
+                let rotated = format!("{}.{}", self.path, index);
+                if fs::metadata(&rotated).is_ok() {
+                    fs::rename(&rotated, format!("{}.{}", self.path, index + 1))?;
+                }
+            }
+            fs::rename(&self.path, format!("{}.1", self.path))?;
+        }
+        self.file = File::create(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
 static LOG_VERBOSITY: Lazy<Mutex<MessageType>> = Lazy::new(|| Mutex::new(MessageType::default()));
+static LOG_FILE: Lazy<Mutex<Option<LogFile>>> = Lazy::new(|| Mutex::new(None));
+static AGGREGATE_REPEATED: Lazy<Mutex<bool>> = Lazy::new(|| Mutex::new(false));
+/// Category, message type and number of occurrences of the repeated messages, in order of first occurrence
+static REPEATED_MESSAGES: Lazy<Mutex<Vec<(String, MessageType, u32)>>> =
+    Lazy::new(|| Mutex::new(Vec::new()));
 
 pub fn set_log_verbosity(level: MessageType) {
     let mut verbosity = LOG_VERBOSITY.lock().unwrap();
@@ -40,34 +91,77 @@ pub fn get_log_verbosity() -> MessageType {
     *verbosity
 }
 
+/// Also writes the messages to a file, appending to it if it exists.
+///
+/// # Arguments
+///
+/// * `path` - The path of the log file.
+/// * `max_size_mb` - Size in megabytes the file is rotated at, 0 to never rotate.
+/// * `keep_files` - Number of rotated files kept.
+/// * `only` - Only write to the file, not to stdout and stderr.
+pub fn set_log_file(
+    path: &str,
+    max_size_mb: u64,
+    keep_files: u32,
+    only: bool,
+) -> std::io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+    *LOG_FILE.lock().unwrap() = Some(LogFile {
+        path: path.to_string(),
+        file,
+        size,
+        max_size: max_size_mb.saturating_mul(1024 * 1024),
+        keep_files,
+        only,
+    });
+    Ok(())
+}
+
+pub fn set_aggregate_repeated(aggregate: bool) {
+    let mut aggregate_repeated = AGGREGATE_REPEATED.lock().unwrap();
+    *aggregate_repeated = aggregate;
+}
+
 fn print_message_with_ts(message: &str, message_type: MessageType) {
+    if message_type < get_log_verbosity() {
+        return;
+    }
     let datetime_now: DateTime<Local> = Local::now();
     let date_formatted = datetime_now.format("%Y-%m-%d %H:%M:%S");
     let pid = process::id();
-    match message_type {
-        MessageType::Info => {
-            if MessageType::Info >= get_log_verbosity() {
-                println!("{} [INFO] Readyset[{}]: {}", date_formatted, pid, message);
-            }
-        }
-        MessageType::Note => {
-            if MessageType::Note >= get_log_verbosity() {
-                println!("{} [NOTE] Readyset[{}]: {}", date_formatted, pid, message);
-            }
-        }
-        MessageType::Warning => {
-            if MessageType::Warning >= get_log_verbosity() {
+    let level = match message_type {
+        MessageType::Info => "INFO",
+        MessageType::Note => "NOTE",
+        MessageType::Warning => "WARNING",
+        MessageType::Error => "ERROR",
+    };
+    let line = format!(
+        "{} [{}] Readyset[{}]: {}",
+        date_formatted, level, pid, message
+    );
+
+    // the message still goes to stdout or stderr if the log file can't be written
+    let mut log_file = LOG_FILE.lock().unwrap();
+    let written_only = match log_file.as_mut() {
+        Some(log_file) => match log_file.write_line(&line) {
+            Ok(()) => log_file.only,
+            Err(err) => {
                 eprintln!(
-                    "{} [WARNING] Readyset[{}]: {}",
-                    date_formatted, pid, message
+                    "{} [ERROR] Readyset[{}]: Failed to write to log file {}: {}",
+                    date_formatted, pid, log_file.path, err
                 );
+                false
             }
-        }
-        MessageType::Error => {
-            if MessageType::Error >= get_log_verbosity() {
-                eprintln!("{} [ERROR] Readyset[{}]: {}", date_formatted, pid, message);
-            }
-        }
+        },
+        None => false,
+    };
+    if written_only {
+        return;
+    }
+    match message_type {
+        MessageType::Info | MessageType::Note => println!("{}", line),
+        MessageType::Warning | MessageType::Error => eprintln!("{}", line),
     }
 }
 
@@ -86,3 +180,29 @@ pub fn print_warning(message: &str) {
 pub fn print_error(message: &str) {
     print_message_with_ts(message, MessageType::Error);
 }
+
+/// Prints a message that can repeat many times during a run, counting it under `category`.
+/// When aggregation is enabled, the message is printed at Info level and a single line per category
+/// is printed by `print_repeated_summary` at the end of the run.
+pub fn print_repeated(message_type: MessageType, category: &str, message: &str) {
+    let aggregate = *AGGREGATE_REPEATED.lock().unwrap();
+    if aggregate {
+        let mut repeated = REPEATED_MESSAGES.lock().unwrap();
+        match repeated.iter_mut().find(|(c, _, _)| c == category) {
+            Some((_, _, count)) => *count += 1,
+            None => repeated.push((category.to_string(), message_type, 1)),
+        }
+        print_message_with_ts(message, MessageType::Info);
+    } else {
+        print_message_with_ts(message, message_type);
+    }
+}
+
+/// Prints the number of occurrences of each category of repeated messages, at the level of the original messages.
+/// The counts are cleared, so each run reports only its own messages.
+pub fn print_repeated_summary() {
+    let mut repeated = REPEATED_MESSAGES.lock().unwrap();
+    for (category, message_type, count) in repeated.drain(..) {
+        print_message_with_ts(format!("{} x {}", count, category).as_str(), message_type);
+    }
+}
@@ -0,0 +1,102 @@
+use std::fmt;
+
+use crate::queries::Query;
+
+/// Query rule to be added, with its cache, for a query found by query discovery.
+#[derive(Debug, Clone)]
+pub struct NewRule {
+    pub query: Query,
+    pub hostgroup: u16,
+    /// Rule removed to make room for this one under max_total_caches, once this one is added
+    pub eviction: Option<Removal>,
+}
+
+/// Mirror query rule to be changed to destination once its warmup is done.
+#[derive(Debug, Clone)]
+pub struct Promotion {
+    pub rule_id: u32,
+    pub digest: String,
+    pub hostgroup: u16,
+}
+
+/// Query rule to be removed, with its cache, such as when the schema of its query no longer exists.
+#[derive(Debug, Clone)]
+pub struct Removal {
+    pub rule_id: u32,
+    pub digest: String,
+    pub hostgroup: u16,
+    /// Why the rule is removed, such as `schema shop dropped`
+    pub reason: String,
+}
+
+/// Changes to the query rules made by a run. Dry runs print it as a preview of what a live run would do,
+/// live runs apply it once query discovery has planned the new rules.
+#[derive(Debug, Default)]
+pub struct Plan {
+    pub new_rules: Vec<NewRule>,
+    pub promotions: Vec<Promotion>,
+    pub removals: Vec<Removal>,
+}
+
+impl Plan {
+    /// Checks if the plan has no changes.
+    ///
+    /// # Returns
+    ///
+    /// true if no rule is added, promoted or removed.
+    pub fn is_empty(&self) -> bool {
+        self.new_rules.is_empty() && self.promotions.is_empty() && self.removals.is_empty()
+    }
+
+    /// Gets the rules removed, including the rules evicted to make room for the new rules.
+    ///
+    /// # Returns
+    ///
+    /// The removals of the rules.
+    pub fn all_removals(&self) -> impl Iterator<Item = &Removal> {
+        self.removals.iter().chain(
+            self.new_rules
+                .iter()
+                .filter_map(|rule| rule.eviction.as_ref()),
+        )
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "no changes to the query rules");
+        }
+        write!(
+            f,
+            "{} rules to add, {} to promote, {} to remove",
+            self.new_rules.len(),
+            self.promotions.len(),
+            self.all_removals().count()
+        )?;
+        for rule in &self.new_rules {
+            write!(
+                f,
+                "\n+ digest {} (schema {}) to hostgroup {}",
+                rule.query.get_digest(),
+                rule.query.get_schema(),
+                rule.hostgroup
+            )?;
+        }
+        for promotion in &self.promotions {
+            write!(
+                f,
+                "\n~ rule ID {} of digest {} from mirror to destination hostgroup {}",
+                promotion.rule_id, promotion.digest, promotion.hostgroup
+            )?;
+        }
+        for removal in self.all_removals() {
+            write!(
+                f,
+                "\n- rule ID {} of digest {} ({})",
+                removal.rule_id, removal.digest, removal.reason
+            )?;
+        }
+        Ok(())
+    }
+}
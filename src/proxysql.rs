@@ -1,21 +1,105 @@
-use chrono::{DateTime, Local};
-use mysql::{prelude::Queryable, Conn, OptsBuilder};
+use chrono::{DateTime, FixedOffset, Local, NaiveDateTime, TimeZone};
+use std::{
+    collections::HashMap,
+    thread,
+    time::{Duration, Instant},
+};
 
 use crate::{
-    config,
-    hosts::{Host, HostStatus},
+    admin::{is_connection_lost, AdminConnection},
+    config::{self, HostgroupCheck, PromotionMode, SupportConsensus},
+    error::SchedulerError,
+    hosts::{cache_name, Host, HostStatus},
     messages,
+    plan::{Plan, Promotion, Removal},
     queries::Query,
+    run_state::RunState,
+    stats::RunStats,
+    telemetry::Span,
+    webhook::{StatusChange, Webhook},
 };
 
+/// Default start of the comment of the mirror and destination rules, used to find the rules added by the scheduler.
 const MIRROR_QUERY_TOKEN: &str = "Mirror by readyset scheduler at";
 const DESTINATION_QUERY_TOKEN: &str = "Added by readyset scheduler at";
+const DRAINING_SERVER_TOKEN: &str = "Draining by readyset scheduler since";
+const FIRST_SEEN_SERVER_TOKEN: &str = "First seen by readyset scheduler at";
+/// Added to the comment of the servers set to OFFLINE_SOFT by `drain`, so `undrain` only restores those.
+const DRAINED_SERVER_TOKEN: &str = "Drained by readyset scheduler";
+/// Marker added to the comment of the servers whose status was changed by the scheduler, if mark_managed_servers is set.
+const MANAGED_SERVER_MARKER: &str = "[readyset-scheduler]";
+/// Format of the timestamp following the query rule tokens in the comment. The offset keeps the time right
+/// across DST changes.
+const RULE_COMMENT_TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S %z";
+
+/// Format of the time in the comment of the rules added before the offset was kept, read as local time.
+const LEGACY_RULE_COMMENT_TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+/// Maximum length of the comment of the query rules, when not configured.
+const DEFAULT_RULE_COMMENT_MAX_LENGTH: usize = 255;
+
+/// Number of rule ids reserved for the scheduler rules, starting at query_rule_base_id.
+const RULE_ID_RANGE_SIZE: u32 = 10000;
+
+/// Status of a Readyset server as seen by the scheduler, reported by `--status`.
+#[derive(serde::Serialize, Debug)]
+pub struct ServerStatus {
+    hostname: String,
+    port: u16,
+    proxysql_status: String,
+    readyset_status: String,
+}
+
+/// Query rule added by the scheduler and the Readyset cache behind it, reported by `--list-caches`.
+#[derive(serde::Serialize, Debug)]
+pub struct ManagedCache {
+    digest: String,
+    /// mirror while warming up, destination once queries are routed to Readyset
+    rule_type: String,
+    /// Time the rule was added, or promoted to destination, from its comment
+    created_at: String,
+    cache_name: String,
+}
+
 pub struct ProxySQL {
     readyset_hostgroup: u16,
+    readyset_hostgroups: Vec<u16>,
+    readyset_hostgroup_schemas: HashMap<String, u16>,
     warmup_time_s: u16,
-    conn: mysql::Conn,
+    promotion_mode: PromotionMode,
+    conn: AdminConnection,
+    /// Host and port of the ProxySQL admin interface
+    address: String,
     hosts: Vec<Host>,
+    cache_offline_soft_hosts: bool,
+    dataplane: Option<Host>,
+    support_check: Option<Host>,
+    /// Config to connect to the support check host with, until it is first used
+    support_check_config: Option<config::Config>,
     dry_run: bool,
+    verify_servers_load_timeout_s: u16,
+    max_replication_lag_s: u64,
+    drain_time_s: u64,
+    startup_grace_s: u64,
+    respect_manual_offline: bool,
+    mark_managed_servers: bool,
+    max_flaps_per_hour: u32,
+    state_file: String,
+    state_compression: bool,
+    status_change_webhook: Option<Webhook>,
+    support_consensus: SupportConsensus,
+    rule_flag_in: u32,
+    rule_flag_out: Option<u32>,
+    rule_apply: bool,
+    rule_comment: String,
+    mirror_token: String,
+    destination_token: String,
+    rule_comment_max_length: usize,
+    rule_base_id: Option<u32>,
+    stats_reset_confirm: bool,
+    verify_cache_before_promote: bool,
+    promote_when_cache_ready: bool,
+    min_mirror_hits: u64,
+    persist_to_disk: bool,
 }
 
 impl ProxySQL {
@@ -24,44 +108,189 @@ impl ProxySQL {
     /// # Arguments
     ///
     /// * `config` - A reference to a config::Config containing the configuration for the ProxySQL connection.
+    /// * `dry_run` - A boolean indicating if changes should be skipped.
+    /// * `only_host` - If set, only the Readyset server with this hostname and port is managed.
     ///
     /// # Returns
     ///
     /// A new ProxySQL struct.
-    pub fn new(config: &config::Config, dry_run: bool) -> Self {
-        let mut conn = Conn::new(
-            OptsBuilder::new()
-                .ip_or_hostname(Some(config.proxysql_host.as_str()))
-                .tcp_port(config.proxysql_port)
-                .user(Some(config.proxysql_user.as_str()))
-                .pass(Some(config.proxysql_password.as_str()))
-                .prefer_socket(false),
-        )
-        .expect("Failed to create ProxySQL connection");
+    pub fn new(
+        config: &config::Config,
+        dry_run: bool,
+        only_host: Option<&(String, u16)>,
+    ) -> Result<Self, SchedulerError> {
+        let conn = AdminConnection::connect(config)?;
+        ProxySQL::with_connection(conn, config, dry_run, only_host)
+    }
 
-        let query = format!(
-            "SELECT hostname, port, status, comment FROM mysql_servers WHERE hostgroup_id = {} AND status IN ('ONLINE', 'SHUNNED')",
-            config.readyset_hostgroup
+    /// This function is used to create a new ProxySQL struct using the given connection to ProxySQL.
+    ///
+    /// # Arguments
+    ///
+    /// * `conn` - The connection to the ProxySQL admin interface.
+    /// * `config` - A reference to a config::Config containing the configuration for the ProxySQL connection.
+    /// * `dry_run` - A boolean indicating if changes should be skipped.
+    /// * `only_host` - If set, only the Readyset server with this hostname and port is managed.
+    ///
+    /// # Returns
+    ///
+    /// A new ProxySQL struct.
+    pub fn with_connection(
+        mut conn: AdminConnection,
+        config: &config::Config,
+        dry_run: bool,
+        only_host: Option<&(String, u16)>,
+    ) -> Result<Self, SchedulerError> {
+        if config.query_rule_apply.unwrap_or(true) && config.query_rule_flag_out.is_some() {
+            messages::print_warning(
+                "query_rule_flag_out has no effect while query_rule_apply is enabled, as ProxySQL stops processing rules after a match",
+            );
+        }
+
+        let readyset_hostgroup_schemas = config
+            .readyset_hostgroup_schemas
+            .clone()
+            .unwrap_or_default();
+        let mut readyset_hostgroups = vec![config.readyset_hostgroup];
+        readyset_hostgroups.extend(config.readyset_hostgroups.iter().flatten());
+        readyset_hostgroups.extend(readyset_hostgroup_schemas.values());
+        readyset_hostgroups.sort();
+        readyset_hostgroups.dedup();
+
+        let cache_offline_soft_hosts = config.cache_offline_soft_hosts.unwrap_or(false);
+        // the scheduler never sets OFFLINE_HARD, so those servers were taken out by the operator. MANAGED_SERVER_MARKER
+        // can't tell them apart, as it stays in the comment when the operator changes the status of a managed server
+        let respect_manual_offline = config.respect_manual_offline.unwrap_or(true);
+        let mut statuses = vec![
+            HostStatus::Online,
+            HostStatus::Shunned,
+            HostStatus::OfflineSoft,
+        ];
+        if !respect_manual_offline {
+            statuses.push(HostStatus::OfflineHard);
+        }
+        let mut query = format!(
+            "SELECT hostgroup_id, hostname, port, status, comment FROM mysql_servers WHERE hostgroup_id IN ({}) AND status IN ({})",
+            hostgroup_list(&readyset_hostgroups),
+            status_list(&statuses)
         );
-        let results: Vec<(String, u16, String, String)> = conn.query(query).unwrap();
+        if let Some((hostname, port)) = only_host {
+            query = format!(
+                "{} AND hostname = '{}' AND port = {}",
+                query,
+                hostname.replace('\'', "''"),
+                port
+            );
+        }
+        let results: Vec<(u16, String, u16, String, String)> = conn.query(query)?;
         let hosts = results
             .into_iter()
-            .filter_map(|(hostname, port, status, comment)| {
-                if comment.to_lowercase().contains("readyset") {
-                    Some(Host::new(hostname, port, status, config))
-                } else {
-                    None
+            .filter_map(|(hostgroup, hostname, port, status, comment)| {
+                // OFFLINE_SOFT servers are only managed if drained by the health check, or to pre-warm them
+                let offline_soft = HostStatus::from(status.clone()) == HostStatus::OfflineSoft;
+                if !comment.to_lowercase().contains("readyset")
+                    || (offline_soft
+                        && draining_since(&comment).is_none()
+                        && !cache_offline_soft_hosts)
+                {
+                    return None;
                 }
+                let mut host = Host::new(
+                    hostgroup,
+                    hostname,
+                    port,
+                    config.readyset_admin_port,
+                    status,
+                    config,
+                );
+                host.set_comment(comment);
+                Some(host)
             })
             .collect::<Vec<Host>>();
 
-        ProxySQL {
+        let dataplane = config.proxysql_dataplane_port.map(|port| {
+            if !readyset_hostgroup_schemas.is_empty() {
+                messages::print_warning(
+                    "readyset_hostgroup_schemas is ignored for support checks and cache creation through the ProxySQL data-plane, queries are routed to the default hostgroup of readyset_user",
+                );
+            }
+            Host::new(
+                config.readyset_hostgroup,
+                config
+                    .proxysql_dataplane_host
+                    .clone()
+                    .unwrap_or(config.proxysql_host.clone()),
+                port,
+                None,
+                HostStatus::Online.to_string(),
+                config,
+            )
+        });
+
+        // the pinned host is only connected to when a query support is first checked
+        let support_check_config = match (&config.support_check_host, config.support_check_port) {
+            (Some(_), Some(_)) => Some(config.clone()),
+            _ => None,
+        };
+
+        Ok(ProxySQL {
             conn,
+            address: format!("{}:{}", config.proxysql_host, config.proxysql_port),
             readyset_hostgroup: config.readyset_hostgroup,
+            readyset_hostgroups,
+            readyset_hostgroup_schemas,
             warmup_time_s: config.warmup_time_s.unwrap_or(0),
+            promotion_mode: config.promotion_mode.unwrap_or_default(),
             hosts,
+            cache_offline_soft_hosts,
+            dataplane,
+            support_check: None,
+            support_check_config,
             dry_run,
-        }
+            verify_servers_load_timeout_s: config.verify_servers_load_timeout_s.unwrap_or(0),
+            max_replication_lag_s: config.max_replication_lag_s.unwrap_or(0),
+            drain_time_s: config.drain_time_s.unwrap_or(0),
+            startup_grace_s: config.readyset_startup_grace_s.unwrap_or(0),
+            respect_manual_offline,
+            mark_managed_servers: config.mark_managed_servers.unwrap_or(false),
+            max_flaps_per_hour: config.max_flaps_per_hour.unwrap_or(0),
+            support_consensus: config.support_consensus.unwrap_or_default(),
+            status_change_webhook: config
+                .status_change_webhook
+                .as_deref()
+                .and_then(Webhook::new),
+            state_file: format!(
+                "{}.state",
+                config
+                    .lock_file
+                    .as_deref()
+                    .unwrap_or(config::DEFAULT_LOCK_FILE)
+            ),
+            state_compression: config.state_compression.unwrap_or(false),
+            rule_flag_in: config.query_rule_flag_in.unwrap_or(0),
+            rule_flag_out: config.query_rule_flag_out,
+            rule_apply: config.query_rule_apply.unwrap_or(true),
+            rule_comment: config.query_rule_comment.clone().unwrap_or_default(),
+            mirror_token: comment_token(
+                "mirror_comment_token",
+                config.mirror_comment_token.as_deref(),
+                MIRROR_QUERY_TOKEN,
+            ),
+            destination_token: comment_token(
+                "destination_comment_token",
+                config.destination_comment_token.as_deref(),
+                DESTINATION_QUERY_TOKEN,
+            ),
+            rule_comment_max_length: config
+                .query_rule_comment_max_length
+                .unwrap_or(DEFAULT_RULE_COMMENT_MAX_LENGTH),
+            rule_base_id: config.query_rule_base_id,
+            stats_reset_confirm: config.stats_reset_confirm.unwrap_or(false),
+            verify_cache_before_promote: config.verify_cache_before_promote.unwrap_or(false),
+            promote_when_cache_ready: config.promote_when_cache_ready.unwrap_or(false),
+            min_mirror_hits: config.min_mirror_hits.unwrap_or(0),
+            persist_to_disk: config.persist_to_disk.unwrap_or(true),
+        })
     }
 
     /// This function is used to get the dry_run field.
@@ -74,7 +303,27 @@ impl ProxySQL {
         self.dry_run
     }
 
+    /// This function is used to get the Readyset hostgroup a query should be routed to.
+    /// Schemas listed in readyset_hostgroup_schemas are routed to their mapped hostgroup,
+    /// any other schema is routed to readyset_hostgroup.
+    ///
+    /// # Arguments
+    ///
+    /// * `schema` - The schema name of the query.
+    ///
+    /// # Returns
+    ///
+    /// The Readyset hostgroup for the schema.
+    pub fn readyset_hostgroup_for_schema(&self, schema: &str) -> u16 {
+        self.readyset_hostgroup_schemas
+            .get(schema)
+            .copied()
+            .unwrap_or(self.readyset_hostgroup)
+    }
+
     /// This function is used to add a query rule to ProxySQL.
+    /// The rule is not added if the digest already has an active rule added by the scheduler, such as a
+    /// mirror rule added by an overlapping run.
     ///
     /// # Arguments
     ///
@@ -82,30 +331,236 @@ impl ProxySQL {
     ///
     /// # Returns
     ///
-    /// A boolean indicating if the rule was added successfully.
-    pub fn add_as_query_rule(&mut self, query: &Query) -> Result<bool, mysql::Error> {
+    /// A boolean indicating if the rule was added, false if the digest already had one.
+    pub fn add_as_query_rule(&mut self, query: &Query) -> Result<bool, SchedulerError> {
+        if let Some(rule_id) = self.find_scheduler_rule(query.get_digest())? {
+            messages::print_note(
+                format!(
+                    "Query rule {} already exists for digest {}, not adding a duplicate",
+                    rule_id,
+                    query.get_digest()
+                )
+                .as_str(),
+            );
+            return Ok(false);
+        }
         let datetime_now: DateTime<Local> = Local::now();
-        let date_formatted = datetime_now.format("%Y-%m-%d %H:%M:%S");
-        if self.warmup_time_s > 0 {
-            self.conn.query_drop(format!("INSERT INTO mysql_query_rules (username, mirror_hostgroup, active, digest, apply, comment) VALUES ('{}', {}, 1, '{}', 1, '{}: {}')", query.get_user(), self.readyset_hostgroup, query.get_digest(), MIRROR_QUERY_TOKEN, date_formatted)).expect("Failed to insert into mysql_query_rules");
-            messages::print_note("Inserted warm-up rule");
-        } else {
-            self.conn.query_drop(format!("INSERT INTO mysql_query_rules (username, destination_hostgroup, active, digest, apply, comment) VALUES ('{}', {}, 1, '{}', 1, '{}: {}')", query.get_user(), self.readyset_hostgroup, query.get_digest(), DESTINATION_QUERY_TOKEN, date_formatted)).expect("Failed to insert into mysql_query_rules");
-            messages::print_note("Inserted destination rule");
+        let flag_out = self
+            .rule_flag_out
+            .map_or("NULL".to_string(), |flag_out| flag_out.to_string());
+        let apply = self.rule_apply as u8;
+        let readyset_hostgroup = self.readyset_hostgroup_for_schema(query.get_schema());
+        // without a free id in the reserved range, ProxySQL assigns the id
+        let rule_id = match self.next_free_rule_id()? {
+            Some(rule_id) => rule_id.to_string(),
+            None => "NULL".to_string(),
+        };
+        // in Manual promotion mode, only an operator routes queries to Readyset
+        let (hostgroup_column, token, note) =
+            if self.warmup_time_s > 0 || self.promotion_mode == PromotionMode::Manual {
+                (
+                    "mirror_hostgroup",
+                    &self.mirror_token,
+                    "Inserted warm-up rule",
+                )
+            } else {
+                (
+                    "destination_hostgroup",
+                    &self.destination_token,
+                    "Inserted destination rule",
+                )
+            };
+        let comment = self.build_rule_comment(token, &datetime_now);
+        let insert = format!("INSERT INTO mysql_query_rules (rule_id, username, {}, active, digest, flagIN, flagOUT, apply, comment) VALUES ({}, '{}', {}, 1, '{}', {}, {}, {}, '{}')", hostgroup_column, rule_id, query.get_user().replace('\'', "''"), readyset_hostgroup, query.get_digest(), self.rule_flag_in, flag_out, apply, comment);
+        // the insert is not retried blindly, as it may have been applied before the connection was lost
+        match self.conn.query_drop_once(&insert) {
+            Err(SchedulerError::ProxySQL(err)) if is_connection_lost(&err) => {
+                messages::print_warning(
+                    format!(
+                        "Lost the connection to ProxySQL while inserting the rule of digest {}, checking if it was added: {}",
+                        query.get_digest(),
+                        err
+                    )
+                    .as_str(),
+                );
+                if self.find_scheduler_rule(query.get_digest())?.is_none() {
+                    self.conn.query_drop_once(&insert)?;
+                }
+            }
+            result => result?,
         }
+        messages::print_note(note);
         Ok(true)
     }
 
-    pub fn load_query_rules(&mut self) -> Result<bool, mysql::Error> {
-        self.conn
-            .query_drop("LOAD MYSQL QUERY RULES TO RUNTIME")
-            .expect("Failed to load query rules");
+    /// This function is used to find the active rule added by the scheduler for a digest.
+    ///
+    /// # Arguments
+    ///
+    /// * `digest` - The digest of the query.
+    ///
+    /// # Returns
+    ///
+    /// The ID of the rule, None if the digest has no active rule added by the scheduler.
+    fn find_scheduler_rule(&mut self, digest: &str) -> Result<Option<u32>, SchedulerError> {
+        self.conn.query_first(format!(
+            "SELECT rule_id FROM mysql_query_rules WHERE active = 1 AND digest = '{}' AND {}",
+            digest,
+            self.scheduler_rules_filter("comment")
+        ))
+    }
+
+    /// This function is used to find the lowest rule id not in use in the range reserved by
+    /// query_rule_base_id, so the scheduler rules are evaluated at a predictable position among the user rules.
+    ///
+    /// # Returns
+    ///
+    /// The free rule id, None if query_rule_base_id is not set or the range is full.
+    fn next_free_rule_id(&mut self) -> Result<Option<u32>, SchedulerError> {
+        let Some(base_id) = self.rule_base_id else {
+            return Ok(None);
+        };
+        let last_id = base_id.saturating_add(RULE_ID_RANGE_SIZE - 1);
+        let used_ids: Vec<u32> = self
+            .conn
+            .query(format!(
+                "SELECT rule_id FROM mysql_query_rules WHERE rule_id BETWEEN {} AND {} ORDER BY rule_id",
+                base_id, last_id
+            ))?;
+        let mut rule_id = base_id;
+        for used_id in used_ids {
+            if used_id > rule_id {
+                break;
+            }
+            rule_id = used_id + 1;
+        }
+        if rule_id > last_id {
+            messages::print_warning(
+                format!(
+                    "No free rule_id between {} and {}, letting ProxySQL assign it",
+                    base_id, last_id
+                )
+                .as_str(),
+            );
+            return Ok(None);
+        }
+        Ok(Some(rule_id))
+    }
+
+    /// This function is used to build the condition matching the rules added by the scheduler, by the start of their comment.
+    ///
+    /// # Arguments
+    ///
+    /// * `column` - The comment column, qualified by its table alias if needed.
+    ///
+    /// # Returns
+    ///
+    /// The condition, to be used in a WHERE clause.
+    fn scheduler_rules_filter(&self, column: &str) -> String {
+        format!(
+            "({} LIKE '{}%' ESCAPE '!' OR {} LIKE '{}%' ESCAPE '!')",
+            column,
+            like_escape(&self.mirror_token),
+            column,
+            like_escape(&self.destination_token)
+        )
+    }
+
+    /// This function is used to build the comment of a query rule added by the scheduler, escaped to be used in a statement.
+    /// The comment starts with the token and timestamp parsed by `adjust_mirror_rules`, followed by
+    /// query_rule_comment. The escaped comment is truncated to query_rule_comment_max_length, cutting
+    /// query_rule_comment but never the token and timestamp, nor an escaped quote.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - The token identifying the rule as mirror or destination.
+    /// * `datetime` - The time the rule was added or promoted.
+    ///
+    /// # Returns
+    ///
+    /// The escaped comment of the rule.
+    fn build_rule_comment(&self, token: &str, datetime: &DateTime<Local>) -> String {
+        let mut comment =
+            format!("{}: {}", token, datetime.format(RULE_COMMENT_TIME_FORMAT)).replace('\'', "''");
+        if self.rule_comment.is_empty() {
+            return comment;
+        }
+        let mut length = comment.chars().count() + 1;
+        comment.push(' ');
+        for c in self.rule_comment.chars() {
+            let escaped = match c {
+                '\'' => "''".to_string(),
+                c => c.to_string(),
+            };
+            length += escaped.chars().count();
+            if length > self.rule_comment_max_length {
+                break;
+            }
+            comment.push_str(&escaped);
+        }
+        comment.trim_end().to_string()
+    }
+
+    pub fn load_query_rules(&mut self) -> Result<bool, SchedulerError> {
+        self.conn.query_drop("LOAD MYSQL QUERY RULES TO RUNTIME")?;
         Ok(true)
     }
-    pub fn save_query_rules(&mut self) -> Result<bool, mysql::Error> {
-        self.conn
-            .query_drop("SAVE MYSQL QUERY RULES TO DISK")
-            .expect("Failed to load query rules");
+    /// Saves the query rules to disk. Skipped if persist_to_disk is disabled, the rules are only loaded to runtime.
+    pub fn save_query_rules(&mut self) -> Result<bool, SchedulerError> {
+        if !self.persist_to_disk {
+            return Ok(false);
+        }
+        self.conn.query_drop("SAVE MYSQL QUERY RULES TO DISK")?;
+        Ok(true)
+    }
+
+    /// This function is used to load the servers to runtime, so the status changes in mysql_servers take effect.
+    ///
+    /// # Returns
+    ///
+    /// A boolean indicating if the servers were loaded.
+    pub fn load_servers(&mut self) -> Result<bool, SchedulerError> {
+        self.conn.query_drop("LOAD MYSQL SERVERS TO RUNTIME")?;
+        Ok(true)
+    }
+
+    /// Saves the servers to disk. Skipped if persist_to_disk is disabled, the servers are only loaded to runtime.
+    pub fn save_servers(&mut self) -> Result<bool, SchedulerError> {
+        if !self.persist_to_disk {
+            return Ok(false);
+        }
+        self.conn.query_drop("SAVE MYSQL SERVERS TO DISK")?;
+        Ok(true)
+    }
+
+    /// This function is used to reset the query digest stats in ProxySQL.
+    /// Reading stats_mysql_query_digest_reset clears stats_mysql_query_digest, which affects any
+    /// other tool observing it, so this is skipped in dry run mode and unless stats_reset_confirm is enabled.
+    ///
+    /// # Returns
+    ///
+    /// A boolean indicating if the stats were reset.
+    pub fn reset_query_digest_stats(&mut self) -> Result<bool, SchedulerError> {
+        if !self.stats_reset_confirm {
+            messages::print_warning(
+                "reset_stats_after_run requires stats_reset_confirm to be enabled, skipping reset of stats_mysql_query_digest",
+            );
+            return Ok(false);
+        }
+        if self.dry_run {
+            messages::print_note("Dry run, would reset stats_mysql_query_digest");
+            return Ok(false);
+        }
+        let digests: Option<u64> = self
+            .conn
+            .query_first("SELECT COUNT(*) FROM stats_mysql_query_digest_reset")?;
+        messages::print_note(
+            format!(
+                "Reset stats_mysql_query_digest, cleared {} digests",
+                digests.unwrap_or(0)
+            )
+            .as_str(),
+        );
         Ok(true)
     }
 
@@ -116,150 +571,2223 @@ impl ProxySQL {
     ///
     /// # Returns
     /// A vector of tuples containing the digest_text, digest, and schemaname of the queries that are currently routed to ReadySet.
-    pub fn find_queries_routed_to_readyset(&mut self) -> Vec<String> {
-        let rows: Vec<String> = self
-            .conn
-            .query(format!(
-            "SELECT digest FROM mysql_query_rules WHERE comment LIKE '{}%' OR comment LIKE '{}%'",
-            MIRROR_QUERY_TOKEN, DESTINATION_QUERY_TOKEN
+    pub fn find_queries_routed_to_readyset(&mut self) -> Result<Vec<String>, SchedulerError> {
+        self.conn.query(format!(
+            "SELECT digest FROM mysql_query_rules WHERE {}",
+            self.scheduler_rules_filter("comment")
         ))
-            .expect("Failed to find queries routed to ReadySet");
-        rows
+    }
+
+    /// This function is used to list the query rules added by the scheduler and their Readyset caches.
+    ///
+    /// # Returns
+    ///
+    /// The digest, rule type, creation time and cache name of each query rule added by the scheduler.
+    pub fn list_caches(&mut self) -> Result<Vec<ManagedCache>, SchedulerError> {
+        let rows: Vec<(String, String)> = self.conn.query(format!(
+            "SELECT digest, comment FROM mysql_query_rules WHERE {} ORDER BY rule_id",
+            self.scheduler_rules_filter("comment")
+        ))?;
+        Ok(rows
+            .into_iter()
+            .map(|(digest, comment)| {
+                let (rule_type, token) = match comment.starts_with(&self.mirror_token) {
+                    true => ("mirror", &self.mirror_token),
+                    false => ("destination", &self.destination_token),
+                };
+                ManagedCache {
+                    cache_name: cache_name(&digest),
+                    digest,
+                    rule_type: rule_type.to_string(),
+                    created_at: rule_timestamp(&comment, token),
+                }
+            })
+            .collect())
     }
 
     /// This function is used to check if any mirror query rule needs to be changed to destination.
+    /// Only rules still mirroring are promoted, and their comment is replaced by the destination comment,
+    /// so promoting is idempotent and the comment doesn't grow. Rules whose comment already has the
+    /// destination token, such as rules promoted by older versions of the scheduler, are skipped.
+    /// If promote_when_cache_ready is set, a rule is promoted before warmup_time_s elapses once its cache
+    /// exists on all the online Readyset hosts of the hostgroup and has been warmed by mirrored queries.
+    /// If min_mirror_hits is set, a rule is only promoted once at least that many queries were mirrored
+    /// to its hostgroup, otherwise its warmup is extended to the next run.
+    /// The promotions are added to the plan, and only applied if not in dry run.
+    /// In Manual promotion mode, the rules due to be promoted are only reported, for an operator to promote them.
+    ///
+    /// # Arguments
+    ///
+    /// * `plan` - The plan of the run, to add the promotions to.
+    ///
+    /// # Returns
+    ///
+    /// The number of mirror query rules changed to destination.
+    pub fn adjust_mirror_rules(&mut self, plan: &mut Plan) -> Result<u32, SchedulerError> {
+        let mut updated_rules = 0;
+        if self.promotion_mode == PromotionMode::Manual {
+            for promotion in self.plan_promotions()? {
+                messages::print_note(
+                    format!(
+                        "Rule ID {} of digest {} is ready to be promoted to destination hostgroup {}, promotion_mode is Manual. To promote it: {}; LOAD MYSQL QUERY RULES TO RUNTIME; SAVE MYSQL QUERY RULES TO DISK;",
+                        promotion.rule_id,
+                        promotion.digest,
+                        promotion.hostgroup,
+                        self.promotion_statement(&promotion)
+                    )
+                    .as_str(),
+                );
+            }
+            return Ok(updated_rules);
+        }
+        for promotion in self.plan_promotions()? {
+            if !self.dry_run {
+                self.promote_rule(&promotion)?;
+                updated_rules += 1;
+            }
+            plan.promotions.push(promotion);
+        }
+        Ok(updated_rules)
+    }
+
+    /// This function is used to find the mirror query rules that are due to be changed to destination.
     ///
     /// # Returns
     ///
-    /// A boolean indicating if any mirror query rule was changed to destination.
-    pub fn adjust_mirror_rules(&mut self) -> Result<bool, mysql::Error> {
-        let mut updated_rules = false;
+    /// The promotions of the mirror query rules whose warmup is done.
+    fn plan_promotions(&mut self) -> Result<Vec<Promotion>, SchedulerError> {
+        let mut promotions = Vec::new();
         let datetime_now: DateTime<Local> = Local::now();
-        let tz = datetime_now.format("%z").to_string();
-        let date_formatted = datetime_now.format("%Y-%m-%d %H:%M:%S");
-        let rows: Vec<(u16, String)> = self.conn.query(format!("SELECT rule_id, comment FROM mysql_query_rules WHERE comment LIKE '{}: ____-__-__ __:__:__';", MIRROR_QUERY_TOKEN)).expect("Failed to select mirror rules");
-        for (rule_id, comment) in rows {
-            let datetime_mirror_str = comment
-                .split("Mirror by readyset scheduler at:")
-                .nth(1)
-                .unwrap_or("")
-                .trim();
-            let datetime_mirror_str = format!("{} {}", datetime_mirror_str, tz);
+        let rows: Vec<(u32, u16, String, String)> = self.conn.query(format!("SELECT rule_id, mirror_hostgroup, digest, comment FROM mysql_query_rules WHERE comment LIKE '{}: ____-__-__ __:__:__%' ESCAPE '!' AND comment NOT LIKE '%{}%' ESCAPE '!' AND mirror_hostgroup IS NOT NULL AND destination_hostgroup IS NULL;", like_escape(&self.mirror_token), like_escape(&self.destination_token)))?;
+        for (rule_id, readyset_hostgroup, digest, comment) in rows {
+            // a rule promoted by an earlier run is not promoted again
+            if comment.contains(self.destination_token.as_str()) {
+                continue;
+            }
             let datetime_mirror_rule =
-                DateTime::parse_from_str(datetime_mirror_str.as_str(), "%Y-%m-%d %H:%M:%S %z")
-                    .unwrap_or_else(|_| {
-                        panic!("Failed to parse datetime from comment: {}", comment);
-                    });
+                rule_datetime(&comment, &self.mirror_token).ok_or_else(|| {
+                    SchedulerError::Query(format!(
+                        "Failed to parse datetime from comment of rule ID {}: {}",
+                        rule_id, comment
+                    ))
+                })?;
             let elapsed = datetime_now
                 .signed_duration_since(datetime_mirror_rule)
                 .num_seconds();
-            if elapsed > self.warmup_time_s as i64 {
-                let comment = format!(
-                    "{}\n Added by readyset scheduler at: {}",
-                    comment, date_formatted
-                );
-                self.conn.query_drop(format!("UPDATE mysql_query_rules SET mirror_hostgroup = NULL, destination_hostgroup = {}, comment = '{}' WHERE rule_id = {}", self.readyset_hostgroup, comment, rule_id)).expect("Failed to update rule");
+            let ready_early = elapsed <= self.warmup_time_s as i64
+                && self.promote_when_cache_ready
+                && self.cache_is_warm(readyset_hostgroup, &digest)?;
+            if ready_early {
                 messages::print_note(
-                    format!("Updated rule ID {} from warmup to destination", rule_id).as_str(),
+                    format!(
+                        "Cache for rule ID {} is warm on all online hosts after {} of {} seconds of warmup, promoting early",
+                        rule_id, elapsed, self.warmup_time_s
+                    )
+                    .as_str(),
                 );
-                updated_rules = true;
+            }
+            if elapsed > self.warmup_time_s as i64 || ready_early {
+                if self.verify_cache_before_promote
+                    && !self.cache_is_ready(readyset_hostgroup, &digest)
+                {
+                    messages::print_note(
+                        format!(
+                            "Cache for rule ID {} is not ready yet, skipping promotion to destination",
+                            rule_id
+                        )
+                        .as_str(),
+                    );
+                    continue;
+                }
+                if self.min_mirror_hits > 0 {
+                    let mirror_hits = self.mirror_hits(readyset_hostgroup, &digest)?;
+                    if mirror_hits < self.min_mirror_hits {
+                        messages::print_note(
+                            format!(
+                                "Rule ID {} mirrored {} of {} queries, extending warmup",
+                                rule_id, mirror_hits, self.min_mirror_hits
+                            )
+                            .as_str(),
+                        );
+                        continue;
+                    }
+                }
+                promotions.push(Promotion {
+                    rule_id,
+                    digest,
+                    hostgroup: readyset_hostgroup,
+                });
             }
         }
-        Ok(updated_rules)
+        Ok(promotions)
     }
 
-    /// This function is used to check if a given host is healthy.
-    /// This is done by checking if the Readyset host has an active
-    /// connection and if the snapshot is completed.
-    pub fn health_check(&mut self) {
-        let mut status_changes = Vec::new();
+    /// This function is used to check if the cache of a mirror rule is warm enough to promote the rule before its warmup ends.
+    /// The cache has to exist on all the online Readyset hosts of the hostgroup, and to have served mirrored queries,
+    /// at least min_mirror_hits of them if set, as a cache that was only created has not been filled yet.
+    ///
+    /// # Arguments
+    ///
+    /// * `hostgroup` - The Readyset hostgroup the query is mirrored to.
+    /// * `digest` - The digest of the query.
+    ///
+    /// # Returns
+    ///
+    /// A boolean indicating if the cache is warm.
+    fn cache_is_warm(&mut self, hostgroup: u16, digest: &str) -> Result<bool, SchedulerError> {
+        if self.mirror_hits(hostgroup, digest)? < self.min_mirror_hits.max(1) {
+            return Ok(false);
+        }
+        Ok(self.cache_is_ready_on_all_hosts(hostgroup, digest))
+    }
 
-        for host in self.hosts.iter_mut() {
-            match host.check_readyset_is_ready() {
-                Ok(ready) => {
-                    if ready {
-                        status_changes.push((host, HostStatus::Online));
-                    } else {
-                        messages::print_note("Readyset is still running Snapshot.");
-                        status_changes.push((host, HostStatus::Shunned));
-                    }
-                }
-                Err(e) => {
-                    messages::print_error(format!("Cannot check Readyset status: {}.", e).as_str());
-                    status_changes.push((host, HostStatus::Shunned));
-                }
+    /// This function is used to count the queries of a digest mirrored to a Readyset hostgroup.
+    ///
+    /// # Arguments
+    ///
+    /// * `hostgroup` - The Readyset hostgroup the query is mirrored to.
+    /// * `digest` - The digest of the query.
+    ///
+    /// # Returns
+    ///
+    /// The count_star of the digest on the hostgroup in stats_mysql_query_digest.
+    fn mirror_hits(&mut self, hostgroup: u16, digest: &str) -> Result<u64, SchedulerError> {
+        let hits: Option<u64> = self.conn.query_first(format!(
+            "SELECT COALESCE(SUM(count_star), 0) FROM stats_mysql_query_digest WHERE hostgroup = {} AND digest = '{}'",
+            hostgroup, digest
+        ))?;
+        Ok(hits.unwrap_or(0))
+    }
+
+    /// This function is used to build the statement changing a mirror query rule to destination, replacing its comment.
+    ///
+    /// # Arguments
+    ///
+    /// * `promotion` - The promotion of the rule.
+    ///
+    /// # Returns
+    ///
+    /// The UPDATE statement of the rule.
+    fn promotion_statement(&self, promotion: &Promotion) -> String {
+        let comment = self.build_rule_comment(&self.destination_token, &Local::now());
+        format!("UPDATE mysql_query_rules SET mirror_hostgroup = NULL, destination_hostgroup = {}, comment = '{}' WHERE rule_id = {} AND destination_hostgroup IS NULL", promotion.hostgroup, comment, promotion.rule_id)
+    }
+
+    /// This function is used to change a mirror query rule to destination, replacing its comment.
+    ///
+    /// # Arguments
+    ///
+    /// * `promotion` - The promotion of the rule.
+    fn promote_rule(&mut self, promotion: &Promotion) -> Result<(), SchedulerError> {
+        let statement = self.promotion_statement(promotion);
+        self.conn.query_drop(statement)?;
+        messages::print_note(
+            format!(
+                "Updated rule ID {} from warmup to destination",
+                promotion.rule_id
+            )
+            .as_str(),
+        );
+        Ok(())
+    }
+
+    /// This function is used to remove the caches and query rules of queries whose schema no longer exists.
+    /// The schema of each query routed to Readyset is looked up in stats_mysql_query_digest, queries
+    /// no longer in the stats are skipped. The schema is checked on an online Readyset host.
+    /// The removals are added to the plan, and only applied if not in dry run.
+    ///
+    /// # Arguments
+    ///
+    /// * `plan` - The plan of the run, to add the removals to.
+    ///
+    /// # Returns
+    ///
+    /// The number of query rules removed.
+    pub fn remove_dropped_schema_queries(
+        &mut self,
+        plan: &mut Plan,
+    ) -> Result<u32, SchedulerError> {
+        let mut removed_rules = 0;
+        for removal in self.plan_removals()? {
+            if !self.dry_run {
+                self.remove_rule(&removal)?;
+                removed_rules += 1;
+            }
+            plan.removals.push(removal);
+        }
+        if removed_rules > 0 {
+            self.load_query_rules()?;
+            self.save_query_rules()?;
+        }
+        Ok(removed_rules)
+    }
+
+    /// This function is used to find the query rules of queries whose schema no longer exists.
+    ///
+    /// # Returns
+    ///
+    /// The removals of the query rules.
+    fn plan_removals(&mut self) -> Result<Vec<Removal>, SchedulerError> {
+        let rules: Vec<(u32, u16, String)> = self.conn.query(format!(
+            "SELECT rule_id, COALESCE(destination_hostgroup, mirror_hostgroup), digest FROM mysql_query_rules WHERE {}",
+            self.scheduler_rules_filter("comment")
+        ))?;
+        let mut removals = Vec::new();
+        for (rule_id, hostgroup, digest) in rules {
+            let schema: Option<String> = self.conn.query_first(format!(
+                "SELECT schemaname FROM stats_mysql_query_digest WHERE digest = '{}' LIMIT 1",
+                digest
+            ))?;
+            let Some(schema) = schema else {
+                continue;
             };
+            let exists = match self.get_first_online_host(hostgroup) {
+                Some(host) => host.schema_exists(&schema),
+                None => continue,
+            };
+            match exists {
+                Ok(true) => continue,
+                Ok(false) => {}
+                Err(err) => {
+                    messages::print_warning(
+                        format!("Failed to check if schema {} exists: {}", schema, err).as_str(),
+                    );
+                    continue;
+                }
+            }
+            removals.push(Removal {
+                rule_id,
+                digest,
+                hostgroup,
+                reason: format!("schema {} dropped", schema),
+            });
         }
+        Ok(removals)
+    }
 
-        for (host, status) in status_changes {
-            if host.get_status() != status {
-                let where_clause = format!(
-                    "WHERE hostgroup_id = {} AND hostname = '{}' AND port = {}",
-                    self.readyset_hostgroup,
-                    host.get_hostname(),
-                    host.get_port()
-                );
-                messages::print_note(
+    /// This function is used to remove a query rule and its cache on the online Readyset hosts.
+    ///
+    /// # Arguments
+    ///
+    /// * `removal` - The removal of the rule.
+    pub fn remove_rule(&mut self, removal: &Removal) -> Result<(), SchedulerError> {
+        messages::print_note(
+            format!(
+                "Removing cache and rule ID {} of digest {}: {}",
+                removal.rule_id, removal.digest, removal.reason
+            )
+            .as_str(),
+        );
+        for host in self.get_online_hosts(removal.hostgroup) {
+            if let Err(err) = host.drop_cache(&removal.digest) {
+                messages::print_warning(
                     format!(
-                        "Server HG: {}, Host: {}, Port: {} is currently {}. Changing to {}",
-                        self.readyset_hostgroup,
+                        "Failed to drop readyset cache on host {}:{}: {}",
                         host.get_hostname(),
                         host.get_port(),
-                        host.get_status(),
-                        status
+                        err
                     )
                     .as_str(),
                 );
-                host.change_status(status);
-                if self.dry_run {
-                    messages::print_info("Dry run, skipping changes to ProxySQL");
-                    continue;
-                }
-                let _ = self.conn.query_drop(format!(
-                    "UPDATE mysql_servers SET status = '{}' {}",
-                    host.get_status(),
-                    where_clause
-                ));
-                let _ = self.conn.query_drop("LOAD MYSQL SERVERS TO RUNTIME");
-                let _ = self.conn.query_drop("SAVE MYSQL SERVERS TO DISK");
             }
         }
+        self.delete_query_rule(removal.rule_id)
     }
 
-    /// This function is used to get the number of online hosts.
-    /// This is done by filtering the hosts vector and counting the number of hosts with status Online.
+    /// This function is used to find the query rule with the fewest executions in stats_mysql_query_digest,
+    /// to make room for a query with more executions. The rule is not removed, so it is kept if the cache
+    /// of the query fails to be created.
     ///
-    /// # Returns
+    /// # Arguments
     ///
-    /// A u16 containing the number of online hosts.
-    pub fn number_of_online_hosts(&self) -> u16 {
-        self.hosts
-            .iter()
-            .filter(|host| host.is_online())
-            .collect::<Vec<&Host>>()
-            .len() as u16
-    }
-
-    /// This function is used to get the first online host.
-    /// This is done by iterating over the hosts vector and returning the first host with status Online.
+    /// * `query` - The query to make room for.
+    /// * `plan` - The plan of the run, whose rules already planned to be evicted are skipped.
     ///
     /// # Returns
     ///
-    /// An Option containing a reference to the first online host.
-    pub fn get_first_online_host(&mut self) -> Option<&mut Host> {
-        self.hosts.iter_mut().find(|host| host.is_online())
+    /// The removal of the rule. None if every rule has at least as many executions as the query.
+    pub fn plan_eviction(
+        &mut self,
+        query: &Query,
+        plan: &Plan,
+    ) -> Result<Option<Removal>, SchedulerError> {
+        let count_star = query.get_metrics().map_or(0, |metrics| metrics.count_star);
+        let planned: Vec<String> = plan
+            .all_removals()
+            .map(|removal| removal.rule_id.to_string())
+            .collect();
+        let not_planned = match planned.is_empty() {
+            true => String::new(),
+            false => format!(" AND q.rule_id NOT IN ({})", planned.join(", ")),
+        };
+        let lowest: Option<(u32, u16, String, u64)> = self
+            .conn
+            .query_first(format!(
+                "SELECT q.rule_id, COALESCE(q.destination_hostgroup, q.mirror_hostgroup), q.digest, COALESCE(SUM(s.count_star), 0) AS count_star
+    FROM mysql_query_rules q
+    LEFT JOIN stats_mysql_query_digest s
+    ON s.digest = q.digest
+    WHERE {}{}
+    GROUP BY q.rule_id
+    ORDER BY count_star ASC
+    LIMIT 1",
+                self.scheduler_rules_filter("q.comment"),
+                not_planned
+            ))?;
+        let Some((rule_id, hostgroup, digest, lowest_count_star)) = lowest else {
+            return Ok(None);
+        };
+        if lowest_count_star >= count_star {
+            return Ok(None);
+        }
+        Ok(Some(Removal {
+            rule_id,
+            digest,
+            hostgroup,
+            reason: format!(
+                "evicted for digest {}, {} executions against {}",
+                query.get_digest(),
+                count_star,
+                lowest_count_star
+            ),
+        }))
     }
 
-    /// This function is used to get all the online hosts.
-    /// This is done by filtering the hosts vector and collecting the hosts with status Online.
+    /// This function is used to count the caches on the online Readyset hosts, including the caches not
+    /// created by the scheduler.
     ///
     /// # Returns
     ///
-    /// A vector containing references to the online hosts.
-    pub fn get_online_hosts(&mut self) -> Vec<&mut Host> {
+    /// The highest number of caches on an online host. None if no online host could be checked.
+    pub fn count_caches(&mut self) -> Option<u32> {
         self.hosts
             .iter_mut()
             .filter(|host| host.is_online())
-            .collect()
+            .filter_map(|host| match host.count_caches() {
+                Ok(count) => Some(count),
+                Err(err) => {
+                    messages::print_warning(
+                        format!(
+                            "Failed to count caches on host {}:{}: {}",
+                            host.get_hostname(),
+                            host.get_port(),
+                            err
+                        )
+                        .as_str(),
+                    );
+                    None
+                }
+            })
+            .max()
+    }
+
+    /// This function is used to delete a query rule. The rules are not loaded to runtime.
+    ///
+    /// # Arguments
+    ///
+    /// * `rule_id` - The ID of the rule.
+    pub fn delete_query_rule(&mut self, rule_id: u32) -> Result<(), SchedulerError> {
+        self.conn.query_drop(format!(
+            "DELETE FROM mysql_query_rules WHERE rule_id = {}",
+            rule_id
+        ))
+    }
+
+    /// This function is used to find the query rules added by the scheduler that route to Readyset.
+    ///
+    /// # Returns
+    ///
+    /// The rule ID, destination hostgroup and digest of each destination rule.
+    pub fn find_destination_rules(&mut self) -> Result<Vec<(u32, u16, String)>, SchedulerError> {
+        self.conn
+            .query(format!(
+                "SELECT rule_id, destination_hostgroup, digest FROM mysql_query_rules WHERE comment LIKE '{}%' ESCAPE '!' AND destination_hostgroup IS NOT NULL",
+                like_escape(&self.destination_token)
+            ))
+    }
+
+    /// This function is used to check if the cache for a digest exists on an online Readyset host.
+    ///
+    /// # Arguments
+    ///
+    /// * `hostgroup` - The Readyset hostgroup the query is routed to.
+    /// * `digest` - The digest of the query.
+    ///
+    /// # Returns
+    ///
+    /// A boolean indicating if the cache exists. false if there is no online host or the check failed.
+    fn cache_is_ready(&mut self, hostgroup: u16, digest: &str) -> bool {
+        match self.get_first_online_host(hostgroup) {
+            Some(host) => match host.has_cache(digest) {
+                Ok(exists) => exists,
+                Err(err) => {
+                    messages::print_warning(
+                        format!("Failed to check cache for digest {}: {}", digest, err).as_str(),
+                    );
+                    false
+                }
+            },
+            None => false,
+        }
+    }
+
+    /// This function is used to check if the cache for a digest exists on all the online Readyset hosts of a hostgroup.
+    ///
+    /// # Arguments
+    ///
+    /// * `hostgroup` - The Readyset hostgroup the query is routed to.
+    /// * `digest` - The digest of the query.
+    ///
+    /// # Returns
+    ///
+    /// A boolean indicating if the cache exists on all the hosts. false if there is no online host or a check failed.
+    fn cache_is_ready_on_all_hosts(&mut self, hostgroup: u16, digest: &str) -> bool {
+        let hosts = self.get_online_hosts(hostgroup);
+        !hosts.is_empty()
+            && hosts.into_iter().all(|host| match host.has_cache(digest) {
+                Ok(exists) => exists,
+                Err(err) => {
+                    messages::print_warning(
+                        format!(
+                            "Failed to check cache for digest {} on host {}:{}: {}",
+                            digest,
+                            host.get_hostname(),
+                            host.get_port(),
+                            err
+                        )
+                        .as_str(),
+                    );
+                    false
+                }
+            })
+    }
+
+    /// This function is used to check if a given host is healthy.
+    /// This is done by checking if the Readyset host has an active
+    /// connection and if the snapshot is completed.
+    /// Hosts in OFFLINE_SOFT were taken out of service by the operator and are not checked.
+    /// Hosts in OFFLINE_HARD are not managed either, unless respect_manual_offline is disabled, even if their
+    /// comment has the managed server marker, as the scheduler never sets OFFLINE_HARD itself.
+    /// If drain_time_s is set, an ONLINE host that becomes unhealthy is first set to OFFLINE_SOFT,
+    /// and only SHUNNED if it is still unhealthy after drain_time_s. The time the drain started is
+    /// kept in the comment of the server, so it persists across runs.
+    /// If readyset_startup_grace_s is set, a host still running snapshot is left in its current status
+    /// for that long after it was first seen, instead of being shunned. The time the host was first seen
+    /// is also kept in the comment of the server, and removed once the host is ready, so the grace period
+    /// applies again after a restart.
+    /// The changes to the servers are loaded to runtime once, after all the hosts are checked.
+    /// If max_flaps_per_hour is set, a host that already changed status that many times in the last hour
+    /// is not set back to ONLINE, holding it out of service until it is stable. The status changes are
+    /// kept in the run state file.
+    ///
+    /// # Arguments
+    ///
+    /// * `stats` - The run statistics, updated with the number of status changes and unreachable hosts.
+    pub fn health_check(&mut self, stats: &mut RunStats) {
+        let mut status_changes = Vec::new();
+        let mut loaded_changes = Vec::new();
+        // (hostgroup, hostname, port, comment) of the servers whose first seen time changed
+        let mut comment_updates = Vec::new();
+
+        let max_replication_lag_s = self.max_replication_lag_s;
+        let drain_time_s = self.drain_time_s;
+        let startup_grace_s = self.startup_grace_s;
+        let respect_manual_offline = self.respect_manual_offline;
+        let mark_managed_servers = self.mark_managed_servers;
+        let now = Local::now().timestamp();
+        let mut run_state = match self.max_flaps_per_hour {
+            0 => None,
+            _ => Some(RunState::load(&self.state_file, self.state_compression)),
+        };
+        for host in self.hosts.iter_mut().filter(|host| {
+            host.get_status() != HostStatus::OfflineSoft
+                || draining_since(host.get_comment()).is_some()
+        }) {
+            match host.check_readyset_is_ready() {
+                Ok(ready) => {
+                    let lag_s = host.get_replication_lag_s();
+                    let seen_s = comment_timestamp(host.get_comment(), FIRST_SEEN_SERVER_TOKEN)
+                        .map(|first_seen_at| now - first_seen_at);
+                    if ready && seen_s.is_some() {
+                        let comment =
+                            without_comment_timestamp(host.get_comment(), FIRST_SEEN_SERVER_TOKEN);
+                        host.set_comment(comment.clone());
+                        comment_updates.push((
+                            host.get_hostgroup(),
+                            host.get_hostname().clone(),
+                            host.get_port(),
+                            comment,
+                        ));
+                    }
+                    if ready
+                        && max_replication_lag_s > 0
+                        && lag_s.is_some_and(|lag_s| lag_s > max_replication_lag_s)
+                    {
+                        messages::print_note(
+                            format!(
+                                "Readyset host {}:{} replication lag is {}s, above max_replication_lag_s {}s.",
+                                host.get_hostname(),
+                                host.get_port(),
+                                lag_s.unwrap_or(0),
+                                max_replication_lag_s
+                            )
+                            .as_str(),
+                        );
+                        let reason = format!(
+                            "replication lag {}s above max_replication_lag_s {}s",
+                            lag_s.unwrap_or(0),
+                            max_replication_lag_s
+                        );
+                        status_changes.push((host, HostStatus::Shunned, "Ready", reason));
+                    } else if ready {
+                        status_changes.push((
+                            host,
+                            HostStatus::Online,
+                            "Ready",
+                            "Readyset is ready".to_string(),
+                        ));
+                    } else if startup_grace_s > 0 && seen_s.is_none() {
+                        messages::print_note(
+                            format!(
+                                "Readyset host {}:{} is new and still running Snapshot, leaving it {} for readyset_startup_grace_s {}s.",
+                                host.get_hostname(),
+                                host.get_port(),
+                                host.get_status(),
+                                startup_grace_s
+                            )
+                            .as_str(),
+                        );
+                        let comment = format!(
+                            "{} {}: {}",
+                            without_comment_timestamp(host.get_comment(), FIRST_SEEN_SERVER_TOKEN),
+                            FIRST_SEEN_SERVER_TOKEN,
+                            now
+                        );
+                        host.set_comment(comment.clone());
+                        comment_updates.push((
+                            host.get_hostgroup(),
+                            host.get_hostname().clone(),
+                            host.get_port(),
+                            comment,
+                        ));
+                    } else if startup_grace_s > 0
+                        && seen_s.is_some_and(|seen_s| seen_s < startup_grace_s as i64)
+                    {
+                        messages::print_note(
+                            format!(
+                                "Readyset host {}:{} is still running Snapshot, leaving it {} for {} of {} seconds of startup grace.",
+                                host.get_hostname(),
+                                host.get_port(),
+                                host.get_status(),
+                                seen_s.unwrap_or(0),
+                                startup_grace_s
+                            )
+                            .as_str(),
+                        );
+                    } else {
+                        messages::print_note("Readyset is still running Snapshot.");
+                        status_changes.push((
+                            host,
+                            HostStatus::Shunned,
+                            "Snapshot in progress",
+                            "Readyset is still running snapshot".to_string(),
+                        ));
+                    }
+                }
+                Err(e) => {
+                    messages::print_error(format!("Cannot check Readyset status: {}.", e).as_str());
+                    stats.hosts_unreachable += 1;
+                    status_changes.push((host, HostStatus::Shunned, "Unreachable", e.to_string()));
+                }
+            };
+        }
+
+        let mut servers_changed = false;
+        let mut webhook_changes = Vec::new();
+        for (hostgroup, hostname, port, comment) in comment_updates {
+            if self.dry_run {
+                messages::print_info("Dry run, skipping changes to ProxySQL");
+                continue;
+            }
+            let _ = self.conn.query_drop(format!(
+                "UPDATE mysql_servers SET comment = '{}' WHERE hostgroup_id = {} AND hostname = '{}' AND port = {}",
+                comment.replace('\'', "''"),
+                hostgroup,
+                hostname,
+                port
+            ));
+            servers_changed = true;
+        }
+
+        for (host, status, readyset_status, reason) in status_changes {
+            let status = match (host.get_status(), status) {
+                (HostStatus::Online, HostStatus::Shunned) if drain_time_s > 0 => {
+                    HostStatus::OfflineSoft
+                }
+                (HostStatus::OfflineSoft, HostStatus::Shunned) => {
+                    let draining_s = now - draining_since(host.get_comment()).unwrap_or(now);
+                    if draining_s < drain_time_s as i64 {
+                        messages::print_note(
+                            format!(
+                                "Server HG: {}, Host: {}, Port: {} is draining for {} of {} seconds",
+                                host.get_hostgroup(),
+                                host.get_hostname(),
+                                host.get_port(),
+                                draining_s,
+                                drain_time_s
+                            )
+                            .as_str(),
+                        );
+                        continue;
+                    }
+                    HostStatus::Shunned
+                }
+                (_, status) => status,
+            };
+            if host.get_status() != status {
+                let server = format!(
+                    "{}:{}:{}",
+                    host.get_hostgroup(),
+                    host.get_hostname(),
+                    host.get_port()
+                );
+                if let Some(run_state) = run_state.as_mut() {
+                    let flaps = run_state.count_status_changes(&server, now - 3600);
+                    // changes out of ONLINE are always allowed, as keeping the server out of service is the safer side
+                    if status == HostStatus::Online && flaps >= self.max_flaps_per_hour as usize {
+                        messages::print_warning(
+                            format!(
+                                "Server HG: {}, Host: {}, Port: {} changed status {} times in the last hour, reaching max_flaps_per_hour. Keeping it {}",
+                                host.get_hostgroup(),
+                                host.get_hostname(),
+                                host.get_port(),
+                                flaps,
+                                host.get_status()
+                            )
+                            .as_str(),
+                        );
+                        continue;
+                    }
+                }
+                let comment = match status {
+                    HostStatus::OfflineSoft => {
+                        format!(
+                            "{} {}: {}",
+                            without_draining_since(host.get_comment()),
+                            DRAINING_SERVER_TOKEN,
+                            now
+                        )
+                    }
+                    _ => without_draining_since(host.get_comment()),
+                };
+                let comment = match mark_managed_servers {
+                    true => with_managed_server_marker(&comment),
+                    false => comment,
+                };
+                let mut where_clause = format!(
+                    "WHERE hostgroup_id = {} AND hostname = '{}' AND port = {}",
+                    host.get_hostgroup(),
+                    host.get_hostname(),
+                    host.get_port()
+                );
+                // the operator may have set the server OFFLINE_HARD since it was loaded, whatever its marker
+                if respect_manual_offline {
+                    where_clause.push_str(" AND status <> 'OFFLINE_HARD'");
+                }
+                messages::print_note(
+                    format!(
+                        "Server HG: {}, Host: {}, Port: {} is currently {}. Changing to {}",
+                        host.get_hostgroup(),
+                        host.get_hostname(),
+                        host.get_port(),
+                        host.get_status(),
+                        status
+                    )
+                    .as_str(),
+                );
+                let old_status = host.get_status();
+                host.change_status(status);
+                stats.status_changes += 1;
+                if self.dry_run {
+                    messages::print_info("Dry run, skipping changes to ProxySQL");
+                    continue;
+                }
+                if self.status_change_webhook.is_some() {
+                    webhook_changes.push(StatusChange {
+                        hostgroup: host.get_hostgroup(),
+                        hostname: host.get_hostname().clone(),
+                        port: host.get_port(),
+                        old_status: old_status.to_string(),
+                        new_status: status.to_string(),
+                        readyset_status: readyset_status.to_string(),
+                        reason,
+                    });
+                }
+                if let Some(run_state) = run_state.as_mut() {
+                    run_state.record_status_change(&server, now);
+                }
+                let _ = self.conn.query_drop(format!(
+                    "UPDATE mysql_servers SET status = '{}', comment = '{}' {}",
+                    host.get_status(),
+                    comment.replace('\'', "''"),
+                    where_clause
+                ));
+                host.set_comment(comment);
+                servers_changed = true;
+                loaded_changes.push((
+                    host.get_hostgroup(),
+                    host.get_hostname().clone(),
+                    host.get_port(),
+                    status,
+                ));
+            }
+        }
+
+        if servers_changed {
+            let _ = self.conn.query_drop("LOAD MYSQL SERVERS TO RUNTIME");
+            if self.persist_to_disk {
+                let _ = self.conn.query_drop("SAVE MYSQL SERVERS TO DISK");
+            }
+        }
+
+        // the changes are posted once they are loaded, without holding the health check on the webhook
+        if let Some(webhook) = self.status_change_webhook.as_ref() {
+            webhook.post_all(webhook_changes);
+        }
+
+        if self.verify_servers_load_timeout_s > 0 {
+            for (hostgroup, hostname, port, status) in loaded_changes {
+                if !self.verify_runtime_server_status(hostgroup, &hostname, port, status) {
+                    messages::print_warning(
+                        format!(
+                            "Server HG: {}, Host: {}, Port: {} did not change to {} at runtime after {} seconds",
+                            hostgroup,
+                            hostname,
+                            port,
+                            status,
+                            self.verify_servers_load_timeout_s
+                        )
+                        .as_str(),
+                    );
+                }
+            }
+        }
+    }
+
+    /// This function is used to get the status of each Readyset server, using the same checks as `health_check`
+    /// but without changing anything in ProxySQL.
+    ///
+    /// # Returns
+    ///
+    /// A vector with the ProxySQL and Readyset status of each server.
+    pub fn server_status(&mut self) -> Vec<ServerStatus> {
+        self.hosts
+            .iter_mut()
+            .map(|host| {
+                let readyset_status = match host.check_readyset_is_ready() {
+                    Ok(true) => "Ready".to_string(),
+                    Ok(false) => "Snapshot in progress".to_string(),
+                    Err(e) => format!("Unreachable: {}", e),
+                };
+                ServerStatus {
+                    hostname: host.get_hostname().clone(),
+                    port: host.get_port(),
+                    proxysql_status: host.get_status().to_string(),
+                    readyset_status,
+                }
+            })
+            .collect()
+    }
+
+    /// This function is used to check that the source hostgroup and the Readyset hostgroups have servers in
+    /// runtime_mysql_servers, as a misconfigured hostgroup otherwise silently finds nothing to do.
+    ///
+    /// # Arguments
+    ///
+    /// * `source_hostgroup` - The source hostgroup, None if query discovery doesn't run.
+    /// * `check` - What to do with a hostgroup without servers.
+    ///
+    /// # Returns
+    ///
+    /// A config error listing the empty hostgroups if check is Error.
+    pub fn check_hostgroups(
+        &mut self,
+        source_hostgroup: Option<u16>,
+        check: HostgroupCheck,
+    ) -> Result<(), SchedulerError> {
+        if check == HostgroupCheck::Off {
+            return Ok(());
+        }
+        let mut hostgroups: Vec<(u16, &str)> = self
+            .readyset_hostgroups
+            .iter()
+            .map(|hostgroup| (*hostgroup, "Readyset"))
+            .collect();
+        if let Some(source_hostgroup) = source_hostgroup {
+            hostgroups.push((source_hostgroup, "source"));
+        }
+        let ids: Vec<u16> = hostgroups.iter().map(|(hostgroup, _)| *hostgroup).collect();
+        let existing: Vec<u16> = self.conn.query(format!(
+            "SELECT DISTINCT hostgroup_id FROM runtime_mysql_servers WHERE hostgroup_id IN ({})",
+            hostgroup_list(&ids)
+        ))?;
+        let empty: Vec<String> = hostgroups
+            .into_iter()
+            .filter(|(hostgroup, _)| !existing.contains(hostgroup))
+            .map(|(hostgroup, kind)| format!("{} hostgroup {}", kind, hostgroup))
+            .collect();
+        if empty.is_empty() {
+            return Ok(());
+        }
+        let message = format!(
+            "{} has no server in runtime_mysql_servers, check the hostgroups in the config",
+            empty.join(", ")
+        );
+        match check {
+            HostgroupCheck::Error => Err(SchedulerError::Config(message)),
+            _ => {
+                messages::print_warning(message.as_str());
+                Ok(())
+            }
+        }
+    }
+
+    /// This function is used to drain all the Readyset servers managed by the scheduler, for planned maintenance.
+    /// ONLINE and SHUNNED servers are set to OFFLINE_SOFT, so no new connections are sent to them while
+    /// existing connections finish. Query rules and caches are left untouched. The drained servers are marked
+    /// in their comment, so `undrain` leaves alone the servers set to OFFLINE_SOFT otherwise.
+    ///
+    /// # Arguments
+    ///
+    /// * `wait_s` - Optional time in seconds to wait for the connections to the drained servers to finish.
+    ///
+    /// # Returns
+    ///
+    /// The number of servers drained.
+    pub fn drain(&mut self, wait_s: Option<u64>) -> Result<usize, SchedulerError> {
+        let servers = self.change_servers_status(
+            &[HostStatus::Online, HostStatus::Shunned],
+            HostStatus::OfflineSoft,
+            "",
+            &with_comment_token("comment", DRAINED_SERVER_TOKEN),
+        )?;
+        messages::print_note(format!("Drained {} Readyset servers", servers).as_str());
+        let wait_s = match wait_s {
+            Some(wait_s) if servers > 0 && !self.dry_run => wait_s,
+            _ => return Ok(servers),
+        };
+
+        let start = Instant::now();
+        let query = format!(
+            "SELECT COALESCE(SUM(ConnUsed), 0) FROM stats_mysql_connection_pool WHERE hostgroup IN ({}) AND status = '{}'",
+            hostgroup_list(&self.readyset_hostgroups),
+            HostStatus::OfflineSoft
+        );
+        loop {
+            let connections: u64 = self.conn.query_first(&query)?.unwrap_or(0);
+            if connections == 0 {
+                messages::print_note("All connections to the drained servers finished");
+                return Ok(servers);
+            }
+            if start.elapsed() >= Duration::from_secs(wait_s) {
+                messages::print_warning(
+                    format!(
+                        "{} connections still in use on the drained servers after {} seconds",
+                        connections, wait_s
+                    )
+                    .as_str(),
+                );
+                return Ok(servers);
+            }
+            thread::sleep(Duration::from_secs(1));
+        }
+    }
+
+    /// This function is used to restore the Readyset servers drained by `drain` back to ONLINE, removing
+    /// the drain mark from their comment. Servers set to OFFLINE_SOFT by the operator or by the health check
+    /// are left alone. The next health check will shun them if they are not ready.
+    ///
+    /// # Returns
+    ///
+    /// The number of servers undrained.
+    pub fn undrain(&mut self) -> Result<usize, SchedulerError> {
+        let servers = self.change_servers_status(
+            &[HostStatus::OfflineSoft],
+            HostStatus::Online,
+            &format!(" AND comment LIKE '%{}%'", DRAINED_SERVER_TOKEN),
+            &format!(
+                "TRIM(REPLACE(REPLACE(comment, ' {0}', ''), '{0}', ''))",
+                DRAINED_SERVER_TOKEN
+            ),
+        )?;
+        messages::print_note(format!("Undrained {} Readyset servers", servers).as_str());
+        Ok(servers)
+    }
+
+    /// This function is used to change the status of all the Readyset servers in the Readyset hostgroups
+    /// that are currently in one of the given statuses.
+    ///
+    /// # Arguments
+    ///
+    /// * `from` - The statuses of the servers to change.
+    /// * `to` - The new status of the servers.
+    /// * `condition` - Extra condition on the servers to change, starting with AND, or empty.
+    /// * `comment` - SQL expression of the new comment of the servers.
+    ///
+    /// # Returns
+    ///
+    /// The number of servers changed.
+    fn change_servers_status(
+        &mut self,
+        from: &[HostStatus],
+        to: HostStatus,
+        condition: &str,
+        comment: &str,
+    ) -> Result<usize, SchedulerError> {
+        let where_clause = format!(
+            "WHERE hostgroup_id IN ({}) AND status IN ({}) AND LOWER(comment) LIKE '%readyset%'{}",
+            hostgroup_list(&self.readyset_hostgroups),
+            status_list(from),
+            condition
+        );
+        let servers: Vec<(u16, String, u16, String)> = self.conn.query(format!(
+            "SELECT hostgroup_id, hostname, port, status FROM mysql_servers {}",
+            where_clause
+        ))?;
+        for (hostgroup, hostname, port, status) in servers.iter() {
+            messages::print_note(
+                format!(
+                    "Server HG: {}, Host: {}, Port: {} is currently {}. Changing to {}",
+                    hostgroup, hostname, port, status, to
+                )
+                .as_str(),
+            );
+        }
+        if servers.is_empty() {
+            return Ok(0);
+        }
+        if self.dry_run {
+            messages::print_info("Dry run, skipping changes to ProxySQL");
+            return Ok(servers.len());
+        }
+        let comment = match self.mark_managed_servers {
+            true => with_comment_token(comment, MANAGED_SERVER_MARKER),
+            false => comment.to_string(),
+        };
+        self.conn.query_drop(format!(
+            "UPDATE mysql_servers SET status = '{}', comment = {} {}",
+            to, comment, where_clause
+        ))?;
+        self.load_servers()?;
+        self.save_servers()?;
+        Ok(servers.len())
+    }
+
+    /// This function is used to verify that a server status change took effect at runtime.
+    /// Some ProxySQL versions apply LOAD MYSQL SERVERS TO RUNTIME asynchronously, so this
+    /// re-reads runtime_mysql_servers until the status matches or verify_servers_load_timeout_s elapses.
+    ///
+    /// # Arguments
+    ///
+    /// * `hostgroup` - The hostgroup of the server.
+    /// * `hostname` - The hostname of the server.
+    /// * `port` - The port of the server.
+    /// * `status` - The expected status of the server.
+    ///
+    /// # Returns
+    ///
+    /// A boolean indicating if the runtime status matches the expected status.
+    fn verify_runtime_server_status(
+        &mut self,
+        hostgroup: u16,
+        hostname: &str,
+        port: u16,
+        status: HostStatus,
+    ) -> bool {
+        let timeout = Duration::from_secs(self.verify_servers_load_timeout_s as u64);
+        let start = Instant::now();
+        loop {
+            let runtime_status: Option<String> = self
+                .conn
+                .query_first(format!(
+                    "SELECT status FROM runtime_mysql_servers WHERE hostgroup_id = {} AND hostname = '{}' AND port = {}",
+                    hostgroup, hostname, port
+                ))
+                .unwrap_or(None);
+            if runtime_status
+                .is_some_and(|runtime_status| HostStatus::from(runtime_status) == status)
+            {
+                return true;
+            }
+            if start.elapsed() >= timeout {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(500));
+        }
+    }
+
+    /// This function is used to cache a query on all the online hosts of its Readyset hostgroup.
+    /// The cache is created concurrently, one thread per host, each using its own connection.
+    /// A failure on one host is logged and doesn't prevent the cache from being created on the others.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - A reference to the Query to be cached.
+    /// * `stats` - The run statistics, updated with the time to create the cache on each host.
+    ///
+    /// # Returns
+    ///
+    /// The number of hosts where the cache was created.
+    pub fn cache_query_on_online_hosts(&mut self, query: &Query, stats: &mut RunStats) -> u16 {
+        let hostgroup = self.readyset_hostgroup_for_schema(query.get_schema());
+        let span = Span::start("cache_query");
+        span.set_text("digest", query.get_digest());
+        let results = thread::scope(|scope| {
+            let handles = self
+                .get_online_hosts(hostgroup)
+                .into_iter()
+                .map(|host| {
+                    let span = &span;
+                    scope.spawn(move || {
+                        let host_span = span.child("create_cache");
+                        host_span.set_text("host", host.get_hostname());
+                        host_span.set_count("port", host.get_port() as u32);
+                        let start = Instant::now();
+                        let result = host.cache_query(query);
+                        host_span.set_text(
+                            "outcome",
+                            match &result {
+                                Ok(_) => "cached",
+                                Err(_) => "failed",
+                            },
+                        );
+                        host_span.end();
+                        (
+                            host.get_hostname().clone(),
+                            host.get_port(),
+                            result,
+                            start.elapsed(),
+                        )
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("Cache creation thread panicked"))
+                .collect::<Vec<_>>()
+        });
+
+        let mut cached_hosts = 0;
+        for (hostname, port, result, elapsed) in results {
+            match result {
+                Ok(_) => {
+                    messages::print_info(
+                        format!(
+                            "Created cache for digest {} on host {}:{} in {} ms",
+                            query.get_digest(),
+                            hostname,
+                            port,
+                            elapsed.as_millis()
+                        )
+                        .as_str(),
+                    );
+                    stats.cache_creation_time.record(elapsed);
+                    cached_hosts += 1
+                }
+                Err(err) => messages::print_error(
+                    format!(
+                        "Failed to create readyset cache on host {}:{}: {}, query: {} (original digest text: {})",
+                        hostname,
+                        port,
+                        err,
+                        query.get_cache_text(),
+                        query.get_original_digest_text()
+                    )
+                    .as_str(),
+                ),
+            }
+        }
+        span.set_count("cached_hosts", cached_hosts as u32);
+        span.end();
+        cached_hosts
+    }
+
+    /// This function is used to get the Readyset version of the online hosts.
+    /// Different versions across hosts are joined with a comma, so that a change in any host is detected.
+    ///
+    /// # Returns
+    ///
+    /// A string containing the sorted, distinct versions of the online hosts.
+    pub fn readyset_version(&self) -> String {
+        let mut versions = self
+            .hosts
+            .iter()
+            .filter(|host| host.is_online())
+            .filter_map(|host| host.get_version().map(|version| version.to_string()))
+            .collect::<Vec<String>>();
+        versions.sort();
+        versions.dedup();
+        versions.join(",")
+    }
+
+    /// This function is used to report the versions of ProxySQL and of each managed Readyset host, before an upgrade.
+    /// The versions are rendered as a table, and Readyset hosts of the same hostgroup running different versions
+    /// are printed as warnings.
+    ///
+    /// # Returns
+    ///
+    /// The table of versions, one row per server.
+    pub fn compatibility_report(&mut self) -> String {
+        let mut rows = vec![[
+            "Component".to_string(),
+            "Server".to_string(),
+            "Version".to_string(),
+            "Compatibility".to_string(),
+        ]];
+        let proxysql_version: Result<Option<String>, SchedulerError> =
+            self.conn.query_first("SELECT version()");
+        let (version, compatibility) = match proxysql_version {
+            Ok(Some(version)) => (version, "ok"),
+            Ok(None) => ("unknown".to_string(), "unknown"),
+            Err(err) => {
+                messages::print_warning(
+                    format!("Failed to fetch ProxySQL version: {}", err).as_str(),
+                );
+                ("unknown".to_string(), "unknown")
+            }
+        };
+        rows.push([
+            "ProxySQL".to_string(),
+            self.address.clone(),
+            version,
+            compatibility.to_string(),
+        ]);
+
+        let mut hostgroup_versions: HashMap<u16, Vec<String>> = HashMap::new();
+        for host in self.hosts.iter_mut() {
+            let version = match host.get_version() {
+                Some(version) => Ok(version.clone()),
+                None => host.fetch_version(),
+            };
+            let server = format!(
+                "{}:{}:{}",
+                host.get_hostgroup(),
+                host.get_hostname(),
+                host.get_port()
+            );
+            let (version, compatibility) = match version {
+                Ok(version) => (version.to_string(), "ok"),
+                Err(err) => {
+                    messages::print_warning(
+                        format!(
+                            "Failed to fetch Readyset version of host {}: {}",
+                            server, err
+                        )
+                        .as_str(),
+                    );
+                    ("unknown".to_string(), "unknown")
+                }
+            };
+            if compatibility != "unknown" {
+                hostgroup_versions
+                    .entry(host.get_hostgroup())
+                    .or_default()
+                    .push(version.clone());
+            }
+            rows.push([
+                "Readyset".to_string(),
+                server,
+                version,
+                compatibility.to_string(),
+            ]);
+        }
+
+        for (hostgroup, mut versions) in hostgroup_versions {
+            versions.sort();
+            versions.dedup();
+            if versions.len() > 1 {
+                messages::print_warning(
+                    format!(
+                        "Readyset hosts of hostgroup {} run different versions ({}), their support checks may disagree, see support_consensus",
+                        hostgroup,
+                        versions.join(", ")
+                    )
+                    .as_str(),
+                );
+            }
+        }
+        render_table(&rows)
+    }
+
+    /// This function is used to get the number of online hosts.
+    /// This is done by filtering the hosts vector and counting the number of hosts with status Online.
+    ///
+    /// # Returns
+    ///
+    /// A u16 containing the number of online hosts.
+    pub fn number_of_online_hosts(&self) -> u16 {
+        self.hosts
+            .iter()
+            .filter(|host| host.is_online())
+            .collect::<Vec<&Host>>()
+            .len() as u16
+    }
+
+    /// This function is used to check if a hostgroup has any online host.
+    ///
+    /// # Arguments
+    ///
+    /// * `hostgroup` - The Readyset hostgroup to look for hosts.
+    ///
+    /// # Returns
+    ///
+    /// A boolean indicating if the hostgroup has an online host.
+    fn has_online_host(&self, hostgroup: u16) -> bool {
+        self.hosts
+            .iter()
+            .any(|host| host.get_hostgroup() == hostgroup && host.is_online())
+    }
+
+    /// This function is used to get the first online host of a hostgroup.
+    /// This is done by iterating over the hosts vector and returning the first host of the hostgroup with status Online.
+    /// When connecting to Readyset through the ProxySQL data-plane, the data-plane connection is returned instead.
+    ///
+    /// # Arguments
+    ///
+    /// * `hostgroup` - The Readyset hostgroup to look for hosts.
+    ///
+    /// # Returns
+    ///
+    /// An Option containing a reference to the first online host.
+    pub fn get_first_online_host(&mut self, hostgroup: u16) -> Option<&mut Host> {
+        if self.dataplane.is_some() {
+            return match self.has_online_host(hostgroup) {
+                true => self.dataplane.as_mut(),
+                false => None,
+            };
+        }
+        self.hosts
+            .iter_mut()
+            .find(|host| host.get_hostgroup() == hostgroup && host.is_online())
+    }
+
+    /// This function is used to get the host to check query support on.
+    /// This is the host pinned by support_check_host and support_check_port if it is ready,
+    /// or the first online host of the hostgroup otherwise.
+    ///
+    /// # Arguments
+    ///
+    /// * `hostgroup` - The Readyset hostgroup to look for hosts.
+    ///
+    /// # Returns
+    ///
+    /// An Option containing a reference to the host to check query support on.
+    pub fn get_support_check_host(&mut self, hostgroup: u16) -> Option<&mut Host> {
+        if let Some(config) = self.support_check_config.take() {
+            self.support_check = connect_support_check_host(&config);
+        }
+        if self
+            .support_check
+            .as_ref()
+            .is_some_and(|host| host.is_online())
+        {
+            return self.support_check.as_mut();
+        }
+        self.get_first_online_host(hostgroup)
+    }
+
+    /// This function is used to check if Readyset supports a query, combining the answers of the hosts of the
+    /// hostgroup as set by support_consensus. With `Any`, only the support check host is asked. Otherwise every
+    /// host a cache would be created on is asked, hosts failing the check are left out, and hosts disagreeing
+    /// with each other are logged, as they may run different Readyset versions.
+    ///
+    /// # Arguments
+    ///
+    /// * `hostgroup` - The Readyset hostgroup of the query.
+    /// * `digest_text` - The text of the query.
+    /// * `schema` - The schema of the query.
+    ///
+    /// # Returns
+    ///
+    /// None if the hostgroup has no online host, the combined support otherwise, or the error of the first host
+    /// if none of them could check the query.
+    pub fn check_query_support(
+        &mut self,
+        hostgroup: u16,
+        digest_text: &String,
+        schema: &str,
+    ) -> Option<Result<bool, SchedulerError>> {
+        let consensus = self.support_consensus;
+        if consensus == SupportConsensus::Any {
+            return self
+                .get_support_check_host(hostgroup)
+                .map(|host| host.check_query_support(digest_text, schema));
+        }
+        let answers: Vec<(String, Result<bool, SchedulerError>)> = self
+            .get_online_hosts(hostgroup)
+            .into_iter()
+            .map(|host| {
+                (
+                    format!("{}:{}", host.get_hostname(), host.get_port()),
+                    host.check_query_support(digest_text, schema),
+                )
+            })
+            .collect();
+        if answers.is_empty() {
+            return None;
+        }
+        let mut supported = 0;
+        let mut checked = 0;
+        for (host, answer) in answers.iter() {
+            match answer {
+                Ok(true) => {
+                    supported += 1;
+                    checked += 1;
+                }
+                Ok(false) => checked += 1,
+                Err(err) => messages::print_warning(
+                    format!(
+                        "Readyset host {} failed to check query support, leaving it out of support_consensus: {}",
+                        host, err
+                    )
+                    .as_str(),
+                ),
+            }
+        }
+        if checked == 0 {
+            return answers
+                .into_iter()
+                .find_map(|(_, answer)| answer.err())
+                .map(Err);
+        }
+        if supported > 0 && supported < checked {
+            messages::print_warning(
+                format!(
+                    "Readyset hosts disagree on the support of query {}: {}",
+                    digest_text,
+                    answers
+                        .iter()
+                        .filter_map(|(host, answer)| match answer {
+                            Ok(true) => Some(format!("{} supported", host)),
+                            Ok(false) => Some(format!("{} not supported", host)),
+                            Err(_) => None,
+                        })
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                )
+                .as_str(),
+            );
+        }
+        Some(Ok(match consensus {
+            SupportConsensus::Any => supported > 0,
+            SupportConsensus::All => supported == checked,
+            SupportConsensus::Majority => supported * 2 > checked,
+        }))
+    }
+
+    /// This function is used to get all the online hosts of a hostgroup, to create caches on.
+    /// This is done by filtering the hosts vector and collecting the hosts of the hostgroup with status Online,
+    /// or OfflineSoft if cache_offline_soft_hosts is enabled.
+    /// When connecting to Readyset through the ProxySQL data-plane, only the data-plane connection is returned.
+    ///
+    /// # Arguments
+    ///
+    /// * `hostgroup` - The Readyset hostgroup to look for hosts.
+    ///
+    /// # Returns
+    ///
+    /// A vector containing references to the online hosts.
+    pub fn get_online_hosts(&mut self, hostgroup: u16) -> Vec<&mut Host> {
+        if self.dataplane.is_some() {
+            return match self.has_online_host(hostgroup) {
+                true => self.dataplane.iter_mut().collect(),
+                false => Vec::new(),
+            };
+        }
+        let cache_offline_soft_hosts = self.cache_offline_soft_hosts;
+        self.hosts
+            .iter_mut()
+            .filter(|host| {
+                host.get_hostgroup() == hostgroup
+                    && (host.is_online()
+                        || (cache_offline_soft_hosts
+                            && host.get_status() == HostStatus::OfflineSoft))
+            })
+            .collect()
+    }
+}
+
+/// Gets a comment token of the query rules, as configured or the default one.
+///
+/// # Arguments
+///
+/// * `option` - The name of the config option, for the warning.
+/// * `token` - The configured token, if any.
+/// * `default` - The default token.
+///
+/// # Returns
+///
+/// The configured token, or the default one if it is not set or empty.
+fn comment_token(option: &str, token: Option<&str>, default: &str) -> String {
+    match token.map(str::trim) {
+        Some("") => {
+            messages::print_warning(
+                format!("{} can't be empty, using '{}'", option, default).as_str(),
+            );
+            default.to_string()
+        }
+        Some(token) => token.to_string(),
+        None => default.to_string(),
+    }
+}
+
+/// Escapes a text to be matched literally in a LIKE pattern with `ESCAPE '!'`, also escaping its quotes.
+/// `!` is used as the escape character as backslashes are not handled the same by MySQL and SQLite string literals.
+///
+/// # Arguments
+///
+/// * `text` - The text to escape.
+///
+/// # Returns
+///
+/// The escaped text.
+fn like_escape(text: &str) -> String {
+    text.replace('!', "!!")
+        .replace('%', "!%")
+        .replace('_', "!_")
+        .replace('\'', "''")
+}
+
+/// Renders rows as a table with aligned columns, the first row being the header.
+///
+/// # Arguments
+///
+/// * `rows` - The rows of the table.
+///
+/// # Returns
+///
+/// The table, one line per row.
+fn render_table(rows: &[[String; 4]]) -> String {
+    let mut widths = [0; 4];
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    rows.iter()
+        .map(|row| {
+            row.iter()
+                .zip(widths)
+                .map(|(cell, width)| format!("{:width$}", cell, width = width))
+                .collect::<Vec<String>>()
+                .join(" | ")
+                .trim_end()
+                .to_string()
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Builds the SQL expression of a server comment with a token added, unless it already has it.
+///
+/// # Arguments
+///
+/// * `comment` - SQL expression of the comment.
+/// * `token` - The token to add.
+///
+/// # Returns
+///
+/// The SQL expression of the comment with the token.
+fn with_comment_token(comment: &str, token: &str) -> String {
+    format!(
+        "CASE WHEN {0} LIKE '%{1}%' THEN {0} ELSE TRIM({0} || ' {1}') END",
+        comment, token
+    )
+}
+
+/// Formats a list of hostgroups to be used in an IN clause.
+fn hostgroup_list(hostgroups: &[u16]) -> String {
+    hostgroups
+        .iter()
+        .map(|hostgroup| hostgroup.to_string())
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// Gets the time a server was set to OFFLINE_SOFT by the health check, from its comment.
+///
+/// # Arguments
+///
+/// * `comment` - The comment of the server in mysql_servers.
+///
+/// # Returns
+///
+/// The unix timestamp the drain started. None if the server is not being drained by the health check.
+fn draining_since(comment: &str) -> Option<i64> {
+    comment_timestamp(comment, DRAINING_SERVER_TOKEN)
+}
+
+/// Removes the time a server was set to OFFLINE_SOFT by the health check from its comment.
+///
+/// # Arguments
+///
+/// * `comment` - The comment of the server in mysql_servers.
+///
+/// # Returns
+///
+/// The comment without the drain start time.
+fn without_draining_since(comment: &str) -> String {
+    without_comment_timestamp(comment, DRAINING_SERVER_TOKEN)
+}
+
+/// Gets the time a query rule was added by the scheduler, from its comment.
+/// The timestamp is followed by its offset, except in rules added by older versions of the scheduler.
+/// Anything after it is query_rule_comment.
+///
+/// # Arguments
+///
+/// * `comment` - The comment of the rule in mysql_query_rules.
+/// * `token` - The token the comment starts with.
+///
+/// # Returns
+///
+/// The time the rule was added, as `YYYY-MM-DD HH:MM:SS +HHMM` or `YYYY-MM-DD HH:MM:SS` in local time
+/// for older rules. Empty if the comment doesn't have the token.
+fn rule_timestamp(comment: &str, token: &str) -> String {
+    let timestamp: String = comment
+        .split(format!("{}:", token).as_str())
+        .nth(1)
+        .unwrap_or("")
+        .trim_start()
+        .chars()
+        .take("YYYY-MM-DD HH:MM:SS +HHMM".len())
+        .collect();
+    match DateTime::parse_from_str(&timestamp, RULE_COMMENT_TIME_FORMAT) {
+        Ok(_) => timestamp,
+        Err(_) => timestamp
+            .chars()
+            .take("YYYY-MM-DD HH:MM:SS".len())
+            .collect(),
+    }
+}
+
+/// Connects to the host pinned by support_check_host and support_check_port.
+/// The host is only used if it is ready, support checks fall back to the first online host otherwise.
+///
+/// # Arguments
+///
+/// * `config` - The config of the scheduler.
+///
+/// # Returns
+///
+/// The support check host, shunned if it is not ready. None if it is not set.
+fn connect_support_check_host(config: &config::Config) -> Option<Host> {
+    let (Some(hostname), Some(port)) = (&config.support_check_host, config.support_check_port)
+    else {
+        return None;
+    };
+    let mut host = Host::new(
+        config.readyset_hostgroup,
+        hostname.clone(),
+        port,
+        None,
+        HostStatus::Online.to_string(),
+        config,
+    );
+    if !host.check_readyset_is_ready().unwrap_or(false) {
+        messages::print_warning(
+            format!(
+                "Support check host {}:{} is not ready, checking query support on the first online Readyset host",
+                hostname, port
+            )
+            .as_str(),
+        );
+        host.change_status(HostStatus::Shunned);
+    }
+    Some(host)
+}
+
+/// Parses the time a query rule was added by the scheduler, from its comment.
+/// Rules added by older versions of the scheduler have no offset, their time is read as local time
+/// with the offset in effect at that time, not the current one.
+///
+/// # Arguments
+///
+/// * `comment` - The comment of the rule in mysql_query_rules.
+/// * `token` - The token the comment starts with.
+///
+/// # Returns
+///
+/// The time the rule was added. None if the comment doesn't have the token or the time can't be parsed.
+fn rule_datetime(comment: &str, token: &str) -> Option<DateTime<FixedOffset>> {
+    let timestamp = rule_timestamp(comment, token);
+    if let Ok(datetime) = DateTime::parse_from_str(&timestamp, RULE_COMMENT_TIME_FORMAT) {
+        return Some(datetime);
+    }
+    let naive = NaiveDateTime::parse_from_str(&timestamp, LEGACY_RULE_COMMENT_TIME_FORMAT).ok()?;
+    Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .map(|datetime| datetime.fixed_offset())
+}
+
+/// Gets a timestamp kept by the health check in the comment of a server, as `<token>: <timestamp>`.
+///
+/// # Arguments
+///
+/// * `comment` - The comment of the server in mysql_servers.
+/// * `token` - The token preceding the timestamp.
+///
+/// # Returns
+///
+/// The unix timestamp. None if the comment doesn't have the token.
+fn comment_timestamp(comment: &str, token: &str) -> Option<i64> {
+    comment
+        .split(format!("{}:", token).as_str())
+        .nth(1)
+        .and_then(|since| since.split_whitespace().next())
+        .and_then(|since| since.parse::<i64>().ok())
+}
+
+/// Removes a timestamp kept by the health check, with its token, from the comment of a server.
+///
+/// # Arguments
+///
+/// * `comment` - The comment of the server in mysql_servers.
+/// * `token` - The token preceding the timestamp.
+///
+/// # Returns
+///
+/// The comment without the token and timestamp.
+fn without_comment_timestamp(comment: &str, token: &str) -> String {
+    match comment.split_once(token) {
+        Some((before, after)) => {
+            let after = after.trim_start_matches(':').trim_start();
+            let after = after
+                .split_once(char::is_whitespace)
+                .map_or("", |(_, rest)| rest);
+            format!("{} {}", before.trim_end(), after.trim_start())
+                .trim()
+                .to_string()
+        }
+        None => comment.trim_end().to_string(),
+    }
+}
+
+/// Adds the marker of the servers managed by the scheduler to a server comment, if not there yet.
+///
+/// # Arguments
+///
+/// * `comment` - The comment of the server in mysql_servers.
+///
+/// # Returns
+///
+/// The comment with the marker.
+fn with_managed_server_marker(comment: &str) -> String {
+    if comment.contains(MANAGED_SERVER_MARKER) {
+        return comment.to_string();
+    }
+    format!("{} {}", comment, MANAGED_SERVER_MARKER)
+        .trim()
+        .to_string()
+}
+
+/// Formats a list of server statuses to be used in an IN clause.
+fn status_list(statuses: &[HostStatus]) -> String {
+    statuses
+        .iter()
+        .map(|status| format!("'{}'", status))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{admin::fake::FakeBackend, config::test_config, hosts::fake::FakeReadyset};
+    use mysql::Value;
+
+    /// Builds a ProxySQL without Readyset hosts, running its statements on the fake backend.
+    fn proxysql(fake: &FakeBackend, extra_config: &str, dry_run: bool) -> ProxySQL {
+        let conn = AdminConnection::new(fake.clone());
+        ProxySQL::with_connection(conn, &test_config(extra_config), dry_run, None).unwrap()
+    }
+
+    /// Builds the row of a mirror rule added the given number of seconds ago.
+    fn mirror_rule(rule_id: u32, digest: &str, age_s: i64) -> Vec<Value> {
+        let added_at = Local::now() - chrono::Duration::seconds(age_s);
+        vec![
+            Value::from(rule_id.to_string()),
+            Value::from("99"),
+            Value::from(digest),
+            Value::from(format!(
+                "{}: {}",
+                MIRROR_QUERY_TOKEN,
+                added_at.format(RULE_COMMENT_TIME_FORMAT)
+            )),
+        ]
+    }
+
+    const MIRROR_RULES: &str = "SELECT rule_id, mirror_hostgroup, digest, comment";
+    const MIRROR_RULE_COLUMNS: [&str; 4] = ["rule_id", "mirror_hostgroup", "digest", "comment"];
+
+    #[test]
+    fn check_host_hostname_is_escaped() {
+        let fake = FakeBackend::default();
+        let conn = AdminConnection::new(fake.clone());
+        let only_host = ("db' OR '1'='1".to_string(), 3307);
+        ProxySQL::with_connection(conn, &test_config(""), false, Some(&only_host)).unwrap();
+        let statement = fake.statements_containing("FROM mysql_servers").remove(0);
+        assert!(statement.ends_with("AND hostname = 'db'' OR ''1''=''1' AND port = 3307"));
+    }
+
+    #[test]
+    fn drain_returns_the_error_of_the_status_change() {
+        let fake = FakeBackend::default();
+        fake.respond(
+            "SELECT hostgroup_id, hostname, port, status FROM mysql_servers",
+            &["hostgroup_id", "hostname", "port", "status"],
+            vec![vec![
+                Value::from("99"),
+                Value::from("readyset1"),
+                Value::from("3307"),
+                Value::from("ONLINE"),
+            ]],
+        )
+        .fail_on("UPDATE mysql_servers SET status", "read-only admin");
+        let mut proxysql = proxysql(&fake, "", false);
+        assert!(matches!(
+            proxysql.drain(None),
+            Err(SchedulerError::ProxySQL(_))
+        ));
+        assert!(fake
+            .statements_containing("LOAD MYSQL SERVERS TO RUNTIME")
+            .is_empty());
+    }
+
+    #[test]
+    fn undrain_only_restores_the_servers_marked_by_drain() {
+        let fake = FakeBackend::default();
+        fake.respond(
+            "SELECT hostgroup_id, hostname, port, status FROM mysql_servers",
+            &["hostgroup_id", "hostname", "port", "status"],
+            vec![vec![
+                Value::from("99"),
+                Value::from("readyset1"),
+                Value::from("3307"),
+                Value::from("ONLINE"),
+            ]],
+        );
+        let mut proxysql = proxysql(&fake, "", false);
+        proxysql.drain(None).unwrap();
+        proxysql.undrain().unwrap();
+        let updates = fake.statements_containing("UPDATE mysql_servers SET status");
+        assert_eq!(
+            updates[0],
+            format!(
+                "UPDATE mysql_servers SET status = 'OFFLINE_SOFT', comment = CASE WHEN comment LIKE '%{0}%' THEN comment ELSE TRIM(comment || ' {0}') END WHERE hostgroup_id IN (99) AND status IN ('ONLINE', 'SHUNNED') AND LOWER(comment) LIKE '%readyset%'",
+                DRAINED_SERVER_TOKEN
+            )
+        );
+        assert_eq!(
+            updates[1],
+            format!(
+                "UPDATE mysql_servers SET status = 'ONLINE', comment = TRIM(REPLACE(REPLACE(comment, ' {0}', ''), '{0}', '')) WHERE hostgroup_id IN (99) AND status IN ('OFFLINE_SOFT') AND LOWER(comment) LIKE '%readyset%' AND comment LIKE '%{0}%'",
+                DRAINED_SERVER_TOKEN
+            )
+        );
+    }
+
+    #[test]
+    fn support_check_host_is_connected_when_first_used() {
+        let fake = FakeBackend::default();
+        let readyset = FakeReadyset::default();
+        let extra_config = format!(
+            "support_check_host = '127.0.0.1'\nsupport_check_port = {}",
+            readyset.start()
+        );
+        let mut proxysql = proxysql(&fake, &extra_config, false);
+        assert!(readyset.statements_containing("").is_empty());
+        proxysql.get_support_check_host(99);
+        assert_eq!(
+            readyset.statements_containing("SHOW READYSET STATUS").len(),
+            1
+        );
+    }
+
+    #[test]
+    fn health_check_clears_the_first_seen_marker_and_loads_the_servers_once() {
+        let fake = FakeBackend::default();
+        let ready = FakeReadyset::default();
+        ready.respond(
+            "SHOW READYSET STATUS",
+            &["field", "value"],
+            vec![vec!["Status", "Online"]],
+        );
+        let snapshotting = FakeReadyset::default();
+        snapshotting.respond(
+            "SHOW READYSET STATUS",
+            &["field", "value"],
+            vec![vec!["Snapshot Status", "In Progress"]],
+        );
+        let server = |port: u16, status: &str, comment: &str| {
+            vec![
+                Value::from("99"),
+                Value::from("127.0.0.1"),
+                Value::from(port.to_string()),
+                Value::from(status),
+                Value::from(comment),
+            ]
+        };
+        let ready_port = ready.start();
+        fake.respond(
+            "FROM mysql_servers",
+            &["hostgroup_id", "hostname", "port", "status", "comment"],
+            vec![
+                server(
+                    ready_port,
+                    "SHUNNED",
+                    &format!("Readyset's node {}: 1700000000", FIRST_SEEN_SERVER_TOKEN),
+                ),
+                server(snapshotting.start(), "SHUNNED", "Readyset"),
+            ],
+        );
+        let mut proxysql = proxysql(&fake, "readyset_startup_grace_s = 600", false);
+        proxysql.health_check(&mut RunStats::default());
+        let updates = fake.statements_containing("UPDATE mysql_servers");
+        assert_eq!(updates.len(), 3);
+        assert!(updates[0].contains(&format!(
+            "SET comment = 'Readyset''s node' WHERE hostgroup_id = 99 AND hostname = '127.0.0.1' AND port = {}",
+            ready_port
+        )));
+        assert!(updates[1].contains(&format!("Readyset {}: ", FIRST_SEEN_SERVER_TOKEN)));
+        assert!(updates[2].contains("SET status = 'ONLINE', comment = 'Readyset''s node'"));
+        assert_eq!(
+            fake.statements_containing("LOAD MYSQL SERVERS TO RUNTIME")
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn manual_offline_hard_is_left_alone_even_with_the_managed_server_marker() {
+        let fake = FakeBackend::default();
+        let snapshotting = FakeReadyset::default();
+        snapshotting.respond(
+            "SHOW READYSET STATUS",
+            &["field", "value"],
+            vec![vec!["Snapshot Status", "In Progress"]],
+        );
+        fake.respond(
+            "FROM mysql_servers",
+            &["hostgroup_id", "hostname", "port", "status", "comment"],
+            vec![vec![
+                Value::from("99"),
+                Value::from("127.0.0.1"),
+                Value::from(snapshotting.start().to_string()),
+                Value::from("ONLINE"),
+                Value::from(format!("Readyset {}", MANAGED_SERVER_MARKER)),
+            ]],
+        );
+        let mut proxysql = proxysql(&fake, "", false);
+        let servers = fake.statements_containing("FROM mysql_servers").remove(0);
+        assert!(!servers.contains("OFFLINE_HARD"));
+        proxysql.health_check(&mut RunStats::default());
+        let updates = fake.statements_containing("UPDATE mysql_servers");
+        assert_eq!(updates.len(), 1);
+        assert!(updates[0].contains("SET status = 'SHUNNED'"));
+        assert!(updates[0].ends_with("AND status <> 'OFFLINE_HARD'"));
+    }
+
+    #[test]
+    fn compatibility_report_lists_the_proxysql_version() {
+        let fake = FakeBackend::default();
+        fake.respond(
+            "SELECT version()",
+            &["version()"],
+            vec![vec![Value::from("1.4.16")]],
+        );
+        let mut proxysql = proxysql(&fake, "", true);
+        let report = proxysql.compatibility_report();
+        let row = report
+            .lines()
+            .find(|line| line.starts_with("ProxySQL"))
+            .unwrap()
+            .split('|')
+            .map(|cell| cell.trim())
+            .collect::<Vec<&str>>();
+        assert_eq!(row[2..], ["1.4.16", "ok"]);
+    }
+
+    #[test]
+    fn reset_query_digest_stats_is_skipped_in_dry_run() {
+        let fake = FakeBackend::default();
+        let mut proxysql = proxysql(&fake, "stats_reset_confirm = true", true);
+        assert!(!proxysql.reset_query_digest_stats().unwrap());
+        assert!(fake
+            .statements_containing("stats_mysql_query_digest_reset")
+            .is_empty());
+    }
+
+    #[test]
+    fn draining_since_is_kept_with_the_operator_comment() {
+        let comment = format!(
+            "Readyset, operator's note {}: 1700000000",
+            DRAINING_SERVER_TOKEN
+        );
+        assert_eq!(draining_since(&comment), Some(1700000000));
+        assert_eq!(
+            without_draining_since(&comment),
+            "Readyset, operator's note"
+        );
+        assert_eq!(draining_since("Readyset"), None);
+        assert_eq!(without_draining_since("Readyset"), "Readyset");
+    }
+
+    #[test]
+    fn draining_since_is_replaced_in_the_middle_of_the_comment() {
+        let comment = format!(
+            "Readyset {}: 1700000000 {}",
+            DRAINING_SERVER_TOKEN, MANAGED_SERVER_MARKER
+        );
+        assert_eq!(draining_since(&comment), Some(1700000000));
+        assert_eq!(
+            without_draining_since(&comment),
+            format!("Readyset {}", MANAGED_SERVER_MARKER)
+        );
+    }
+
+    #[test]
+    fn rule_datetime_compares_comments_written_in_another_offset_by_instant() {
+        // the same instant, written before and after a DST change
+        let winter = format!("{}: 2026-03-29 00:30:00 +0100", MIRROR_QUERY_TOKEN);
+        let summer = format!("{}: 2026-03-29 01:30:00 +0200", MIRROR_QUERY_TOKEN);
+        let winter = rule_datetime(&winter, MIRROR_QUERY_TOKEN).unwrap();
+        let summer = rule_datetime(&summer, MIRROR_QUERY_TOKEN).unwrap();
+        assert_eq!(winter, summer);
+        assert_eq!(winter.timestamp(), 1774740600);
+        let now = Local::now();
+        assert_eq!(
+            now.signed_duration_since(winter),
+            now.signed_duration_since(summer)
+        );
+    }
+
+    #[test]
+    fn build_rule_comment_truncates_a_long_comment_and_keeps_the_timestamp() {
+        let fake = FakeBackend::default();
+        let extra_config = format!(
+            "query_rule_comment = '{}'\nquery_rule_comment_max_length = 80",
+            "x".repeat(200)
+        );
+        let proxysql = proxysql(&fake, &extra_config, false);
+        let now = Local::now();
+        let comment = proxysql.build_rule_comment(MIRROR_QUERY_TOKEN, &now);
+        assert_eq!(comment.chars().count(), 80);
+        let datetime = rule_datetime(&comment, MIRROR_QUERY_TOKEN).unwrap();
+        assert_eq!(datetime.timestamp(), now.timestamp());
+    }
+
+    #[test]
+    fn build_rule_comment_measures_the_escaped_comment() {
+        let fake = FakeBackend::default();
+        let now = Local::now();
+        let prefix_length = format!(
+            "{}: {}",
+            MIRROR_QUERY_TOKEN,
+            now.format(RULE_COMMENT_TIME_FORMAT)
+        )
+        .chars()
+        .count();
+        for max_length in prefix_length..prefix_length + 10 {
+            let extra_config = format!(
+                "query_rule_comment = \"a'b'c'd\"\nquery_rule_comment_max_length = {}",
+                max_length
+            );
+            let proxysql = proxysql(&fake, &extra_config, false);
+            let comment = proxysql.build_rule_comment(MIRROR_QUERY_TOKEN, &now);
+            assert!(comment.chars().count() <= max_length, "{}", comment);
+            let trailing_quotes = comment.len() - comment.trim_end_matches('\'').len();
+            assert_eq!(trailing_quotes % 2, 0, "{}", comment);
+        }
+    }
+
+    #[test]
+    fn adjust_mirror_rules_promotes_rules_after_warmup() {
+        let fake = FakeBackend::default();
+        fake.respond(
+            MIRROR_RULES,
+            &MIRROR_RULE_COLUMNS,
+            vec![mirror_rule(1, "0x1", 120), mirror_rule(2, "0x2", 10)],
+        );
+        let mut proxysql = proxysql(&fake, "warmup_time_s = 60", false);
+        let mut plan = Plan::default();
+        assert_eq!(proxysql.adjust_mirror_rules(&mut plan).unwrap(), 1);
+        assert_eq!(plan.promotions.len(), 1);
+        assert_eq!(plan.promotions[0].rule_id, 1);
+        let updates = fake.statements_containing("UPDATE mysql_query_rules");
+        assert_eq!(updates.len(), 1);
+        assert!(updates[0].contains("destination_hostgroup = 99"));
+        assert!(updates[0].contains(&format!("comment = '{}: ", DESTINATION_QUERY_TOKEN)));
+        assert!(updates[0].ends_with("WHERE rule_id = 1 AND destination_hostgroup IS NULL"));
+    }
+
+    #[test]
+    fn adjust_mirror_rules_only_plans_in_dry_run() {
+        let fake = FakeBackend::default();
+        fake.respond(
+            MIRROR_RULES,
+            &MIRROR_RULE_COLUMNS,
+            vec![mirror_rule(1, "0x1", 120)],
+        );
+        let mut proxysql = proxysql(&fake, "warmup_time_s = 60", true);
+        let mut plan = Plan::default();
+        assert_eq!(proxysql.adjust_mirror_rules(&mut plan).unwrap(), 0);
+        assert_eq!(plan.promotions.len(), 1);
+        assert!(fake.statements_containing("UPDATE").is_empty());
+    }
+
+    #[test]
+    fn adjust_mirror_rules_promotes_a_rule_once() {
+        let fake = FakeBackend::default();
+        // the promoted rule no longer matches the mirror rules query on the second run
+        fake.respond(
+            MIRROR_RULES,
+            &MIRROR_RULE_COLUMNS,
+            vec![mirror_rule(1, "0x1", 120)],
+        )
+        .respond(MIRROR_RULES, &MIRROR_RULE_COLUMNS, vec![]);
+        let mut proxysql = proxysql(&fake, "warmup_time_s = 60", false);
+        assert_eq!(
+            proxysql.adjust_mirror_rules(&mut Plan::default()).unwrap(),
+            1
+        );
+        assert_eq!(
+            proxysql.adjust_mirror_rules(&mut Plan::default()).unwrap(),
+            0
+        );
+        assert_eq!(fake.statements_containing("UPDATE").len(), 1);
+        let select = fake.statements_containing(MIRROR_RULES).remove(0);
+        assert!(select.contains("destination_hostgroup IS NULL"));
+        assert!(select.contains(&format!("comment NOT LIKE '%{}%'", DESTINATION_QUERY_TOKEN)));
+    }
+
+    #[test]
+    fn promotion_statement_replaces_the_comment() {
+        let fake = FakeBackend::default();
+        let proxysql = proxysql(&fake, "", false);
+        let promotion = Promotion {
+            rule_id: 1,
+            digest: "0x1".to_string(),
+            hostgroup: 99,
+        };
+        let statement = proxysql.promotion_statement(&promotion);
+        assert_eq!(statement.matches(DESTINATION_QUERY_TOKEN).count(), 1);
+        assert!(!statement.contains(MIRROR_QUERY_TOKEN));
+        assert!(statement.ends_with("WHERE rule_id = 1 AND destination_hostgroup IS NULL"));
+    }
+
+    #[test]
+    fn find_queries_routed_to_readyset_matches_scheduler_comments() {
+        let fake = FakeBackend::default();
+        fake.respond(
+            "SELECT digest FROM mysql_query_rules",
+            &["digest"],
+            vec![vec![Value::from("0x1")], vec![Value::from("0x2")]],
+        );
+        let mut proxysql = proxysql(&fake, "", false);
+        assert_eq!(
+            proxysql.find_queries_routed_to_readyset().unwrap(),
+            vec!["0x1".to_string(), "0x2".to_string()]
+        );
+        let statement = fake.statements_containing("SELECT digest").remove(0);
+        assert!(statement.contains("comment LIKE 'Mirror by readyset scheduler at%' ESCAPE '!'"));
+        assert!(statement.contains("comment LIKE 'Added by readyset scheduler at%' ESCAPE '!'"));
+    }
+
+    fn query(digest: &str) -> Query {
+        Query::new(
+            "SELECT * FROM t WHERE id = ?".to_string(),
+            "SELECT * FROM t WHERE id = ?".to_string(),
+            digest.to_string(),
+            "shop".to_string(),
+            "root".to_string(),
+            None,
+        )
+    }
+
+    const SCHEDULER_RULE: &str = "SELECT rule_id FROM mysql_query_rules WHERE active = 1";
+
+    #[test]
+    fn add_as_query_rule_does_not_duplicate_an_insert_applied_before_the_connection_was_lost() {
+        let fake = FakeBackend::default();
+        // no rule before the insert, the rule inserted when checked again
+        fake.respond(SCHEDULER_RULE, &["rule_id"], vec![])
+            .respond(SCHEDULER_RULE, &["rule_id"], vec![vec![Value::from("7")]])
+            .lose_connection_on("INSERT INTO mysql_query_rules");
+        let mut proxysql = proxysql(&fake, "", false);
+        assert!(proxysql.add_as_query_rule(&query("0x1")).unwrap());
+        assert_eq!(fake.statements_containing("INSERT").len(), 1);
+        assert_eq!(fake.statements_containing(SCHEDULER_RULE).len(), 2);
+    }
+
+    #[test]
+    fn add_as_query_rule_skips_a_digest_that_already_has_a_rule() {
+        let fake = FakeBackend::default();
+        fake.respond(SCHEDULER_RULE, &["rule_id"], vec![vec![Value::from("7")]]);
+        let mut proxysql = proxysql(&fake, "", false);
+        assert!(!proxysql.add_as_query_rule(&query("0x1")).unwrap());
+        assert!(fake.statements_containing("INSERT").is_empty());
+    }
+
+    #[test]
+    fn add_as_query_rule_inserts_again_if_the_insert_was_lost() {
+        let fake = FakeBackend::default();
+        fake.lose_connection_on("INSERT INTO mysql_query_rules");
+        let mut proxysql = proxysql(&fake, "", false);
+        assert!(proxysql.add_as_query_rule(&query("0x1")).unwrap());
+        assert_eq!(fake.statements_containing("INSERT").len(), 2);
+    }
+
+    #[test]
+    fn adjust_mirror_rules_skips_an_already_promoted_comment() {
+        let fake = FakeBackend::default();
+        let added_at = Local::now() - chrono::Duration::seconds(600);
+        let mut promoted = mirror_rule(1, "0x1", 600);
+        promoted[3] = Value::from(format!(
+            "{}: {} {}: {}",
+            MIRROR_QUERY_TOKEN,
+            added_at.format(RULE_COMMENT_TIME_FORMAT),
+            DESTINATION_QUERY_TOKEN,
+            Local::now().format(RULE_COMMENT_TIME_FORMAT)
+        ));
+        fake.respond(MIRROR_RULES, &MIRROR_RULE_COLUMNS, vec![promoted]);
+        let mut proxysql = proxysql(&fake, "warmup_time_s = 60", false);
+        let mut plan = Plan::default();
+        assert_eq!(proxysql.adjust_mirror_rules(&mut plan).unwrap(), 0);
+        assert!(plan.promotions.is_empty());
+        assert!(fake
+            .statements_containing("UPDATE mysql_query_rules")
+            .is_empty());
+    }
+
+    #[test]
+    fn promote_when_cache_ready_waits_for_mirrored_queries() {
+        let fake = FakeBackend::default();
+        fake.respond(
+            MIRROR_RULES,
+            &MIRROR_RULE_COLUMNS,
+            vec![mirror_rule(1, "0x1", 10)],
+        )
+        .respond("SUM(count_star)", &["hits"], vec![vec![Value::from("0")]]);
+        let mut proxysql = proxysql(
+            &fake,
+            "warmup_time_s = 60\npromote_when_cache_ready = true",
+            false,
+        );
+        let mut plan = Plan::default();
+        assert_eq!(proxysql.adjust_mirror_rules(&mut plan).unwrap(), 0);
+        assert!(plan.promotions.is_empty());
+        assert_eq!(fake.statements_containing("SUM(count_star)").len(), 1);
     }
 }
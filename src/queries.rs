@@ -1,15 +1,111 @@
 use crate::{
-    config::{Config, QueryDiscoveryMode},
-    messages,
+    admin::{AdminConnection, LazyConnection},
+    config::{self, Config, PlaceholderRule, QueryDiscoveryMode, ReconcileAction},
+    error::SchedulerError,
+    hosts::{is_parse_error, Host},
+    messages::{self, MessageType},
+    plan::{NewRule, Plan},
     proxysql::ProxySQL,
+    retries,
+    run_state::RunState,
+    signals,
+    stats::RunStats,
+    support_cache::SupportCache,
 };
-use mysql::{prelude::Queryable, Conn};
+use regex::Regex;
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Row of stats_mysql_query_digest selected by the query discovery: digest_text, digest, schemaname,
+/// count_star, sum_time, min_time, max_time, sum_rows_sent and the value the query is ranked by.
+type CandidateRow = (String, String, String, u64, u64, u64, u64, u64, f64, String);
+
+/// Candidate queries of query discovery, read one at a time.
+type Candidates<'a> = Box<dyn Iterator<Item = Result<Query, SchedulerError>> + 'a>;
+
+/// Columns of stats_mysql_query_digest that can be weighted in the Composite query discovery mode.
+const COMPOSITE_COLUMNS: [&str; 5] = [
+    "count_star",
+    "sum_time",
+    "sum_rows_sent",
+    "min_time",
+    "max_time",
+];
 
+/// Columns of stats_mysql_query_digest used by the query discovery that older ProxySQL versions may not have.
+/// Missing columns are selected as 0, and the discovery modes and filters depending on them are disabled.
+const OPTIONAL_DIGEST_COLUMNS: [&str; 7] = [
+    "count_star",
+    "sum_time",
+    "min_time",
+    "max_time",
+    "sum_rows_sent",
+    "first_seen",
+    "last_seen",
+];
+
+#[derive(Clone, Debug)]
 pub struct Query {
     digest_text: String,
+    /// Digest text as found in stats_mysql_query_digest, before the placeholder rules were applied
+    original_digest_text: String,
     digest: String,
     schema: String,
     user: String,
+    metrics: Option<QueryMetrics>,
+    /// Example of the query with literals, cached instead of the digest text if set
+    concrete_text: Option<String>,
+}
+
+/// Values of stats_mysql_query_digest for a query, as used to rank the queries to cache. Times are in microseconds.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct QueryMetrics {
+    /// Number of times the query was executed
+    pub count_star: u64,
+    /// Total execution time of the query
+    pub sum_time: u64,
+    /// Minimum execution time of the query
+    pub min_time: u64,
+    /// Maximum execution time of the query
+    pub max_time: u64,
+    /// Total number of rows returned by the query
+    pub sum_rows_sent: u64,
+    /// Value of the query discovery mode formula the query was ranked by
+    pub ranking: f64,
+}
+
+impl QueryMetrics {
+    /// Gets the mean execution time of the query.
+    ///
+    /// # Returns
+    ///
+    /// The mean execution time in microseconds, 0 if the query was never executed.
+    pub fn mean_time(&self) -> u64 {
+        self.sum_time.checked_div(self.count_star).unwrap_or(0)
+    }
+}
+
+impl fmt::Display for QueryMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "count_star={} sum_time_us={} mean_time_us={} min_time_us={} max_time_us={} sum_rows_sent={}",
+            self.count_star,
+            self.sum_time,
+            self.mean_time(),
+            self.min_time,
+            self.max_time,
+            self.sum_rows_sent
+        )
+    }
 }
 
 impl Query {
@@ -18,19 +114,31 @@ impl Query {
     /// # Arguments
     ///
     /// * `digest_text` - A string containing the digest text of the query.
+    /// * `original_digest_text` - A string containing the digest text of the query before normalization.
     /// * `digest` - A string containing the digest of the query.
     /// * `schema` - A string containing the schema name of the query.
     /// * `user` - A string containing the user that executed the query.
+    /// * `metrics` - The stats_mysql_query_digest values of the query, if known.
     ///
     /// # Returns
     ///
     /// A new Query struct.
-    fn new(digest_text: String, digest: String, schema: String, user: String) -> Self {
+    pub fn new(
+        digest_text: String,
+        original_digest_text: String,
+        digest: String,
+        schema: String,
+        user: String,
+        metrics: Option<QueryMetrics>,
+    ) -> Self {
         Query {
             digest_text,
+            original_digest_text,
             digest,
             schema,
             user,
+            metrics,
+            concrete_text: None,
         }
     }
 
@@ -42,6 +150,15 @@ impl Query {
         &self.digest_text
     }
 
+    /// This function is used to get the digest text of the query before the placeholder rules were applied.
+    ///
+    /// # Returns
+    ///
+    /// A string containing the digest text as found in stats_mysql_query_digest.
+    pub fn get_original_digest_text(&self) -> &String {
+        &self.original_digest_text
+    }
+
     /// This function is used to get the digest of the query.
     ///
     /// # Returns
@@ -68,16 +185,81 @@ impl Query {
     pub fn get_user(&self) -> &String {
         &self.user
     }
+
+    /// This function is used to get the stats_mysql_query_digest values of the query.
+    ///
+    /// # Returns
+    ///
+    /// The metrics of the query, None if they were not loaded.
+    pub fn get_metrics(&self) -> Option<&QueryMetrics> {
+        self.metrics.as_ref()
+    }
+
+    /// This function is used to get the example of the query with literals, if any.
+    ///
+    /// # Returns
+    ///
+    /// The concrete text of the query, None if the digest text is cached.
+    pub fn get_concrete_text(&self) -> Option<&String> {
+        self.concrete_text.as_ref()
+    }
+
+    /// This function is used to set the example of the query with literals, to be cached instead of the digest text.
+    ///
+    /// # Arguments
+    ///
+    /// * `concrete_text` - The concrete text of the query.
+    pub fn set_concrete_text(&mut self, concrete_text: String) {
+        self.concrete_text = Some(concrete_text);
+    }
+
+    /// This function is used to get the text of the query to cache in Readyset.
+    ///
+    /// # Returns
+    ///
+    /// The concrete text of the query if set, the digest text otherwise.
+    pub fn get_cache_text(&self) -> &String {
+        self.concrete_text.as_ref().unwrap_or(&self.digest_text)
+    }
 }
 
 pub struct QueryDiscovery {
     query_discovery_mode: QueryDiscoveryMode,
     query_discovery_min_execution: u64,
     query_discovery_min_rows_sent: u64,
+    query_discovery_max_rows_sent: u64,
+    query_discovery_min_age_s: u64,
+    query_discovery_max_idle_s: u64,
+    query_discovery_digest_text_filter: Vec<String>,
+    query_discovery_digest_file: Option<String>,
+    query_discovery_max_complexity: Option<u32>,
+    query_discovery_min_tables: u32,
+    cache_concrete_queries: bool,
+    cache_batch_size: u32,
+    cache_batch_sleep: Duration,
+    max_total_caches: u32,
+    max_total_caches_evict: bool,
+    composite_weights: Vec<(String, f64)>,
+    /// Columns of OPTIONAL_DIGEST_COLUMNS missing from stats_mysql_query_digest, found on the first run
+    missing_digest_columns: Option<Vec<String>>,
+    placeholder_rules: Vec<(Regex, String)>,
+    trace_normalization: bool,
+    unsupported_recheck_s: u64,
+    reconcile_action: Option<ReconcileAction>,
+    support_cache_file: String,
+    persist_offset: bool,
+    recache_cooldown_s: u64,
+    state_file: String,
+    state_compression: bool,
     source_hostgroup: u16,
     readyset_user: String,
+    /// Users whose queries are considered for caching
+    discovery_usernames: Vec<String>,
     number_of_queries: u16,
     offset: u16,
+    max_runtime: Option<Duration>,
+    /// Set when the scheduler is asked to stop, see `signals::stop_flag`
+    stop: Arc<AtomicBool>,
 }
 
 /// Query Discovery is a feature responsible for discovering queries that are hurting the database performance.
@@ -96,19 +278,97 @@ impl QueryDiscovery {
     ///
     /// A new QueryDiscovery struct.
     pub fn new(config: Config) -> Self {
+        let mut query_discovery_mode = config
+            .query_discovery_mode
+            .unwrap_or(QueryDiscoveryMode::CountStar);
+        let composite_weights = composite_weights(
+            config
+                .query_discovery_composite_weights
+                .clone()
+                .unwrap_or_default(),
+        );
+        if query_discovery_mode == QueryDiscoveryMode::Composite && composite_weights.is_empty() {
+            messages::print_warning(
+                "Composite query discovery mode requires query_discovery_composite_weights, using CountStar",
+            );
+            query_discovery_mode = QueryDiscoveryMode::CountStar;
+        }
         QueryDiscovery {
-            query_discovery_mode: config
-                .query_discovery_mode
-                .unwrap_or(QueryDiscoveryMode::CountStar),
+            query_discovery_mode,
             query_discovery_min_execution: config.query_discovery_min_execution.unwrap_or(0),
             query_discovery_min_rows_sent: config.query_discovery_min_row_sent.unwrap_or(0),
+            query_discovery_max_rows_sent: config.query_discovery_max_rows_sent.unwrap_or(0),
+            query_discovery_min_age_s: config.query_discovery_min_age_s.unwrap_or(0),
+            query_discovery_max_idle_s: config.query_discovery_max_idle_s.unwrap_or(0),
+            query_discovery_digest_text_filter: digest_text_filter(
+                config
+                    .query_discovery_digest_text_filter
+                    .clone()
+                    .unwrap_or_default(),
+                config.query_discovery_include_cte.unwrap_or(false),
+            ),
+            query_discovery_digest_file: config.query_discovery_digest_file.clone(),
+            query_discovery_max_complexity: config.query_discovery_max_complexity,
+            query_discovery_min_tables: config.query_discovery_min_tables.unwrap_or(0),
+            cache_concrete_queries: config.cache_concrete_queries.unwrap_or(false),
+            cache_batch_size: config.cache_batch_size.unwrap_or(0),
+            cache_batch_sleep: Duration::from_millis(config.cache_batch_sleep_ms.unwrap_or(0)),
+            max_total_caches: config.max_total_caches.unwrap_or(0),
+            max_total_caches_evict: config.max_total_caches_evict.unwrap_or(false),
+            composite_weights,
+            missing_digest_columns: None,
+            placeholder_rules: placeholder_rules(
+                config
+                    .query_discovery_placeholder_rules
+                    .clone()
+                    .unwrap_or_else(default_placeholder_rules),
+            ),
+            trace_normalization: config.trace_normalization,
+            unsupported_recheck_s: config.unsupported_recheck_s.unwrap_or(0),
+            reconcile_action: config.reconcile_action,
+            support_cache_file: format!(
+                "{}.support_cache",
+                config
+                    .lock_file
+                    .as_deref()
+                    .unwrap_or(config::DEFAULT_LOCK_FILE)
+            ),
+            persist_offset: config.query_discovery_persist_offset.unwrap_or(false)
+                && query_discovery_mode != QueryDiscoveryMode::External,
+            recache_cooldown_s: config.recache_cooldown_s.unwrap_or(0),
+            state_file: format!(
+                "{}.state",
+                config
+                    .lock_file
+                    .as_deref()
+                    .unwrap_or(config::DEFAULT_LOCK_FILE)
+            ),
+            state_compression: config.state_compression.unwrap_or(false),
             source_hostgroup: config.source_hostgroup,
             readyset_user: config.readyset_user.clone(),
+            discovery_usernames: discovery_usernames(
+                config.discovery_usernames.clone().unwrap_or_default(),
+                &config.readyset_user,
+            ),
             number_of_queries: config.number_of_queries,
             offset: 0,
+            max_runtime: config
+                .max_runtime_s
+                .filter(|max_runtime_s| *max_runtime_s > 0)
+                .map(Duration::from_secs),
+            stop: signals::stop_flag(),
         }
     }
 
+    /// This function is used to check if the scheduler was asked to stop, to stop after the current step.
+    ///
+    /// # Returns
+    ///
+    /// true if SIGTERM or SIGINT was received.
+    fn stop_requested(&self) -> bool {
+        self.stop.load(Ordering::Relaxed)
+    }
+
     /// This function is used to generate the query responsible for finding queries that are not cached in ReadySet and are not in the mysql_query_rules table.
     /// Queries have to return 3 fields: digest_text, digest, and schema name.
     ///
@@ -120,6 +380,7 @@ impl QueryDiscovery {
     ///
     /// A string containing the query responsible for finding queries that are not cached in ReadySet and are not in the mysql_query_rules table.
     fn query_builder(&self) -> String {
+        let column = |name: &str| self.digest_column(name);
         let order_by = match self.query_discovery_mode {
             QueryDiscoveryMode::SumRowsSent => "s.sum_rows_sent".to_string(),
             QueryDiscoveryMode::SumTime => "s.sum_time".to_string(),
@@ -132,141 +393,2281 @@ impl QueryDiscovery {
             QueryDiscoveryMode::DistanceMeanMax => {
                 "(s.max_time - (s.sum_time / s.count_star))".to_string()
             }
+            QueryDiscoveryMode::Composite => self
+                .composite_weights
+                .iter()
+                .map(|(column, weight)| {
+                    // each column is normalized to 0..1 by its maximum, a maximum of 0 counts as 0
+                    format!(
+                        "{} * COALESCE(s.{} * 1.0 / NULLIF((SELECT MAX({}) FROM stats_mysql_query_digest WHERE hostgroup = {}), 0), 0)",
+                        weight, column, column, self.source_hostgroup
+                    )
+                })
+                .collect::<Vec<String>>()
+                .join(" + "),
             QueryDiscoveryMode::External => unreachable!("External mode is caught earlier"),
         };
 
+        // queries that returned no rows are skipped, unless sum_rows_sent is not available
+        let min_rows_sent = match self.is_digest_column_missing("sum_rows_sent") {
+            true => String::new(),
+            false => format!(
+                "\n    AND s.sum_rows_sent > {}",
+                self.query_discovery_min_rows_sent
+            ),
+        };
+
+        // average rows per execution, count_star is checked first so it is never a division by 0
+        let max_rows_sent = match self.query_discovery_max_rows_sent {
+            0 => String::new(),
+            max_rows_sent => format!(
+                "\n    AND s.count_star > 0 AND (s.sum_rows_sent / s.count_star) <= {}",
+                max_rows_sent
+            ),
+        };
+
+        // first_seen and last_seen are unix timestamps, compared to the scheduler clock
+        let now = chrono::Utc::now().timestamp();
+        let min_age = match self.query_discovery_min_age_s {
+            0 => String::new(),
+            min_age_s => format!(
+                "\n    AND s.first_seen <= {}",
+                now.saturating_sub(min_age_s as i64)
+            ),
+        };
+        let max_idle = match self.query_discovery_max_idle_s {
+            0 => String::new(),
+            max_idle_s => format!(
+                "\n    AND s.last_seen >= {}",
+                now.saturating_sub(max_idle_s as i64)
+            ),
+        };
+
+        // SQLite needs a LIMIT before an OFFSET, -1 is no limit as the candidates are read until enough are cached
+        let offset = match self.offset {
+            0 => String::new(),
+            offset => format!("\n    LIMIT -1 OFFSET {}", offset),
+        };
+
+        // a query matching any of the patterns is a candidate, Readyset support is still checked for each of them
+        let digest_text_filter = self
+            .query_discovery_digest_text_filter
+            .iter()
+            .map(|pattern| format!("s.digest_text LIKE '{}'", pattern.replace('\'', "''")))
+            .collect::<Vec<String>>()
+            .join(" OR ");
+
         format!(
-            "SELECT s.digest_text, s.digest, s.schemaname
+            "SELECT s.digest_text, s.digest, s.schemaname, {}, {}, {}, {}, {}, {}, s.username
     FROM stats_mysql_query_digest s 
     LEFT JOIN mysql_query_rules q 
     USING(digest) 
     WHERE s.hostgroup = {}
-    AND s.username = '{}'
+    AND s.username IN ({})
     AND s.schemaname NOT IN ('sys', 'information_schema', 'performance_schema', 'mysql')
-    AND s.digest_text LIKE 'SELECT%FROM%'
+    AND ({})
     AND digest_text NOT LIKE '%?=?%'
-    AND s.count_star > {}
-    AND s.sum_rows_sent > {}
+    AND {} > {}{}{}{}{}
     AND q.rule_id IS NULL
-    ORDER BY {} DESC
-    LIMIT {} OFFSET {}",
+    ORDER BY {} DESC{}",
+            column("count_star"),
+            column("sum_time"),
+            column("min_time"),
+            column("max_time"),
+            column("sum_rows_sent"),
+            order_by,
             self.source_hostgroup,
-            self.readyset_user,
+            self.username_list(),
+            digest_text_filter,
+            column("count_star"),
             self.query_discovery_min_execution,
-            self.query_discovery_min_rows_sent,
+            min_rows_sent,
+            max_rows_sent,
+            min_age,
+            max_idle,
             order_by,
-            self.number_of_queries,
-            self.offset
+            offset
         )
     }
 
-    pub fn run(&mut self, proxysql: &mut ProxySQL, conn: &mut Conn) {
+    /// This function is used to get the discovery usernames as a SQL list.
+    ///
+    /// # Returns
+    ///
+    /// The quoted usernames, separated by commas.
+    fn username_list(&self) -> String {
+        self.discovery_usernames
+            .iter()
+            .map(|username| format!("'{}'", username.replace('\'', "''")))
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+
+    /// This function is used to get the expression selecting a column of stats_mysql_query_digest.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the column.
+    ///
+    /// # Returns
+    ///
+    /// The column of the digest table, or 0 if the ProxySQL version does not have it.
+    fn digest_column(&self, name: &str) -> String {
+        match self.is_digest_column_missing(name) {
+            true => "0".to_string(),
+            false => format!("s.{}", name),
+        }
+    }
+
+    /// This function is used to check if a column of stats_mysql_query_digest is missing in this ProxySQL version.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the column.
+    ///
+    /// # Returns
+    ///
+    /// true if the column was found missing, false if it exists or the table was not inspected yet.
+    fn is_digest_column_missing(&self, name: &str) -> bool {
+        self.missing_digest_columns
+            .as_ref()
+            .is_some_and(|missing| missing.iter().any(|column| column == name))
+    }
+
+    /// This function is used to inspect the columns of stats_mysql_query_digest, as they vary between ProxySQL versions.
+    /// The discovery mode, composite weights and filters depending on a missing column are disabled with a warning, so the
+    /// discovery query does not fail. It is done once, on the first run.
+    ///
+    /// # Arguments
+    ///
+    /// * `conn` - A reference to a connection to ProxySQL.
+    fn check_digest_columns(&mut self, conn: &mut AdminConnection) -> Result<(), SchedulerError> {
+        if self.missing_digest_columns.is_some()
+            || self.query_discovery_mode == QueryDiscoveryMode::External
+        {
+            return Ok(());
+        }
+        let columns: Vec<String> = conn
+            .column_names("SELECT * FROM stats_mysql_query_digest LIMIT 0")?
+            .into_iter()
+            .map(|column| column.to_lowercase())
+            .collect();
+        let missing: Vec<String> = OPTIONAL_DIGEST_COLUMNS
+            .iter()
+            .filter(|column| !columns.iter().any(|name| name == *column))
+            .map(|column| column.to_string())
+            .collect();
+        if missing.is_empty() {
+            self.missing_digest_columns = Some(missing);
+            return Ok(());
+        }
+        messages::print_warning(
+            format!(
+                "stats_mysql_query_digest has no {} column in this ProxySQL version, query discovery will not use it",
+                missing.join(", ")
+            )
+            .as_str(),
+        );
+
+        let mode_columns: &[&str] = match self.query_discovery_mode {
+            QueryDiscoveryMode::CountStar => &["count_star"],
+            QueryDiscoveryMode::SumTime => &["sum_time"],
+            QueryDiscoveryMode::SumRowsSent => &["sum_rows_sent"],
+            QueryDiscoveryMode::MeanTime | QueryDiscoveryMode::QueryThroughput => {
+                &["count_star", "sum_time"]
+            }
+            QueryDiscoveryMode::ExecutionTimeDistance => &["min_time", "max_time"],
+            QueryDiscoveryMode::WorstBestCase => &["min_time"],
+            QueryDiscoveryMode::WorstWorstCase => &["max_time"],
+            QueryDiscoveryMode::DistanceMeanMax => &["count_star", "sum_time", "max_time"],
+            QueryDiscoveryMode::Composite | QueryDiscoveryMode::External => &[],
+        };
+        if mode_columns
+            .iter()
+            .any(|column| missing.iter().any(|name| name == column))
+        {
+            messages::print_warning(
+                format!(
+                    "Query discovery mode {:?} is not available in this ProxySQL version, using CountStar",
+                    self.query_discovery_mode
+                )
+                .as_str(),
+            );
+            self.query_discovery_mode = QueryDiscoveryMode::CountStar;
+        }
+        if self.query_discovery_mode == QueryDiscoveryMode::Composite {
+            self.composite_weights
+                .retain(|(column, _)| !missing.iter().any(|name| name == column));
+            if self.composite_weights.is_empty() {
+                messages::print_warning(
+                    "No query_discovery_composite_weights column is available in this ProxySQL version, using CountStar",
+                );
+                self.query_discovery_mode = QueryDiscoveryMode::CountStar;
+            }
+        }
+        let filters = [
+            (
+                "query_discovery_min_row_sent",
+                "sum_rows_sent",
+                &mut self.query_discovery_min_rows_sent,
+            ),
+            (
+                "query_discovery_max_rows_sent",
+                "sum_rows_sent",
+                &mut self.query_discovery_max_rows_sent,
+            ),
+            (
+                "query_discovery_min_age_s",
+                "first_seen",
+                &mut self.query_discovery_min_age_s,
+            ),
+            (
+                "query_discovery_max_idle_s",
+                "last_seen",
+                &mut self.query_discovery_max_idle_s,
+            ),
+        ];
+        for (option, column, value) in filters {
+            if *value > 0 && missing.iter().any(|name| name == column) {
+                messages::print_warning(
+                    format!(
+                        "Ignoring {} as this ProxySQL version has no {} column",
+                        option, column
+                    )
+                    .as_str(),
+                );
+                *value = 0;
+            }
+        }
+        self.missing_digest_columns = Some(missing);
+        Ok(())
+    }
+
+    /// This function is used to check a query against query_discovery_max_complexity and query_discovery_min_tables.
+    /// Skipped queries are logged.
+    ///
+    /// # Arguments
+    ///
+    /// * `digest_text` - The text of the query to be checked for support.
+    ///
+    /// # Returns
+    ///
+    /// true if the query can be cached, false if it is skipped.
+    fn passes_text_filters(&self, digest_text: &str) -> bool {
+        if let Some(max_complexity) = self.query_discovery_max_complexity {
+            let complexity = estimate_query_complexity(digest_text);
+            if complexity > max_complexity {
+                messages::print_repeated(
+                    MessageType::Note,
+                    "Query skipped for exceeding query_discovery_max_complexity",
+                    format!(
+                        "Skipping query with estimated complexity {} above query_discovery_max_complexity {}: {}",
+                        complexity, max_complexity, digest_text
+                    )
+                    .as_str(),
+                );
+                return false;
+            }
+        }
+        if self.query_discovery_min_tables > 0 {
+            let tables = count_referenced_tables(digest_text);
+            if tables < self.query_discovery_min_tables {
+                messages::print_repeated(
+                    MessageType::Info,
+                    "Query skipped for referencing less than query_discovery_min_tables",
+                    format!(
+                        "Skipping query referencing {} tables, below query_discovery_min_tables {}: {}",
+                        tables, self.query_discovery_min_tables, digest_text
+                    )
+                    .as_str(),
+                );
+                return false;
+            }
+        }
+        true
+    }
+
+    /// This function is used to write every candidate query to a CSV file, or TSV if the path ends with `.tsv`,
+    /// with its digest, schema, whether Readyset supports it and the value it is ranked by. The candidates go
+    /// through the same filters as in a live run, but no cache or query rule is created.
+    ///
+    /// # Arguments
+    ///
+    /// * `proxysql` - A mutable reference to the ProxySQL instance, to find the Readyset host checking support.
+    /// * `conn` - A reference to a connection to ProxySQL.
+    /// * `path` - The path of the file to write.
+    ///
+    /// # Returns
+    ///
+    /// The number of candidates written.
+    pub fn export_candidates(
+        &mut self,
+        proxysql: &mut ProxySQL,
+        conn: &mut AdminConnection,
+        path: &str,
+    ) -> Result<usize, SchedulerError> {
+        self.check_digest_columns(conn)?;
+        let separator = match path.to_lowercase().ends_with(".tsv") {
+            true => "\t",
+            false => ",",
+        };
+        let mut lines = vec![["digest", "schema", "supported", "ranking"].join(separator)];
+        self.offset = 0;
+        // samples are looked up on another connection, while this one streams the candidates
+        let mut sample_conn = conn.connect_later();
+        for query in self.find_queries_to_cache(conn)? {
+            if self.stop_requested() {
+                messages::print_warning("Stop requested, stopping the export of candidates");
+                break;
+            }
+            let mut query = query?;
+            if self.cache_concrete_queries {
+                self.use_sample_query(&mut sample_conn, &mut query);
+            }
+            let digest_text = query.get_cache_text();
+            if !self.passes_text_filters(digest_text) {
+                continue;
+            }
+            let readyset_hostgroup = proxysql.readyset_hostgroup_for_schema(query.get_schema());
+            let supported = proxysql
+                .check_query_support(readyset_hostgroup, digest_text, query.get_schema())
+                .unwrap_or_else(|| {
+                    Err(SchedulerError::Query(format!(
+                        "no online Readyset host in hostgroup {}",
+                        readyset_hostgroup
+                    )))
+                });
+            let supported = match supported {
+                Ok(true) => "y",
+                Ok(false) => "n",
+                Err(err) => {
+                    messages::print_warning(
+                        format!(
+                            "Failed to check support of query {}, exporting it as not supported: {}",
+                            query.get_digest(),
+                            err
+                        )
+                        .as_str(),
+                    );
+                    "n"
+                }
+            };
+            lines.push(
+                [
+                    query.get_digest().clone(),
+                    query.get_schema().clone(),
+                    supported.to_string(),
+                    query
+                        .get_metrics()
+                        .map_or(String::new(), |metrics| metrics.ranking.to_string()),
+                ]
+                .join(separator),
+            );
+        }
+        fs::write(path, lines.join("\n") + "\n").map_err(|err| {
+            SchedulerError::Query(format!("Failed to write candidates to {}: {}", path, err))
+        })?;
+        Ok(lines.len() - 1)
+    }
+
+    pub fn run(
+        &mut self,
+        proxysql: &mut ProxySQL,
+        conn: &mut AdminConnection,
+        stats: &mut RunStats,
+        plan: &mut Plan,
+    ) -> Result<(), SchedulerError> {
         if proxysql.number_of_online_hosts() == 0 {
-            return;
+            return Ok(());
+        }
+        self.check_digest_columns(conn)?;
+
+        let start = Instant::now();
+        let mut support_cache = if self.unsupported_recheck_s > 0 {
+            Some(SupportCache::load(
+                &self.support_cache_file,
+                &proxysql.readyset_version(),
+                self.unsupported_recheck_s,
+                self.state_compression,
+            ))
+        } else {
+            None
+        };
+
+        let discovery_mode = format!("{:?}", self.query_discovery_mode);
+        // a single run state is kept, as each change writes the whole file
+        let mut run_state = match self.persist_offset || self.recache_cooldown_s > 0 {
+            true => Some(RunState::load(&self.state_file, self.state_compression)),
+            false => None,
+        };
+        if let Some(run_state) = run_state.as_ref().filter(|_| self.persist_offset) {
+            self.offset = run_state.get_discovery_offset(&discovery_mode);
+            if self.offset > 0 {
+                messages::print_info(
+                    format!("Resuming query discovery from offset {}", self.offset).as_str(),
+                );
+            }
         }
 
-        let mut queries_added_or_change = proxysql.adjust_mirror_rules().unwrap();
+        let mut queries_added_or_change = false;
+        if let Some(action) = self.reconcile_action {
+            queries_added_or_change = self.reconcile_caches(action, proxysql, conn, stats)? > 0;
+        }
 
-        let mut current_queries_digest: Vec<String> = proxysql.find_queries_routed_to_readyset();
+        stats.rules_promoted = proxysql.adjust_mirror_rules(plan)?;
+        queries_added_or_change |= stats.rules_promoted > 0;
 
+        let mut current_queries_digest: Vec<String> = proxysql.find_queries_routed_to_readyset()?;
+        // caches not created by the scheduler also count towards max_total_caches
+        let mut total_caches = match self.max_total_caches {
+            0 => 0,
+            _ => proxysql
+                .count_caches()
+                .unwrap_or(0)
+                .max(current_queries_digest.len() as u32),
+        };
+
+        // samples are looked up on another connection, while this one streams the candidates
+        let mut sample_conn = conn.connect_later();
+        let mut candidates = self.find_queries_to_cache(conn)?;
+        let mut queries_seen: u16 = 0;
         let mut more_queries = true;
-        while more_queries && current_queries_digest.len() < self.number_of_queries as usize {
-            let queries_to_cache = self.find_queries_to_cache(conn);
-            more_queries = !queries_to_cache.is_empty();
-            for query in queries_to_cache[0..queries_to_cache.len()].iter() {
-                if current_queries_digest.len() > self.number_of_queries as usize {
+        while current_queries_digest.len() < self.number_of_queries as usize {
+            // the remaining candidates are left for the next run
+            if self.stop_requested() {
+                messages::print_warning("Stop requested, stopping query discovery");
+                break;
+            }
+            if let Some(max_runtime) = self.max_runtime {
+                if start.elapsed() >= max_runtime {
+                    messages::print_warning(
+                        format!(
+                            "Reached max_runtime_s of {} seconds, stopping query discovery with {} queries remaining",
+                            max_runtime.as_secs(),
+                            self.number_of_queries as usize - current_queries_digest.len()
+                        )
+                        .as_str(),
+                    );
+                    break;
+                }
+            }
+            let mut query = match candidates.next() {
+                Some(query) => query?,
+                None => {
+                    more_queries = false;
                     break;
                 }
-                let digest_text = self.replace_placeholders(query.get_digest_text());
-                messages::print_note(
-                    format!("Going to test query support for {}", digest_text).as_str(),
+            };
+            queries_seen = queries_seen.saturating_add(1);
+            if support_cache
+                .as_ref()
+                .is_some_and(|cache| cache.is_unsupported(query.get_digest()))
+            {
+                messages::print_info(
+                    format!(
+                        "Query {} is known to be unsupported, skipping",
+                        query.get_digest()
+                    )
+                    .as_str(),
+                );
+                continue;
+            }
+            // the rule of a query cached earlier may not be visible yet, such as before it is loaded to runtime
+            if current_queries_digest.contains(query.get_digest()) {
+                continue;
+            }
+            let cached_s = run_state
+                .as_ref()
+                .and_then(|run_state| run_state.get_cached_at(query.get_digest()))
+                .map(|cached_at| chrono::Utc::now().timestamp() - cached_at);
+            if let Some(cached_s) =
+                cached_s.filter(|cached_s| *cached_s < self.recache_cooldown_s as i64)
+            {
+                messages::print_info(
+                    format!(
+                        "Query {} was cached {} seconds ago, within recache_cooldown_s, skipping",
+                        query.get_digest(),
+                        cached_s
+                    )
+                    .as_str(),
                 );
-                let supported = proxysql
-                    .get_first_online_host()
-                    .unwrap()
-                    .check_query_support(&digest_text, query.get_schema()); // Safe to unwrap because we checked if hosts is empty
-                match supported {
-                    Ok(true) => {
-                        messages::print_note(
-                            "Query is supported, adding it to proxysql and readyset"
-                                .to_string()
+                continue;
+            }
+            if self.cache_concrete_queries {
+                self.use_sample_query(&mut sample_conn, &mut query);
+            }
+            let digest_text = match query.get_concrete_text() {
+                Some(concrete_text) => concrete_text.clone(),
+                None => self.replace_placeholders(query.get_digest_text()),
+            };
+            if !self.passes_text_filters(&digest_text) {
+                continue;
+            }
+            messages::print_note(
+                format!("Going to test query support for {}", digest_text).as_str(),
+            );
+            let readyset_hostgroup = proxysql.readyset_hostgroup_for_schema(query.get_schema());
+            let support_check_start = Instant::now();
+            let mut supported = match proxysql.check_query_support(
+                readyset_hostgroup,
+                &digest_text,
+                query.get_schema(),
+            ) {
+                Some(supported) => supported,
+                None => {
+                    messages::print_repeated(
+                        MessageType::Warning,
+                        "Query skipped for having no online Readyset host in its hostgroup",
+                        format!(
+                            "No online Readyset host in hostgroup {} for schema {}, skipping query",
+                            readyset_hostgroup,
+                            query.get_schema()
+                        )
+                        .as_str(),
+                    );
+                    continue;
+                }
+            };
+            stats.queries_evaluated += 1;
+            let mut sample_query = None;
+            if supported.as_ref().is_err_and(is_parse_error) {
+                match self.retry_with_sample(proxysql, sample_conn.get(), &query, readyset_hostgroup, stats) {
+                    Some((sample_supported, sample)) => {
+                        supported = sample_supported;
+                        sample_query = Some(sample);
+                    }
+                    None => messages::print_repeated(
+                        MessageType::Warning,
+                        "Query skipped for failing to parse without a sample query",
+                        format!(
+                            "Readyset failed to parse the digest text of query {} and no sample query is available: {} (original digest text: {})",
+                            query.get_digest(),
+                            digest_text,
+                            query.get_original_digest_text()
+                        )
+                        .as_str(),
+                    ),
+                }
+            }
+            let query = sample_query.as_ref().unwrap_or(&query);
+            let support_check_time = support_check_start.elapsed();
+            stats.support_check_time.record(support_check_time);
+            let outcome = match &supported {
+                Ok(true) if proxysql.dry_run() => "supported, dry run".to_string(),
+                Ok(true) => "supported".to_string(),
+                Ok(false) => "not supported".to_string(),
+                Err(err) => format!("support check failed: {}", err),
+            };
+            messages::print_info(
+                format!(
+                    "Query {} support check took {} ms, outcome: {}{}",
+                    query.get_digest(),
+                    support_check_time.as_millis(),
+                    outcome,
+                    query
+                        .get_metrics()
+                        .map_or(String::new(), |metrics| format!(", {}", metrics))
+                )
+                .as_str(),
+            );
+            match supported {
+                Ok(true) => {
+                    // the metrics that ranked the query, to explain why it was cached
+                    messages::print_note(
+                        format!(
+                            "Query {} is supported, adding it to proxysql and readyset{}",
+                            query.get_digest(),
+                            query
+                                .get_metrics()
+                                .map_or(String::new(), |metrics| format!(", {}", metrics))
+                        )
+                        .as_str(),
+                    );
+                    stats.queries_supported += 1;
+                    let mut eviction = None;
+                    if self.max_total_caches > 0 && total_caches >= self.max_total_caches {
+                        if self.max_total_caches_evict {
+                            eviction = proxysql.plan_eviction(query, plan)?;
+                        }
+                        if eviction.is_none() {
+                            messages::print_warning(
+                                format!(
+                                    "Reached max_total_caches of {}, not creating more caches",
+                                    self.max_total_caches
+                                )
                                 .as_str(),
-                        );
-                        queries_added_or_change = true;
-                        if !proxysql.dry_run() {
-                            proxysql.get_online_hosts().iter_mut().for_each(|host| {
-                                host.cache_query(query).unwrap_or_else(|_| {
-                                    panic!(
-                                        "Failed to create readyset cache on host {}:{}",
-                                        host.get_hostname(),
-                                        host.get_port()
-                                    )
-                                });
-                            });
-                            proxysql
-                                .add_as_query_rule(query)
-                                .expect("Failed to add query rule");
-                        } else {
-                            messages::print_info("Dry run, not adding query");
+                            );
+                            // the query is left for the next run, which starts over from the top
+                            queries_seen -= 1;
+                            more_queries = false;
+                            break;
                         }
-                        current_queries_digest.push(query.get_digest().to_string());
                     }
-                    Ok(false) => {
-                        messages::print_note("Query is not supported");
+                    if eviction.is_none() {
+                        total_caches += 1;
                     }
-                    Err(err) => {
-                        messages::print_warning(
-                            format!("Failed to check query support: {}", err).as_str(),
-                        );
+                    plan.new_rules.push(NewRule {
+                        query: query.clone(),
+                        hostgroup: readyset_hostgroup,
+                        eviction,
+                    });
+                    current_queries_digest.push(query.get_digest().to_string());
+                }
+                Ok(false) => {
+                    messages::print_repeated(
+                        MessageType::Note,
+                        "Query is not supported",
+                        "Query is not supported",
+                    );
+                    if let Some(cache) = support_cache.as_mut() {
+                        cache.mark_unsupported(query.get_digest());
                     }
                 }
+                Err(err) => {
+                    messages::print_repeated(
+                        MessageType::Warning,
+                        "Failed to check query support",
+                        format!(
+                            "Failed to check query support: {}, query: {} (original digest text: {})",
+                            err,
+                            query.get_cache_text(),
+                            query.get_original_digest_text()
+                        )
+                        .as_str(),
+                    );
+                }
             }
-            self.offset += queries_to_cache.len() as u16;
         }
+        drop(candidates);
+        self.offset = self.offset.saturating_add(queries_seen);
+        if !proxysql.dry_run() {
+            queries_added_or_change |=
+                self.apply_new_rules(proxysql, plan, stats, run_state.as_mut())? > 0;
+        }
+        // dry runs leave the support cache as it was
+        if let Some(cache) = support_cache.filter(|_| !proxysql.dry_run()) {
+            cache.save();
+        }
+        if let Some(run_state) = run_state.as_mut().filter(|_| self.persist_offset) {
+            // cached queries have a rule now and are no longer candidates, so they don't count towards the offset.
+            // Once all the candidates were seen, the next run starts over from the top.
+            let offset = match more_queries {
+                true => self.offset.saturating_sub(stats.queries_cached as u16),
+                false => 0,
+            };
+            run_state.set_discovery_offset(&discovery_mode, offset);
+        }
+        // the rules already added are loaded and saved even if a stop was requested, so runtime and disk agree
         if queries_added_or_change {
-            proxysql
-                .load_query_rules()
-                .expect("Failed to load query rules");
-            proxysql
-                .save_query_rules()
-                .expect("Failed to save query rules");
+            proxysql.load_query_rules()?;
+            proxysql.save_query_rules()?;
+        }
+        Ok(())
+    }
+
+    /// This function is used to apply the new rules of the plan, in the order they were planned. The cache of
+    /// each query is created on the online Readyset hosts, then its query rule is added, then the rule it evicts
+    /// is removed. Rules that fail to be added are dropped from the plan, with their eviction.
+    /// If a stop is requested, the remaining rules are dropped from the plan and left for the next run.
+    ///
+    /// # Arguments
+    ///
+    /// * `proxysql` - The ProxySQL instance.
+    /// * `plan` - The plan of the run, with the new rules to apply.
+    /// * `stats` - The run statistics, updated with the queries cached.
+    /// * `run_state` - The run state, to keep the cached and evicted queries out of discovery for recache_cooldown_s.
+    ///
+    /// # Returns
+    ///
+    /// The number of rules added.
+    fn apply_new_rules(
+        &mut self,
+        proxysql: &mut ProxySQL,
+        plan: &mut Plan,
+        stats: &mut RunStats,
+        mut run_state: Option<&mut RunState>,
+    ) -> Result<u32, SchedulerError> {
+        let planned = std::mem::take(&mut plan.new_rules);
+        let mut remaining = planned.len();
+        let mut added_rules = 0;
+        for new_rule in planned {
+            if self.stop_requested() {
+                messages::print_warning(
+                    format!(
+                        "Stop requested, leaving {} planned queries for the next run",
+                        remaining
+                    )
+                    .as_str(),
+                );
+                // the queries left are candidates again, so they don't count towards the offset
+                self.offset = self.offset.saturating_sub(remaining as u16);
+                break;
+            }
+            remaining -= 1;
+            let query = &new_rule.query;
+            if proxysql.cache_query_on_online_hosts(query, stats) == 0 {
+                messages::print_warning(
+                    "Failed to create readyset cache on all hosts, not adding query rule",
+                );
+                continue;
+            }
+            if !proxysql.add_as_query_rule(query)? {
+                continue;
+            }
+            if let Some(removal) = new_rule.eviction.as_ref() {
+                proxysql.remove_rule(removal)?;
+            }
+            added_rules += 1;
+            stats.queries_cached += 1;
+            if self.recache_cooldown_s > 0 {
+                if let Some(run_state) = run_state.as_mut() {
+                    let now = chrono::Utc::now().timestamp();
+                    run_state.record_cached(query.get_digest(), now, self.recache_cooldown_s);
+                    // an evicted query is kept out of discovery, so the rules don't flap
+                    if let Some(removal) = new_rule.eviction.as_ref() {
+                        run_state.record_cached(&removal.digest, now, self.recache_cooldown_s);
+                    }
+                }
+            }
+            plan.new_rules.push(new_rule);
+            if self.cache_batch_size > 0
+                && stats.queries_cached.is_multiple_of(self.cache_batch_size)
+                && !self.cache_batch_sleep.is_zero()
+            {
+                messages::print_info(
+                    format!(
+                        "Created a batch of {} caches, sleeping {} ms",
+                        self.cache_batch_size,
+                        self.cache_batch_sleep.as_millis()
+                    )
+                    .as_str(),
+                );
+                thread::sleep(self.cache_batch_sleep);
+            }
         }
+        Ok(added_rules)
     }
 
     /// This function is used to find queries that are not cached in ReadySet and are not in the mysql_query_rules table.
+    /// The candidates are streamed in ranking order, so they are only read until enough queries are cached.
     ///
     /// # Arguments
-    /// * `conn` - A reference to a connection to ProxySQL.
-    /// * `config` - A reference to the configuration struct.
+    /// * `conn` - A reference to a connection to ProxySQL, busy until the candidates are dropped.
     ///
     /// # Returns
-    /// A vector of tuples containing the digest_text, digest, and schema name of the queries that are not cached in ReadySet and are not in the mysql_query_rules table.
-    fn find_queries_to_cache(&self, con: &mut Conn) -> Vec<Query> {
+    /// The queries that are not cached in ReadySet and are not in the mysql_query_rules table, starting at the offset.
+    fn find_queries_to_cache<'a>(
+        &'a self,
+        con: &'a mut AdminConnection,
+    ) -> Result<Candidates<'a>, SchedulerError> {
         match self.query_discovery_mode {
-            QueryDiscoveryMode::External => {
-                todo!("External mode is not implemented yet");
-            }
+            QueryDiscoveryMode::External => Ok(Box::new(
+                self.find_queries_from_digest_file(con)?
+                    .into_iter()
+                    .skip(self.offset as usize)
+                    .map(Ok),
+            )),
             _ => {
-                let query = self.query_builder();
-                let rows: Vec<(String, String, String)> =
-                    con.query(query).expect("Failed to find queries to cache");
-                rows.iter()
-                    .map(|(digest_text, digest, schema)| {
-                        Query::new(
-                            self.replace_placeholders(digest_text),
-                            digest.to_string(),
-                            schema.to_string(),
-                            self.readyset_user.clone(),
+                let rows = con.query_iter::<CandidateRow>(self.query_builder())?;
+                Ok(Box::new(rows.map(|row| {
+                    row.map(
+                        |(
+                            digest_text,
+                            digest,
+                            schema,
+                            count_star,
+                            sum_time,
+                            min_time,
+                            max_time,
+                            sum_rows_sent,
+                            ranking,
+                            username,
+                        )| {
+                            Query::new(
+                                self.replace_placeholders(&digest_text),
+                                digest_text,
+                                digest,
+                                schema,
+                                username,
+                                Some(QueryMetrics {
+                                    count_star,
+                                    sum_time,
+                                    min_time,
+                                    max_time,
+                                    sum_rows_sent,
+                                    ranking,
+                                }),
+                            )
+                        },
+                    )
+                })))
+            }
+        }
+    }
+
+    /// This function is used to replace the digest text of a query by a sample of it, with cache_concrete_queries.
+    /// The query keeps its digest text when no sample is found, or when the connection fails to open.
+    ///
+    /// # Arguments
+    /// * `con` - A connection to ProxySQL, other than the one streaming the candidates.
+    /// * `query` - The query to find a sample of.
+    fn use_sample_query(&self, con: &mut LazyConnection, query: &mut Query) {
+        let Some(con) = con.get() else {
+            return;
+        };
+        match self.find_sample_query(con, query.get_digest()) {
+            Ok(Some(sample)) => query.set_concrete_text(sample),
+            Err(err) => messages::print_warning(
+                format!(
+                    "Failed to find a sample of query {}, caching its digest text: {}",
+                    query.get_digest(),
+                    err
+                )
+                .as_str(),
+            ),
+            Ok(None) => messages::print_info(
+                format!(
+                    "No sample query for digest {}, caching its digest text",
+                    query.get_digest()
+                )
+                .as_str(),
+            ),
+        }
+    }
+
+    /// This function is used to find the queries listed in the query_discovery_digest_file.
+    /// The file contains one ProxySQL digest per line. Empty lines and lines starting with # are ignored.
+    /// Ranking and thresholds are not applied, each digest is looked up in stats_mysql_query_digest.
+    ///
+    /// # Arguments
+    /// * `conn` - A reference to a connection to ProxySQL.
+    ///
+    /// # Returns
+    /// A vector of the queries found in stats_mysql_query_digest that are not in the mysql_query_rules table.
+    fn find_queries_from_digest_file(
+        &self,
+        con: &mut AdminConnection,
+    ) -> Result<Vec<Query>, SchedulerError> {
+        let path = match &self.query_discovery_digest_file {
+            Some(path) => path,
+            None => {
+                messages::print_error(
+                    "query_discovery_digest_file is required when query_discovery_mode is External",
+                );
+                return Ok(Vec::new());
+            }
+        };
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) => {
+                messages::print_error(
+                    format!("Failed to read digest file {}: {}", path, err).as_str(),
+                );
+                return Ok(Vec::new());
+            }
+        };
+
+        let mut queries = Vec::new();
+        for digest in contents
+            .lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        {
+            match self.find_query_by_digest(con, digest)? {
+                Some((_, Some(_))) => {
+                    messages::print_info(
+                        format!("Digest {} already has a query rule, skipping", digest).as_str(),
+                    );
+                }
+                Some((query, None)) => queries.push(query),
+                None => {
+                    messages::print_warning(
+                        format!(
+                            "Digest {} not found in stats_mysql_query_digest for users {}",
+                            digest,
+                            self.discovery_usernames.join(", ")
                         )
-                    })
-                    .collect()
+                        .as_str(),
+                    );
+                }
             }
         }
+        Ok(queries)
+    }
+
+    /// This function is used to look up a digest in stats_mysql_query_digest, for the queries of the discovery usernames.
+    ///
+    /// # Arguments
+    /// * `con` - A reference to a connection to ProxySQL.
+    /// * `digest` - The digest of the query.
+    ///
+    /// # Returns
+    /// The query and the id of its query rule if it has one, None if the digest is not in stats_mysql_query_digest.
+    fn find_query_by_digest(
+        &self,
+        con: &mut AdminConnection,
+        digest: &str,
+    ) -> Result<Option<(Query, Option<u32>)>, SchedulerError> {
+        let row: Option<(String, String, String, String, Option<u32>)> =
+            con.query_first(format!(
+                "SELECT s.digest_text, s.digest, s.schemaname, s.username, q.rule_id
+    FROM stats_mysql_query_digest s
+    LEFT JOIN mysql_query_rules q
+    USING(digest)
+    WHERE s.digest = '{}'
+    AND s.username IN ({})
+    ORDER BY s.count_star DESC
+    LIMIT 1",
+                digest.replace('\'', "''"),
+                self.username_list()
+            ))?;
+        Ok(row.map(|(digest_text, digest, schema, username, rule_id)| {
+            (
+                Query::new(
+                    self.replace_placeholders(&digest_text),
+                    digest_text,
+                    digest,
+                    schema,
+                    username,
+                    None,
+                ),
+                rule_id,
+            )
+        }))
     }
 
+    /// This function is used to cache a single query given by its digest, as requested with --cache-digest.
+    /// The ranking and thresholds of query discovery are bypassed, only Readyset support is checked.
+    /// In dry run, the query rule is only added to the plan.
+    ///
+    /// # Arguments
+    ///
+    /// * `proxysql` - The ProxySQL instance.
+    /// * `conn` - A reference to a connection to ProxySQL.
+    /// * `digest` - The digest of the query.
+    /// * `stats` - The run statistics, updated with the query cached.
+    /// * `plan` - The plan of the run, to add the query rule to.
+    ///
+    /// # Returns
+    ///
+    /// true if the query was cached, or would be in dry run. false if the digest was not found, already has a
+    /// query rule, is not supported or failed to cache, with the reason printed as an error.
+    pub fn cache_digest(
+        &mut self,
+        proxysql: &mut ProxySQL,
+        conn: &mut AdminConnection,
+        digest: &str,
+        stats: &mut RunStats,
+        plan: &mut Plan,
+    ) -> Result<bool, SchedulerError> {
+        let query = match self.find_query_by_digest(conn, digest)? {
+            Some((_, Some(rule_id))) => {
+                messages::print_error(
+                    format!("Digest {} already has query rule {}", digest, rule_id).as_str(),
+                );
+                return Ok(false);
+            }
+            Some((query, None)) => query,
+            None => {
+                messages::print_error(
+                    format!(
+                        "Digest {} not found in stats_mysql_query_digest for users {}",
+                        digest,
+                        self.discovery_usernames.join(", ")
+                    )
+                    .as_str(),
+                );
+                return Ok(false);
+            }
+        };
+        let readyset_hostgroup = proxysql.readyset_hostgroup_for_schema(query.get_schema());
+        let Some(mut supported) = proxysql.check_query_support(
+            readyset_hostgroup,
+            query.get_cache_text(),
+            query.get_schema(),
+        ) else {
+            messages::print_error(
+                format!(
+                    "No online Readyset host in hostgroup {} to cache digest {}",
+                    readyset_hostgroup, digest
+                )
+                .as_str(),
+            );
+            return Ok(false);
+        };
+        stats.queries_evaluated += 1;
+        let mut query = query;
+        if supported.as_ref().is_err_and(is_parse_error) {
+            if let Some((sample_supported, sample)) =
+                self.retry_with_sample(proxysql, Some(conn), &query, readyset_hostgroup, stats)
+            {
+                supported = sample_supported;
+                query = sample;
+            }
+        }
+        match supported {
+            Ok(true) => {}
+            Ok(false) => {
+                messages::print_error(
+                    format!(
+                        "Query {} is not supported by Readyset: {}",
+                        digest,
+                        query.get_cache_text()
+                    )
+                    .as_str(),
+                );
+                return Ok(false);
+            }
+            Err(err) => {
+                messages::print_error(
+                    format!(
+                        "Failed to check query support: {}, query: {} (original digest text: {})",
+                        err,
+                        query.get_cache_text(),
+                        query.get_original_digest_text()
+                    )
+                    .as_str(),
+                );
+                return Ok(false);
+            }
+        }
+        stats.queries_supported += 1;
+        plan.new_rules.push(NewRule {
+            query,
+            hostgroup: readyset_hostgroup,
+            eviction: None,
+        });
+        if proxysql.dry_run() {
+            return Ok(true);
+        }
+        if self.apply_new_rules(proxysql, plan, stats, None)? == 0 {
+            messages::print_error(format!("Failed to cache digest {}", digest).as_str());
+            return Ok(false);
+        }
+        proxysql.load_query_rules()?;
+        proxysql.save_query_rules()?;
+        Ok(true)
+    }
+
+    /// This function is used to compare the caches of the destination rules with the caches reported by
+    /// `SHOW READYSET CACHES` on each online Readyset host, as caches can be dropped out-of-band.
+    /// Rules whose cache is missing on a host are counted as drift, and handled according to reconcile_action.
+    /// A rule is only removed when no online host has its cache, otherwise the cache is recreated.
+    /// In dry run, the drift is only reported.
+    ///
+    /// # Arguments
+    ///
+    /// * `action` - What to do with the rules whose cache is missing.
+    /// * `proxysql` - The ProxySQL instance.
+    /// * `con` - A reference to a connection to ProxySQL.
+    /// * `stats` - The run statistics, updated with the drift found.
+    ///
+    /// # Returns
+    ///
+    /// The number of rules removed.
+    fn reconcile_caches(
+        &self,
+        action: ReconcileAction,
+        proxysql: &mut ProxySQL,
+        con: &mut AdminConnection,
+        stats: &mut RunStats,
+    ) -> Result<u32, SchedulerError> {
+        let dry_run = proxysql.dry_run();
+        let mut removed_rules = 0;
+        for (rule_id, hostgroup, digest) in proxysql.find_destination_rules()? {
+            let mut missing_hosts = Vec::new();
+            // the rule is only removed when no online host is known to still serve the cache
+            let mut served = false;
+            for host in proxysql.get_online_hosts(hostgroup) {
+                match host.has_cache(&digest) {
+                    Ok(true) => served = true,
+                    Ok(false) => missing_hosts.push(host),
+                    Err(err) => {
+                        served = true;
+                        messages::print_warning(
+                            format!(
+                                "Failed to list caches on host {}:{}: {}",
+                                host.get_hostname(),
+                                host.get_port(),
+                                err
+                            )
+                            .as_str(),
+                        )
+                    }
+                }
+            }
+            if missing_hosts.is_empty() {
+                continue;
+            }
+            stats.cache_drift += 1;
+            messages::print_warning(
+                format!(
+                    "Cache of rule ID {} for digest {} is missing on {} host(s), reconcile_action is {}",
+                    rule_id,
+                    digest,
+                    missing_hosts.len(),
+                    action
+                )
+                .as_str(),
+            );
+            if dry_run {
+                continue;
+            }
+            match action {
+                ReconcileAction::ReportOnly => {}
+                ReconcileAction::RemoveRule if !served => {
+                    proxysql.delete_query_rule(rule_id)?;
+                    messages::print_note(
+                        format!("Removed rule ID {} of digest {}", rule_id, digest).as_str(),
+                    );
+                    removed_rules += 1;
+                }
+                ReconcileAction::Recreate | ReconcileAction::RemoveRule => {
+                    self.recreate_caches(con, &digest, missing_hosts)?;
+                }
+            }
+        }
+        Ok(removed_rules)
+    }
+
+    /// This function is used to create the cache of a destination rule again on the hosts missing it.
+    ///
+    /// # Arguments
+    ///
+    /// * `con` - A reference to a connection to ProxySQL.
+    /// * `digest` - The digest of the query of the rule.
+    /// * `hosts` - The online Readyset hosts missing the cache.
+    fn recreate_caches(
+        &self,
+        con: &mut AdminConnection,
+        digest: &str,
+        hosts: Vec<&mut Host>,
+    ) -> Result<(), SchedulerError> {
+        let row: Option<(String, String)> = con.query_first(format!(
+            "SELECT digest_text, schemaname FROM stats_mysql_query_digest WHERE digest = '{}' LIMIT 1",
+            digest
+        ))?;
+        let Some((digest_text, schema)) = row else {
+            messages::print_warning(
+                format!(
+                    "Digest {} not found in stats_mysql_query_digest, can't recreate its cache",
+                    digest
+                )
+                .as_str(),
+            );
+            return Ok(());
+        };
+        let query = Query::new(
+            self.replace_placeholders(&digest_text),
+            digest_text,
+            digest.to_string(),
+            schema,
+            self.readyset_user.clone(),
+            None,
+        );
+        for host in hosts {
+            if let Err(err) = host.cache_query(&query) {
+                messages::print_error(
+                    format!(
+                        "Failed to recreate cache for digest {} on host {}:{}: {}",
+                        digest,
+                        host.get_hostname(),
+                        host.get_port(),
+                        err
+                    )
+                    .as_str(),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// This function is used to check the support of a query again with a sample of it, after Readyset failed
+    /// to parse its digest text. Looking up the sample uses the max_total_retries budget.
+    ///
+    /// # Arguments
+    ///
+    /// * `proxysql` - The ProxySQL instance.
+    /// * `con` - A reference to a connection to ProxySQL, None if it failed to open.
+    /// * `query` - The query whose digest text failed to parse.
+    /// * `hostgroup` - The Readyset hostgroup of the query.
+    /// * `stats` - The run statistics, updated with the parse failure.
+    ///
+    /// # Returns
+    ///
+    /// The support of the sample, and the query with the sample as the text to cache. None if no sample is available.
+    fn retry_with_sample(
+        &self,
+        proxysql: &mut ProxySQL,
+        con: Option<&mut AdminConnection>,
+        query: &Query,
+        hostgroup: u16,
+        stats: &mut RunStats,
+    ) -> Option<(Result<bool, SchedulerError>, Query)> {
+        stats.parse_failures += 1;
+        let con = con?;
+        if !retries::try_consume() {
+            return None;
+        }
+        let sample = self
+            .find_sample_query(con, query.get_digest())
+            .unwrap_or_else(|err| {
+                messages::print_warning(
+                    format!(
+                        "Failed to find a sample of query {}: {}",
+                        query.get_digest(),
+                        err
+                    )
+                    .as_str(),
+                );
+                None
+            })?;
+        messages::print_note(
+            format!(
+                "Readyset failed to parse the digest text of query {}, retrying with a sample query",
+                query.get_digest()
+            )
+            .as_str(),
+        );
+        let supported = proxysql
+            .check_query_support(hostgroup, &sample, query.get_schema())
+            .unwrap_or(Ok(false));
+        let mut sample_query = query.clone();
+        sample_query.set_concrete_text(sample);
+        Some((supported, sample_query))
+    }
+
+    /// This function is used to find a sample of a query, with its actual values instead of placeholders.
+    /// Samples are read from stats_mysql_query_events, which is only populated when ProxySQL keeps
+    /// query events in memory (mysql-eventslog_buffer_history_size > 0).
+    ///
+    /// # Arguments
+    ///
+    /// * `con` - A reference to a connection to ProxySQL.
+    /// * `digest` - The digest of the query.
+    ///
+    /// # Returns
+    ///
+    /// The most recent sample of the query, None if there is none.
+    fn find_sample_query(
+        &self,
+        con: &mut AdminConnection,
+        digest: &str,
+    ) -> Result<Option<String>, SchedulerError> {
+        con.query_first(sample_query_sql(digest))
+    }
+
+    /// This function is used to turn the digest text of a query into valid SQL,
+    /// applying the placeholder rules in order.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The digest text of the query.
+    ///
+    /// # Returns
+    ///
+    /// The digest text with the placeholder rules applied.
     fn replace_placeholders(&self, query: &str) -> String {
-        // date placeholder
+        self.placeholder_rules
+            .iter()
+            .fold(query.to_string(), |query, (from, to)| {
+                let replaced = from.replace_all(&query, to.as_str()).into_owned();
+                if self.trace_normalization && replaced != query {
+                    messages::print_note(
+                        format!(
+                            "Placeholder rule '{}' -> '{}' changed {} to {}",
+                            from.as_str(),
+                            to,
+                            query,
+                            replaced
+                        )
+                        .as_str(),
+                    );
+                }
+                replaced
+            })
+    }
+}
+
+/// This function is used to build the query reading the most recent sample of a query from stats_mysql_query_events.
+///
+/// # Arguments
+///
+/// * `digest` - The digest of the query.
+///
+/// # Returns
+///
+/// The query selecting the sample.
+fn sample_query_sql(digest: &str) -> String {
+    format!(
+        "SELECT query FROM stats_mysql_query_events WHERE query_digest = '{}' ORDER BY start_time DESC LIMIT 1",
+        digest.replace('\'', "''")
+    )
+}
+
+/// This function is used to get the placeholder rules used when query_discovery_placeholder_rules is not configured.
+///
+/// # Returns
+///
+/// The rules collapsing multiple placeholders and replacing date placeholders.
+fn default_placeholder_rules() -> Vec<PlaceholderRule> {
+    vec![
         // multiple placeholders
-        query.replace("?,?,?,...", "?,?,?").replace("?-?-?", "?")
+        PlaceholderRule {
+            from: "?,?,?,...".to_string(),
+            to: "?,?,?".to_string(),
+            regex: false,
+        },
+        // date placeholder
+        PlaceholderRule {
+            from: "?-?-?".to_string(),
+            to: "?".to_string(),
+            regex: false,
+        },
+    ]
+}
+
+/// This function is used to compile the placeholder rules.
+/// Literal rules are escaped, and rules with an invalid regular expression are ignored with a warning.
+///
+/// # Arguments
+///
+/// * `rules` - The configured placeholder rules.
+///
+/// # Returns
+///
+/// The compiled pattern and replacement of each rule, in order.
+fn placeholder_rules(rules: Vec<PlaceholderRule>) -> Vec<(Regex, String)> {
+    rules
+        .into_iter()
+        .filter_map(|rule| {
+            let (pattern, to) = match rule.regex {
+                true => (rule.from, rule.to),
+                // $ would otherwise be read as a capture group in the replacement
+                false => (regex::escape(&rule.from), rule.to.replace('$', "$$")),
+            };
+            match Regex::new(&pattern) {
+                Ok(from) => Some((from, to)),
+                Err(err) => {
+                    messages::print_warning(
+                        format!("Ignoring invalid placeholder rule {}: {}", pattern, err).as_str(),
+                    );
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// This function is used to estimate how expensive a query is to cache in Readyset.
+/// Each JOIN, aggregate function, GROUP BY, DISTINCT and HAVING adds 1, and each subquery or UNION adds 2.
+///
+/// # Arguments
+///
+/// * `digest_text` - The digest text of the query.
+///
+/// # Returns
+///
+/// The estimated complexity of the query. A simple single table SELECT has complexity 0.
+fn estimate_query_complexity(digest_text: &str) -> u32 {
+    const AGGREGATES: [&str; 6] = ["COUNT", "SUM", "AVG", "MIN", "MAX", "GROUP_CONCAT"];
+    let text = digest_text.to_uppercase();
+    let mut complexity: u32 = 0;
+    let mut selects: u32 = 0;
+    let mut unions: u32 = 0;
+    let mut rest = text.as_str();
+    while let Some(start) = rest.find(|c: char| c.is_alphanumeric() || c == '_') {
+        let token_len = rest[start..]
+            .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .unwrap_or(rest.len() - start);
+        let token = &rest[start..start + token_len];
+        rest = &rest[start + token_len..];
+        match token {
+            "SELECT" => selects += 1,
+            "JOIN" | "GROUP" | "DISTINCT" | "HAVING" => complexity += 1,
+            "UNION" => unions += 1,
+            _ if AGGREGATES.contains(&token) && rest.trim_start().starts_with('(') => {
+                complexity += 1
+            }
+            _ => {}
+        }
+    }
+    // every SELECT after the first one is either a subquery or part of a UNION
+    complexity + (selects.saturating_sub(1).max(unions)) * 2
+}
+
+/// This function is used to count the tables referenced by a query.
+/// Each FROM and JOIN counts as one table, as well as each comma separated item of a FROM clause,
+/// so tables in subqueries and derived tables are counted as well. FROM inside a function call,
+/// such as `EXTRACT(YEAR FROM d)` or `TRIM(LEADING ? FROM s)`, is not a table.
+///
+/// # Arguments
+///
+/// * `digest_text` - The digest text of the query.
+///
+/// # Returns
+///
+/// The number of tables referenced by the query.
+fn count_referenced_tables(digest_text: &str) -> u32 {
+    const FROM_CLAUSE_END: [&str; 7] = [
+        "WHERE", "GROUP", "HAVING", "ORDER", "LIMIT", "UNION", "WINDOW",
+    ];
+    let mut tokens: Vec<String> = Vec::new();
+    let mut word = String::new();
+    for c in digest_text.chars() {
+        if c.is_alphanumeric() || c == '_' {
+            word.push(c.to_ascii_uppercase());
+            continue;
+        }
+        if !word.is_empty() {
+            tokens.push(std::mem::take(&mut word));
+        }
+        if matches!(c, '(' | ')' | ',') {
+            tokens.push(c.to_string());
+        }
+    }
+    if !word.is_empty() {
+        tokens.push(word);
+    }
+    // for the query and each open parenthesis: whether it is a subquery, where FROM starts a FROM clause,
+    // and whether its FROM clause is being read
+    let mut levels: Vec<(bool, bool)> = vec![(true, false)];
+    let mut tables = 0;
+    for (index, token) in tokens.iter().enumerate() {
+        match token.as_str() {
+            "(" => {
+                let subquery = tokens.get(index + 1).is_some_and(|next| next == "SELECT");
+                levels.push((subquery, false));
+            }
+            ")" if levels.len() > 1 => {
+                levels.pop();
+            }
+            "JOIN" => tables += 1,
+            _ => {
+                let level = levels.last_mut().expect("the query level is never removed");
+                match token.as_str() {
+                    "FROM" if level.0 => {
+                        tables += 1;
+                        level.1 = true;
+                    }
+                    "," if level.1 => tables += 1,
+                    _ if FROM_CLAUSE_END.contains(&token.as_str()) => level.1 = false,
+                    _ => {}
+                }
+            }
+        }
+    }
+    tables
+}
+
+/// Default LIKE pattern of the digest text of the queries to consider for caching.
+const DEFAULT_DIGEST_TEXT_FILTER: &str = "SELECT%FROM%";
+
+/// LIKE pattern of the queries starting with a CTE, added with query_discovery_include_cte.
+const CTE_DIGEST_TEXT_FILTER: &str = "WITH%";
+
+/// This function is used to validate the LIKE patterns of query_discovery_digest_text_filter.
+/// Empty patterns are ignored.
+///
+/// # Arguments
+///
+/// * `patterns` - The patterns as configured.
+/// * `include_cte` - Whether queries starting with a CTE are also candidates.
+///
+/// # Returns
+///
+/// The non empty patterns, or the default SELECT pattern if none is left, followed by the CTE pattern if included.
+fn digest_text_filter(patterns: Vec<String>, include_cte: bool) -> Vec<String> {
+    let mut patterns = patterns
+        .into_iter()
+        .map(|pattern| pattern.trim().to_string())
+        .filter(|pattern| !pattern.is_empty())
+        .collect::<Vec<String>>();
+    if patterns.is_empty() {
+        patterns.push(DEFAULT_DIGEST_TEXT_FILTER.to_string());
+    }
+    if include_cte
+        && !patterns
+            .iter()
+            .any(|pattern| pattern == CTE_DIGEST_TEXT_FILTER)
+    {
+        patterns.push(CTE_DIGEST_TEXT_FILTER.to_string());
+    }
+    patterns
+}
+
+/// This function is used to validate the users of discovery_usernames.
+/// Empty usernames are ignored.
+///
+/// # Arguments
+///
+/// * `usernames` - The usernames as configured.
+/// * `readyset_user` - The Readyset application user.
+///
+/// # Returns
+///
+/// The non empty usernames, or readyset_user if none is left.
+fn discovery_usernames(usernames: Vec<String>, readyset_user: &str) -> Vec<String> {
+    let usernames = usernames
+        .into_iter()
+        .map(|username| username.trim().to_string())
+        .filter(|username| !username.is_empty())
+        .collect::<Vec<String>>();
+    if usernames.is_empty() {
+        return vec![readyset_user.to_string()];
+    }
+    usernames
+}
+
+/// This function is used to validate the weights of the Composite query discovery mode.
+/// Unknown columns and weights that are not positive are ignored with a warning.
+///
+/// # Arguments
+///
+/// * `weights` - The configured weight of each stats_mysql_query_digest column.
+///
+/// # Returns
+///
+/// The valid column and weight pairs, sorted by column name.
+fn composite_weights(weights: HashMap<String, f64>) -> Vec<(String, f64)> {
+    let mut valid_weights = weights
+        .into_iter()
+        .filter(|(column, weight)| {
+            if !COMPOSITE_COLUMNS.contains(&column.as_str()) {
+                messages::print_warning(
+                    format!(
+                        "Ignoring unknown column {} in query_discovery_composite_weights, valid columns are {}",
+                        column,
+                        COMPOSITE_COLUMNS.join(", ")
+                    )
+                    .as_str(),
+                );
+                return false;
+            }
+            if !(*weight > 0.0 && weight.is_finite()) {
+                messages::print_warning(
+                    format!(
+                        "Ignoring weight {} of column {} in query_discovery_composite_weights, weights must be positive",
+                        weight, column
+                    )
+                    .as_str(),
+                );
+                return false;
+            }
+            true
+        })
+        .collect::<Vec<(String, f64)>>();
+    valid_weights.sort_by(|(a, _), (b, _)| a.cmp(b));
+    valid_weights
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{admin::fake::FakeBackend, config::test_config, hosts::fake::FakeReadyset};
+    use mysql::Value;
+
+    /// Builds a ProxySQL managing one online Readyset host, served by `readyset` and supporting every query.
+    fn proxysql_with_host(
+        fake: &FakeBackend,
+        readyset: &FakeReadyset,
+        extra_config: &str,
+    ) -> ProxySQL {
+        readyset.respond(
+            "EXPLAIN CREATE CACHE FROM",
+            &["query id", "proxied query", "readyset supported"],
+            vec![vec!["q_1", "SELECT", "yes"]],
+        );
+        let port = readyset.start();
+        fake.respond(
+            "FROM mysql_servers",
+            &["hostgroup_id", "hostname", "port", "status", "comment"],
+            vec![vec![
+                Value::from("99"),
+                Value::from("127.0.0.1"),
+                Value::from(port.to_string()),
+                Value::from("ONLINE"),
+                Value::from("Readyset"),
+            ]],
+        )
+        .respond(
+            "stats_mysql_query_digest LIMIT 0",
+            &OPTIONAL_DIGEST_COLUMNS,
+            vec![],
+        );
+        let conn = AdminConnection::new(fake.clone());
+        ProxySQL::with_connection(conn, &test_config(extra_config), false, None).unwrap()
+    }
+
+    const CANDIDATE_COLUMNS: [&str; 10] = [
+        "digest_text",
+        "digest",
+        "schemaname",
+        "count_star",
+        "sum_time",
+        "min_time",
+        "max_time",
+        "sum_rows_sent",
+        "ranking",
+        "username",
+    ];
+
+    fn candidate(digest_text: &str, digest: &str) -> Vec<Value> {
+        [
+            digest_text,
+            digest,
+            "shop",
+            "100",
+            "5000",
+            "10",
+            "90",
+            "100",
+            "100",
+            "root",
+        ]
+        .iter()
+        .map(|value| Value::from(*value))
+        .collect()
+    }
+
+    #[test]
+    fn count_referenced_tables_counts_from_and_join() {
+        assert_eq!(count_referenced_tables("SELECT * FROM t WHERE id = ?"), 1);
+        assert_eq!(count_referenced_tables("SELECT a, b, c FROM t"), 1);
+        assert_eq!(
+            count_referenced_tables(
+                "SELECT * FROM a JOIN b ON a.id = b.a_id LEFT JOIN c USING (id)"
+            ),
+            3
+        );
+    }
+
+    #[test]
+    fn count_referenced_tables_counts_comma_joins() {
+        assert_eq!(
+            count_referenced_tables("SELECT * FROM a, b, c WHERE a.id = b.id AND b.id IN (?, ?)"),
+            3
+        );
+        assert_eq!(
+            count_referenced_tables("SELECT * FROM a,b ORDER BY a.id, b.id"),
+            2
+        );
+    }
+
+    #[test]
+    fn count_referenced_tables_skips_from_in_function_calls() {
+        assert_eq!(
+            count_referenced_tables(
+                "SELECT EXTRACT(YEAR FROM created), TRIM(LEADING ? FROM name) FROM t"
+            ),
+            1
+        );
+        assert_eq!(
+            count_referenced_tables("SELECT SUBSTRING(name FROM ?) FROM t, u"),
+            2
+        );
+    }
+
+    #[test]
+    fn count_referenced_tables_counts_subqueries_and_derived_tables() {
+        assert_eq!(
+            count_referenced_tables("SELECT * FROM a WHERE id IN (SELECT a_id FROM b, c)"),
+            3
+        );
+        assert_eq!(
+            count_referenced_tables("SELECT * FROM (SELECT id FROM a, b) d, c WHERE d.id = c.id"),
+            4
+        );
+    }
+
+    #[test]
+    fn default_placeholder_rules_collapse_lists_and_dates() {
+        let query_discovery = QueryDiscovery::new(test_config(""));
+        assert_eq!(
+            query_discovery
+                .replace_placeholders("SELECT * FROM t WHERE id IN (?,?,?,...) AND d = ?-?-?"),
+            "SELECT * FROM t WHERE id IN (?,?,?) AND d = ?"
+        );
+    }
+
+    #[test]
+    fn placeholder_rules_collapse_the_spaced_variant() {
+        let query_discovery = QueryDiscovery::new(test_config(
+            r#"query_discovery_placeholder_rules = [{ from = "?,?,?,...", to = "?,?,?" }, { from = "?, ?, ?, ...", to = "?, ?, ?" }]"#,
+        ));
+        assert_eq!(
+            query_discovery.replace_placeholders("SELECT * FROM t WHERE id IN (?, ?, ?, ...)"),
+            "SELECT * FROM t WHERE id IN (?, ?, ?)"
+        );
+        assert_eq!(
+            query_discovery.replace_placeholders("SELECT * FROM t WHERE id IN (?,?,?,...)"),
+            "SELECT * FROM t WHERE id IN (?,?,?)"
+        );
+    }
+
+    #[test]
+    fn placeholder_rules_accept_regular_expressions() {
+        let query_discovery = QueryDiscovery::new(test_config(
+            r#"query_discovery_placeholder_rules = [{ from = '\?(,\s*\?)*,\s*\.\.\.', to = "?", regex = true }]"#,
+        ));
+        assert_eq!(
+            query_discovery.replace_placeholders(
+                "SELECT * FROM t WHERE id IN (?,  ?, ?, ...) AND a IN (?,?,...)"
+            ),
+            "SELECT * FROM t WHERE id IN (?) AND a IN (?)"
+        );
+    }
+
+    #[test]
+    fn run_stops_after_the_current_query_when_a_stop_is_requested() {
+        let fake = FakeBackend::default();
+        let readyset = FakeReadyset::default();
+        let mut proxysql = proxysql_with_host(&fake, &readyset, "");
+        fake.respond(
+            "FROM stats_mysql_query_digest s",
+            &CANDIDATE_COLUMNS,
+            vec![
+                candidate("SELECT * FROM t WHERE id = ?", "0x1"),
+                candidate("SELECT * FROM u WHERE id = ?", "0x2"),
+            ],
+        );
+        let mut query_discovery = QueryDiscovery::new(test_config(""));
+        let stop = Arc::new(AtomicBool::new(false));
+        query_discovery.stop = stop.clone();
+        // the stop is requested while the rule of the first query is added
+        fake.on_statement("INSERT INTO mysql_query_rules", move || {
+            stop.store(true, Ordering::Relaxed)
+        });
+        let mut conn = AdminConnection::new(fake.clone());
+        query_discovery
+            .run(
+                &mut proxysql,
+                &mut conn,
+                &mut RunStats::default(),
+                &mut Plan::default(),
+            )
+            .unwrap();
+        let inserts = fake.statements_containing("INSERT INTO mysql_query_rules");
+        assert_eq!(inserts.len(), 1);
+        assert!(inserts[0].contains("'0x1'"));
+        assert_eq!(readyset.statements_containing("CREATE CACHE d_").len(), 1);
+        // the second query is left for the next run, and the rule of the first one is loaded
+        assert_eq!(query_discovery.offset, 1);
+        assert_eq!(
+            fake.statements_containing("LOAD MYSQL QUERY RULES TO RUNTIME")
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn run_checks_every_query_before_applying_the_plan() {
+        let fake = FakeBackend::default();
+        let readyset = FakeReadyset::default();
+        let mut proxysql = proxysql_with_host(&fake, &readyset, "");
+        fake.respond(
+            "FROM stats_mysql_query_digest s",
+            &CANDIDATE_COLUMNS,
+            vec![
+                candidate("SELECT * FROM t WHERE id = ?", "0x1"),
+                candidate("SELECT * FROM u WHERE id = ?", "0x2"),
+            ],
+        );
+        let checks = readyset.clone();
+        fake.on_statement("INSERT INTO mysql_query_rules", move || {
+            assert_eq!(
+                checks
+                    .statements_containing("EXPLAIN CREATE CACHE FROM")
+                    .len(),
+                2
+            )
+        });
+        let mut query_discovery = QueryDiscovery::new(test_config(""));
+        let mut conn = AdminConnection::new(fake.clone());
+        let mut plan = Plan::default();
+        query_discovery
+            .run(
+                &mut proxysql,
+                &mut conn,
+                &mut RunStats::default(),
+                &mut plan,
+            )
+            .unwrap();
+        assert_eq!(readyset.statements_containing("CREATE CACHE d_").len(), 2);
+        assert_eq!(
+            fake.statements_containing("INSERT INTO mysql_query_rules")
+                .len(),
+            2
+        );
+        assert_eq!(plan.new_rules.len(), 2);
+    }
+
+    #[test]
+    fn run_keeps_the_lowest_value_rule_when_the_new_cache_fails() {
+        let fake = FakeBackend::default();
+        let readyset = FakeReadyset::default();
+        readyset.fail_on("CREATE CACHE d_0x1", "out of memory");
+        let mut proxysql = proxysql_with_host(
+            &fake,
+            &readyset,
+            "max_total_caches = 1\nmax_total_caches_evict = true",
+        );
+        fake.respond(
+            "SELECT digest FROM mysql_query_rules",
+            &["digest"],
+            vec![vec![Value::from("0x9")]],
+        )
+        .respond(
+            "COALESCE(SUM(s.count_star), 0)",
+            &["rule_id", "hostgroup", "digest", "count_star"],
+            vec![vec![
+                Value::from("7"),
+                Value::from("99"),
+                Value::from("0x9"),
+                Value::from("1"),
+            ]],
+        )
+        .respond(
+            "FROM stats_mysql_query_digest s",
+            &CANDIDATE_COLUMNS,
+            vec![candidate("SELECT * FROM t WHERE id = ?", "0x1")],
+        );
+        let mut query_discovery = QueryDiscovery::new(test_config(
+            "max_total_caches = 1\nmax_total_caches_evict = true",
+        ));
+        let mut conn = AdminConnection::new(fake.clone());
+        let mut plan = Plan::default();
+        query_discovery
+            .run(
+                &mut proxysql,
+                &mut conn,
+                &mut RunStats::default(),
+                &mut plan,
+            )
+            .unwrap();
+        assert!(fake
+            .statements_containing("DELETE FROM mysql_query_rules")
+            .is_empty());
+        assert!(readyset.statements_containing("DROP CACHE").is_empty());
+        assert!(plan.is_empty());
+    }
+
+    #[test]
+    fn run_evicts_the_lowest_value_rule_once_the_new_rule_exists() {
+        let fake = FakeBackend::default();
+        let readyset = FakeReadyset::default();
+        let mut proxysql = proxysql_with_host(
+            &fake,
+            &readyset,
+            "max_total_caches = 1\nmax_total_caches_evict = true",
+        );
+        fake.respond(
+            "SELECT digest FROM mysql_query_rules",
+            &["digest"],
+            vec![vec![Value::from("0x9")]],
+        )
+        .respond(
+            "COALESCE(SUM(s.count_star), 0)",
+            &["rule_id", "hostgroup", "digest", "count_star"],
+            vec![vec![
+                Value::from("7"),
+                Value::from("99"),
+                Value::from("0x9"),
+                Value::from("1"),
+            ]],
+        )
+        .respond(
+            "FROM stats_mysql_query_digest s",
+            &CANDIDATE_COLUMNS,
+            vec![candidate("SELECT * FROM t WHERE id = ?", "0x1")],
+        );
+        let mut query_discovery = QueryDiscovery::new(test_config(
+            "max_total_caches = 1\nmax_total_caches_evict = true",
+        ));
+        // the rule of the new query is inserted before the evicted one is deleted
+        let inserts = fake.clone();
+        fake.on_statement("DELETE FROM mysql_query_rules", move || {
+            assert_eq!(
+                inserts
+                    .statements_containing("INSERT INTO mysql_query_rules")
+                    .len(),
+                1
+            )
+        });
+        let mut conn = AdminConnection::new(fake.clone());
+        let mut plan = Plan::default();
+        query_discovery
+            .run(
+                &mut proxysql,
+                &mut conn,
+                &mut RunStats::default(),
+                &mut plan,
+            )
+            .unwrap();
+        assert_eq!(
+            fake.statements_containing("DELETE FROM mysql_query_rules WHERE rule_id = 7")
+                .len(),
+            1
+        );
+        assert_eq!(readyset.statements_containing("DROP CACHE d_0x9").len(), 1);
+        assert_eq!(plan.new_rules.len(), 1);
+        assert_eq!(plan.all_removals().count(), 1);
+    }
+
+    /// Runs the cache reconciliation with remove_rule on two hosts missing the cache of digest 0x1,
+    /// except the first one if `served` is set.
+    fn reconcile_remove_rule(served: bool) -> FakeBackend {
+        let fake = FakeBackend::default();
+        let with_cache = FakeReadyset::default();
+        if served {
+            with_cache.respond("SHOW READYSET CACHES", &["name"], vec![vec!["d_0x1"]]);
+        }
+        let without_cache = FakeReadyset::default();
+        let rows = [with_cache.start(), without_cache.start()]
+            .iter()
+            .map(|port| {
+                vec![
+                    Value::from("99"),
+                    Value::from("127.0.0.1"),
+                    Value::from(port.to_string()),
+                    Value::from("ONLINE"),
+                    Value::from("Readyset"),
+                ]
+            })
+            .collect();
+        fake.respond(
+            "FROM mysql_servers",
+            &["hostgroup_id", "hostname", "port", "status", "comment"],
+            rows,
+        )
+        .respond(
+            "SELECT rule_id, destination_hostgroup, digest",
+            &["rule_id", "destination_hostgroup", "digest"],
+            vec![vec![
+                Value::from("7"),
+                Value::from("99"),
+                Value::from("0x1"),
+            ]],
+        )
+        .respond(
+            "SELECT digest_text, schemaname",
+            &["digest_text", "schemaname"],
+            vec![vec![
+                Value::from("SELECT * FROM t WHERE id = ?"),
+                Value::from("shop"),
+            ]],
+        );
+        let mut proxysql = ProxySQL::with_connection(
+            AdminConnection::new(fake.clone()),
+            &test_config(""),
+            false,
+            None,
+        )
+        .unwrap();
+        let mut conn = AdminConnection::new(fake.clone());
+        let query_discovery = QueryDiscovery::new(test_config(""));
+        let mut stats = RunStats::default();
+        query_discovery
+            .reconcile_caches(
+                ReconcileAction::RemoveRule,
+                &mut proxysql,
+                &mut conn,
+                &mut stats,
+            )
+            .unwrap();
+        assert_eq!(stats.cache_drift, 1);
+        assert_eq!(
+            without_cache
+                .statements_containing("CREATE CACHE d_0x1")
+                .len(),
+            usize::from(served)
+        );
+        fake
+    }
+
+    #[test]
+    fn reconcile_recreates_a_cache_still_served_by_another_host() {
+        let fake = reconcile_remove_rule(true);
+        assert!(fake
+            .statements_containing("DELETE FROM mysql_query_rules")
+            .is_empty());
+    }
+
+    #[test]
+    fn reconcile_removes_a_rule_once_no_host_has_the_cache() {
+        let fake = reconcile_remove_rule(false);
+        assert_eq!(
+            fake.statements_containing("DELETE FROM mysql_query_rules WHERE rule_id = 7")
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn run_stops_reading_candidates_once_enough_queries_are_cached() {
+        let fake = FakeBackend::default();
+        let readyset = FakeReadyset::default();
+        let mut proxysql = proxysql_with_host(&fake, &readyset, "");
+        fake.respond(
+            "FROM stats_mysql_query_digest s",
+            &CANDIDATE_COLUMNS,
+            vec![
+                candidate("SELECT * FROM t WHERE id = ?", "0x1"),
+                candidate("SELECT * FROM u WHERE id = ?", "0x2"),
+                candidate("SELECT * FROM v WHERE id = ?", "0x3"),
+            ],
+        );
+        let mut query_discovery = QueryDiscovery::new(test_config(""));
+        query_discovery.number_of_queries = 1;
+        let mut conn = AdminConnection::new(fake.clone());
+        query_discovery
+            .run(
+                &mut proxysql,
+                &mut conn,
+                &mut RunStats::default(),
+                &mut Plan::default(),
+            )
+            .unwrap();
+        // the candidates are queried once, and the ones after the first are not checked
+        assert_eq!(
+            fake.statements_containing("FROM stats_mysql_query_digest s")
+                .len(),
+            1
+        );
+        assert_eq!(
+            readyset
+                .statements_containing("EXPLAIN CREATE CACHE FROM")
+                .len(),
+            1
+        );
+        assert_eq!(query_discovery.offset, 1);
+    }
+
+    #[test]
+    fn run_only_opens_the_sample_connection_when_a_sample_is_needed() {
+        let fake = FakeBackend::default();
+        let readyset = FakeReadyset::default();
+        let mut proxysql = proxysql_with_host(&fake, &readyset, "");
+        fake.respond(
+            "FROM stats_mysql_query_digest s",
+            &CANDIDATE_COLUMNS,
+            vec![candidate("SELECT * FROM t WHERE id = ?", "0x1")],
+        );
+        let mut query_discovery = QueryDiscovery::new(test_config(""));
+        let mut conn = AdminConnection::new(fake.clone());
+        query_discovery
+            .run(
+                &mut proxysql,
+                &mut conn,
+                &mut RunStats::default(),
+                &mut Plan::default(),
+            )
+            .unwrap();
+        assert_eq!(readyset.statements_containing("CREATE CACHE d_").len(), 1);
+        assert_eq!(fake.connections_opened(), 0);
+    }
+
+    #[test]
+    fn run_caches_the_digest_text_when_the_sample_connection_fails() {
+        let fake = FakeBackend::default();
+        let readyset = FakeReadyset::default();
+        let mut proxysql = proxysql_with_host(&fake, &readyset, "");
+        fake.respond(
+            "FROM stats_mysql_query_digest s",
+            &CANDIDATE_COLUMNS,
+            vec![
+                candidate("SELECT * FROM t WHERE id = ?", "0x1"),
+                candidate("SELECT * FROM u WHERE id = ?", "0x2"),
+            ],
+        )
+        .refuse_connections();
+        let mut query_discovery = QueryDiscovery::new(test_config("cache_concrete_queries = true"));
+        let mut conn = AdminConnection::new(fake.clone());
+        query_discovery
+            .run(
+                &mut proxysql,
+                &mut conn,
+                &mut RunStats::default(),
+                &mut Plan::default(),
+            )
+            .unwrap();
+        assert_eq!(readyset.statements_containing("CREATE CACHE d_").len(), 2);
+        assert!(fake
+            .statements_containing("FROM stats_mysql_query_events")
+            .is_empty());
+    }
+
+    #[test]
+    fn query_builder_only_skips_candidates_from_an_offset() {
+        let mut query_discovery = QueryDiscovery::new(test_config(""));
+        assert!(!query_discovery.query_builder().contains("LIMIT"));
+        query_discovery.offset = 5;
+        assert!(query_discovery
+            .query_builder()
+            .ends_with("\n    LIMIT -1 OFFSET 5"));
+    }
+
+    #[test]
+    fn find_queries_to_cache_carries_the_metrics() {
+        let fake = FakeBackend::default();
+        fake.respond(
+            "FROM stats_mysql_query_digest s",
+            &CANDIDATE_COLUMNS,
+            vec![candidate("SELECT * FROM t WHERE id = ?", "0x1")],
+        );
+        let mut conn = AdminConnection::new(fake.clone());
+        let query_discovery = QueryDiscovery::new(test_config(""));
+        let queries: Vec<Query> = query_discovery
+            .find_queries_to_cache(&mut conn)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let metrics = queries[0].get_metrics().unwrap();
+        assert_eq!(metrics.count_star, 100);
+        assert_eq!(metrics.sum_time, 5000);
+        assert_eq!(metrics.min_time, 10);
+        assert_eq!(metrics.max_time, 90);
+        assert_eq!(metrics.sum_rows_sent, 100);
+        assert_eq!(metrics.ranking, 100.0);
+    }
+
+    #[test]
+    fn query_metrics_report_the_mean_time() {
+        let metrics = QueryMetrics {
+            count_star: 4,
+            sum_time: 1000,
+            min_time: 100,
+            max_time: 400,
+            sum_rows_sent: 8,
+            ranking: 4.0,
+        };
+        assert_eq!(metrics.mean_time(), 250);
+        assert_eq!(
+            metrics.to_string(),
+            "count_star=4 sum_time_us=1000 mean_time_us=250 min_time_us=100 max_time_us=400 sum_rows_sent=8"
+        );
+        // a query never executed has no mean time, instead of dividing by 0
+        assert_eq!(QueryMetrics::default().mean_time(), 0);
+    }
+
+    #[test]
+    fn query_builder_matches_any_digest_text_filter() {
+        let query_discovery = QueryDiscovery::new(test_config(""));
+        assert!(query_discovery
+            .query_builder()
+            .contains("AND (s.digest_text LIKE 'SELECT%FROM%')\n"));
+        let query_discovery =
+            QueryDiscovery::new(test_config("query_discovery_include_cte = true"));
+        assert!(query_discovery
+            .query_builder()
+            .contains("AND (s.digest_text LIKE 'SELECT%FROM%' OR s.digest_text LIKE 'WITH%')\n"));
+        let query_discovery = QueryDiscovery::new(test_config(
+            r#"query_discovery_digest_text_filter = ["SELECT%FROM%", " ", "%'x'%"]"#,
+        ));
+        assert!(query_discovery
+            .query_builder()
+            .contains("AND (s.digest_text LIKE 'SELECT%FROM%' OR s.digest_text LIKE '%''x''%')\n"));
+    }
+
+    #[test]
+    fn query_builder_limits_the_average_rows_sent() {
+        let query_discovery =
+            QueryDiscovery::new(test_config("query_discovery_max_rows_sent = 1000"));
+        assert!(query_discovery
+            .query_builder()
+            .contains("AND s.count_star > 0 AND (s.sum_rows_sent / s.count_star) <= 1000"));
+        let query_discovery = QueryDiscovery::new(test_config(""));
+        assert!(!query_discovery
+            .query_builder()
+            .contains("s.sum_rows_sent / s.count_star"));
+    }
+
+    #[test]
+    fn sample_query_sql_reads_the_latest_event_of_the_digest() {
+        assert_eq!(
+            sample_query_sql("0x1234"),
+            "SELECT query FROM stats_mysql_query_events WHERE query_digest = '0x1234' ORDER BY start_time DESC LIMIT 1"
+        );
+        assert!(sample_query_sql("0x1'2").contains("query_digest = '0x1''2'"));
+    }
+
+    #[test]
+    fn retry_with_sample_checks_the_sample_after_a_parse_error() {
+        let fake = FakeBackend::default();
+        let readyset = FakeReadyset::default();
+        readyset.fail_on(
+            "EXPLAIN CREATE CACHE FROM SELECT * FROM t WHERE id IN (?)",
+            "Query failed to parse",
+        );
+        let mut proxysql = proxysql_with_host(&fake, &readyset, "");
+        fake.respond(
+            "FROM stats_mysql_query_events",
+            &["query"],
+            vec![vec![Value::from("SELECT * FROM t WHERE id IN (1, 2)")]],
+        );
+        let query = Query::new(
+            "SELECT * FROM t WHERE id IN (?)".to_string(),
+            "SELECT * FROM t WHERE id IN (...)".to_string(),
+            "0x1".to_string(),
+            "shop".to_string(),
+            "root".to_string(),
+            None,
+        );
+        let supported = proxysql
+            .check_query_support(99, query.get_cache_text(), "shop")
+            .unwrap();
+        assert!(supported.as_ref().is_err_and(is_parse_error));
+        let query_discovery = QueryDiscovery::new(test_config(""));
+        let mut conn = AdminConnection::new(fake.clone());
+        let mut stats = RunStats::default();
+        let (supported, sample) = query_discovery
+            .retry_with_sample(&mut proxysql, Some(&mut conn), &query, 99, &mut stats)
+            .unwrap();
+        assert!(supported.unwrap());
+        assert_eq!(
+            sample.get_cache_text(),
+            "SELECT * FROM t WHERE id IN (1, 2)"
+        );
+        assert_eq!(sample.get_digest(), "0x1");
+        assert_eq!(stats.parse_failures, 1);
+    }
+
+    #[test]
+    fn cache_concrete_queries_uses_a_sample_query() {
+        let fake = FakeBackend::default();
+        fake.respond(
+            "FROM stats_mysql_query_digest s",
+            &CANDIDATE_COLUMNS,
+            vec![
+                candidate("SELECT * FROM t WHERE id = ?", "0x1"),
+                candidate("SELECT * FROM u WHERE id = ?", "0x2"),
+            ],
+        )
+        .respond(
+            "query_digest = '0x1'",
+            &["query"],
+            vec![vec![Value::from("SELECT * FROM t WHERE id = 42")]],
+        );
+        let mut conn = AdminConnection::new(fake.clone());
+        let query_discovery = QueryDiscovery::new(test_config("cache_concrete_queries = true"));
+        let mut sample_conn = conn.connect_later();
+        let mut queries: Vec<Query> = query_discovery
+            .find_queries_to_cache(&mut conn)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        for query in queries.iter_mut() {
+            query_discovery.use_sample_query(&mut sample_conn, query);
+        }
+        assert_eq!(queries.len(), 2);
+        assert_eq!(queries[0].get_cache_text(), "SELECT * FROM t WHERE id = 42");
+        // no sample for the second query, so its digest text is cached
+        assert_eq!(queries[1].get_concrete_text(), None);
+        assert_eq!(queries[1].get_cache_text(), "SELECT * FROM u WHERE id = ?");
+        assert_eq!(
+            fake.statements_containing("FROM stats_mysql_query_events")
+                .len(),
+            2
+        );
     }
 }
@@ -0,0 +1,42 @@
+use once_cell::sync::Lazy;
+use std::sync::Mutex;
+
+/// Retries left for the current run, shared by all the retrying operations. None if unlimited.
+static REMAINING_RETRIES: Lazy<Mutex<Option<u32>>> = Lazy::new(|| Mutex::new(None));
+
+/// Sets the retry budget of the run.
+///
+/// # Arguments
+///
+/// * `max_total_retries` - The number of retries allowed across the whole run, None for unlimited.
+pub fn set_max_total_retries(max_total_retries: Option<u32>) {
+    let mut remaining = REMAINING_RETRIES.lock().unwrap();
+    *remaining = max_total_retries;
+}
+
+/// Takes one retry from the budget of the run, to be called before retrying a failed operation.
+///
+/// # Returns
+///
+/// true if the operation can be retried, false if the budget is exhausted.
+pub fn try_consume() -> bool {
+    let mut remaining = REMAINING_RETRIES.lock().unwrap();
+    match remaining.as_mut() {
+        None => true,
+        Some(0) => false,
+        Some(remaining) => {
+            *remaining -= 1;
+            true
+        }
+    }
+}
+
+/// Gets the retries left for the current run.
+///
+/// # Returns
+///
+/// The number of retries left, None if unlimited.
+pub fn get_remaining() -> Option<u32> {
+    let remaining = REMAINING_RETRIES.lock().unwrap();
+    *remaining
+}
@@ -0,0 +1,192 @@
+use chrono::Local;
+use std::collections::HashMap;
+
+use crate::{messages, state_file};
+
+/// On-disk state kept across runs of the scheduler.
+#[derive(serde::Deserialize, serde::Serialize, Default)]
+#[serde(default)]
+pub struct RunState {
+    #[serde(skip)]
+    path: String,
+    #[serde(skip)]
+    compress: bool,
+    /// Number of runs since the state file was created
+    runs: u64,
+    /// Unix timestamp of the first run
+    first_run_at: i64,
+    /// Query discovery mode the offset was saved for
+    discovery_mode: String,
+    /// Offset of the next query discovery run in the ranked candidate queries
+    discovery_offset: u16,
+    /// Unix timestamps of the status changes of the last hour, by server
+    status_changes: HashMap<String, Vec<i64>>,
+    /// Unix timestamp of when each digest was cached, for the digests still within recache_cooldown_s
+    recently_cached: HashMap<String, i64>,
+}
+
+impl RunState {
+    /// Loads the run state from disk.
+    /// If the file doesn't exist or can't be parsed, a new state starting now is returned.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the run state file.
+    /// * `compress` - Whether the state is gzipped when written, as set by state_compression.
+    ///
+    /// # Returns
+    ///
+    /// The run state.
+    pub fn load(path: &str, compress: bool) -> RunState {
+        let mut state = state_file::read(path)
+            .and_then(|contents| toml::from_str::<RunState>(&contents).ok())
+            .unwrap_or_default();
+        if state.runs == 0 {
+            state.first_run_at = Local::now().timestamp();
+        }
+        state.path = path.to_string();
+        state.compress = compress;
+        state
+    }
+
+    /// Records a new run and writes the state to disk.
+    ///
+    /// # Returns
+    ///
+    /// The number of the current run, starting at 1. An error if the state can't be written, as the run
+    /// would not be counted.
+    pub fn record_run(&mut self) -> Result<u64, String> {
+        self.runs += 1;
+        self.write()?;
+        Ok(self.runs)
+    }
+
+    /// Gets the unix timestamp of the first run.
+    pub fn get_first_run_at(&self) -> i64 {
+        self.first_run_at
+    }
+
+    /// Gets the offset saved by the previous query discovery run.
+    ///
+    /// # Arguments
+    ///
+    /// * `discovery_mode` - The current query discovery mode.
+    ///
+    /// # Returns
+    ///
+    /// The saved offset, 0 if it was saved for another query discovery mode.
+    pub fn get_discovery_offset(&self, discovery_mode: &str) -> u16 {
+        if self.discovery_mode == discovery_mode {
+            self.discovery_offset
+        } else {
+            0
+        }
+    }
+
+    /// Saves the offset the next query discovery run should resume from and writes the state to disk.
+    ///
+    /// # Arguments
+    ///
+    /// * `discovery_mode` - The current query discovery mode.
+    /// * `offset` - The offset to resume from.
+    pub fn set_discovery_offset(&mut self, discovery_mode: &str, offset: u16) {
+        self.discovery_mode = discovery_mode.to_string();
+        self.discovery_offset = offset;
+        self.save();
+    }
+
+    /// Gets the number of status changes of a server since a given time, forgetting the older ones.
+    ///
+    /// # Arguments
+    ///
+    /// * `server` - The server, as `hostgroup:hostname:port`.
+    /// * `since` - The unix timestamp to count the status changes from.
+    ///
+    /// # Returns
+    ///
+    /// The number of status changes of the server since the given time.
+    pub fn count_status_changes(&mut self, server: &str, since: i64) -> usize {
+        let Some(changes) = self.status_changes.get_mut(server) else {
+            return 0;
+        };
+        changes.retain(|changed_at| *changed_at >= since);
+        changes.len()
+    }
+
+    /// Records a status change of a server and writes the state to disk.
+    ///
+    /// # Arguments
+    ///
+    /// * `server` - The server, as `hostgroup:hostname:port`.
+    /// * `changed_at` - The unix timestamp of the status change.
+    pub fn record_status_change(&mut self, server: &str, changed_at: i64) {
+        self.status_changes
+            .entry(server.to_string())
+            .or_default()
+            .push(changed_at);
+        self.save();
+    }
+
+    /// Gets the time a query was cached, if it was cached recently.
+    ///
+    /// # Arguments
+    ///
+    /// * `digest` - The digest of the query.
+    ///
+    /// # Returns
+    ///
+    /// The unix timestamp of when the query was cached, None if it is not in the recently cached queries.
+    pub fn get_cached_at(&self, digest: &str) -> Option<i64> {
+        self.recently_cached.get(digest).copied()
+    }
+
+    /// Records a query as cached and writes the state to disk. Queries cached before the cooldown are forgotten.
+    ///
+    /// # Arguments
+    ///
+    /// * `digest` - The digest of the query.
+    /// * `cached_at` - The unix timestamp of when the query was cached.
+    /// * `cooldown_s` - The time in seconds a cached query is remembered.
+    pub fn record_cached(&mut self, digest: &str, cached_at: i64, cooldown_s: u64) {
+        let since = cached_at.saturating_sub(cooldown_s as i64);
+        self.recently_cached
+            .retain(|_, recently_cached_at| *recently_cached_at >= since);
+        self.recently_cached.insert(digest.to_string(), cached_at);
+        self.save();
+    }
+
+    /// Writes the run state to disk, warning if it can't be written.
+    fn save(&self) {
+        if let Err(err) = self.write() {
+            messages::print_warning(err.as_str());
+        }
+    }
+
+    /// Writes the run state to disk.
+    fn write(&self) -> Result<(), String> {
+        toml::to_string(self)
+            .map_err(|err| err.to_string())
+            .and_then(|contents| state_file::write(&self.path, &contents, self.compress))
+            .map_err(|err| format!("Failed to write run state {}: {}", self.path, err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_run_counts_the_runs_kept_on_disk() {
+        let path = std::env::temp_dir().join(format!("run_state_{}.state", std::process::id()));
+        let path = path.to_str().unwrap();
+        assert_eq!(RunState::load(path, false).record_run(), Ok(1));
+        assert_eq!(RunState::load(path, false).record_run(), Ok(2));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn record_run_fails_if_the_state_cant_be_written() {
+        let mut run_state = RunState::load("/nonexistent/scheduler.state", false);
+        assert!(run_state.record_run().is_err());
+    }
+}
@@ -0,0 +1,42 @@
+use once_cell::sync::Lazy;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+/// Set when SIGTERM or SIGINT is received, so the scheduler stops after the current step.
+static STOP_REQUESTED: Lazy<Arc<AtomicBool>> = Lazy::new(|| Arc::new(AtomicBool::new(false)));
+
+/// Handles SIGTERM and SIGINT by requesting the scheduler to stop instead of killing it, so it can finish
+/// the current step, such as loading and saving the query rules, and release the lock.
+/// A second signal kills the scheduler right away.
+///
+/// # Returns
+///
+/// An error if the signal handlers can't be installed.
+pub fn handle_stop_signals() -> Result<(), std::io::Error> {
+    for signal in [SIGTERM, SIGINT] {
+        signal_hook::flag::register_conditional_shutdown(signal, 1, Arc::clone(&STOP_REQUESTED))?;
+        signal_hook::flag::register(signal, Arc::clone(&STOP_REQUESTED))?;
+    }
+    Ok(())
+}
+
+/// Checks if the scheduler was asked to stop.
+///
+/// # Returns
+///
+/// true if SIGTERM or SIGINT was received.
+pub fn stop_requested() -> bool {
+    STOP_REQUESTED.load(Ordering::Relaxed)
+}
+
+/// Gets the flag set when the scheduler is asked to stop, for the loops checking it on their own.
+///
+/// # Returns
+///
+/// The flag set when SIGTERM or SIGINT is received.
+pub fn stop_flag() -> Arc<AtomicBool> {
+    Arc::clone(&STOP_REQUESTED)
+}
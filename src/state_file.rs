@@ -0,0 +1,57 @@
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use std::{
+    fs,
+    io::{Read, Write},
+};
+
+use crate::messages;
+
+/// Magic bytes at the start of a gzip file.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Reads a state file written by `write`.
+/// Gzip files are detected by their magic bytes and decompressed, so the state is kept when
+/// state_compression is changed.
+///
+/// # Arguments
+///
+/// * `path` - The path of the state file.
+///
+/// # Returns
+///
+/// The contents of the file, None if it doesn't exist or can't be decompressed.
+pub fn read(path: &str) -> Option<String> {
+    let bytes = fs::read(path).ok()?;
+    if !bytes.starts_with(&GZIP_MAGIC) {
+        return String::from_utf8(bytes).ok();
+    }
+    let mut contents = String::new();
+    match GzDecoder::new(bytes.as_slice()).read_to_string(&mut contents) {
+        Ok(_) => Some(contents),
+        Err(err) => {
+            messages::print_warning(
+                format!("Failed to decompress {}, starting over: {}", path, err).as_str(),
+            );
+            None
+        }
+    }
+}
+
+/// Writes a state file, gzipped if state_compression is enabled.
+///
+/// # Arguments
+///
+/// * `path` - The path of the state file.
+/// * `contents` - The contents to write.
+/// * `compress` - Whether to gzip the contents.
+pub fn write(path: &str, contents: &str, compress: bool) -> Result<(), String> {
+    if !compress {
+        return fs::write(path, contents).map_err(|err| err.to_string());
+    }
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    let bytes = encoder
+        .write_all(contents.as_bytes())
+        .and_then(|_| encoder.finish())
+        .map_err(|err| err.to_string())?;
+    fs::write(path, bytes).map_err(|err| err.to_string())
+}
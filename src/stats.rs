@@ -0,0 +1,92 @@
+use std::{fmt, time::Duration};
+
+/// Minimum, maximum and total duration of a repeated operation.
+#[derive(Default, Debug)]
+pub struct Timings {
+    count: u32,
+    min: Duration,
+    max: Duration,
+    total: Duration,
+}
+
+impl Timings {
+    /// Records the duration of one operation.
+    ///
+    /// # Arguments
+    ///
+    /// * `duration` - The duration of the operation.
+    pub fn record(&mut self, duration: Duration) {
+        if self.count == 0 || duration < self.min {
+            self.min = duration;
+        }
+        self.max = self.max.max(duration);
+        self.total += duration;
+        self.count += 1;
+    }
+}
+
+impl fmt::Display for Timings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.count == 0 {
+            return write!(f, "-");
+        }
+        write!(
+            f,
+            "{}/{}/{}",
+            self.min.as_millis(),
+            (self.total / self.count).as_millis(),
+            self.max.as_millis()
+        )
+    }
+}
+
+/// Counters collected during a scheduler run, reported as a single summary line at the end of the run.
+#[derive(Default, Debug)]
+pub struct RunStats {
+    /// Number of phases run, health check and query discovery, according to operation_mode
+    pub phases_run: u32,
+    /// Number of queries checked for support in Readyset
+    pub queries_evaluated: u32,
+    /// Number of queries supported by Readyset
+    pub queries_supported: u32,
+    /// Number of queries whose digest text Readyset failed to parse
+    pub parse_failures: u32,
+    /// Number of queries cached in Readyset and added as query rules
+    pub queries_cached: u32,
+    /// Number of query rules promoted from mirror to destination
+    pub rules_promoted: u32,
+    /// Number of Readyset hosts that had their status changed in ProxySQL
+    pub status_changes: u32,
+    /// Number of Readyset hosts whose status couldn't be checked
+    pub hosts_unreachable: u32,
+    /// Number of destination rules whose cache is missing on an online Readyset host
+    pub cache_drift: u32,
+    /// Time to check if a query is supported by Readyset
+    pub support_check_time: Timings,
+    /// Time to create a cache on a Readyset host
+    pub cache_creation_time: Timings,
+    /// Retries left in the max_total_retries budget at the end of the run, None if unlimited
+    pub retries_remaining: Option<u32>,
+}
+
+impl fmt::Display for RunStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Run summary: phases_run={} queries_evaluated={} queries_supported={} parse_failures={} queries_cached={} rules_promoted={} status_changes={} hosts_unreachable={} cache_drift={} support_check_ms(min/avg/max)={} cache_creation_ms(min/avg/max)={} retries_remaining={}",
+            self.phases_run,
+            self.queries_evaluated,
+            self.queries_supported,
+            self.parse_failures,
+            self.queries_cached,
+            self.rules_promoted,
+            self.status_changes,
+            self.hosts_unreachable,
+            self.cache_drift,
+            self.support_check_time,
+            self.cache_creation_time,
+            self.retries_remaining
+                .map_or("unlimited".to_string(), |remaining| remaining.to_string())
+        )
+    }
+}
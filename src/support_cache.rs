@@ -0,0 +1,140 @@
+use chrono::Local;
+use std::collections::HashMap;
+
+use crate::{messages, state_file};
+
+/// On-disk cache of the queries Readyset reported as unsupported, keyed by digest.
+/// It avoids issuing EXPLAIN CREATE CACHE FROM for the same unsupported queries on every run.
+/// The cache is discarded when a Readyset host reports a version the cache was not written for, as support
+/// may differ between versions.
+#[derive(serde::Deserialize, serde::Serialize, Default)]
+pub struct SupportCache {
+    #[serde(skip)]
+    path: String,
+    #[serde(skip)]
+    compress: bool,
+    readyset_version: String,
+    /// Digest of each unsupported query and the unix timestamp of when it was checked
+    unsupported: HashMap<String, i64>,
+}
+
+impl SupportCache {
+    /// Loads the support cache from disk.
+    /// If the file doesn't exist, can't be parsed or was written for other Readyset versions, an empty cache is returned.
+    /// Hosts whose version couldn't be fetched are left out of `readyset_version`, so the cache is kept as long as
+    /// the versions still reported were all in the versions the cache was written for.
+    /// Entries older than `recheck_s` seconds are discarded.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the support cache file.
+    /// * `readyset_version` - The distinct versions of the Readyset hosts, joined with a comma.
+    /// * `recheck_s` - Time in seconds after which an unsupported query is checked again.
+    /// * `compress` - Whether the cache is gzipped when written, as set by state_compression.
+    ///
+    /// # Returns
+    ///
+    /// The support cache.
+    pub fn load(
+        path: &str,
+        readyset_version: &str,
+        recheck_s: u64,
+        compress: bool,
+    ) -> SupportCache {
+        let mut cache = state_file::read(path)
+            .and_then(|contents| toml::from_str::<SupportCache>(&contents).ok())
+            .unwrap_or_default();
+        if !versions_known(&cache.readyset_version, readyset_version) {
+            if !cache.unsupported.is_empty() {
+                messages::print_note(
+                    format!(
+                        "Readyset version changed from {} to {}, invalidating support cache",
+                        cache.readyset_version, readyset_version
+                    )
+                    .as_str(),
+                );
+            }
+            cache.unsupported.clear();
+            cache.readyset_version = readyset_version.to_string();
+        }
+        let now = Local::now().timestamp();
+        cache
+            .unsupported
+            .retain(|_, checked_at| now - *checked_at < recheck_s as i64);
+        cache.path = path.to_string();
+        cache.compress = compress;
+        cache
+    }
+
+    /// Checks if a query is known to be unsupported.
+    ///
+    /// # Arguments
+    ///
+    /// * `digest` - The digest of the query.
+    ///
+    /// # Returns
+    ///
+    /// true if the query was found unsupported within the recheck period, false otherwise.
+    pub fn is_unsupported(&self, digest: &str) -> bool {
+        self.unsupported.contains_key(digest)
+    }
+
+    /// Records a query as unsupported.
+    ///
+    /// # Arguments
+    ///
+    /// * `digest` - The digest of the query.
+    pub fn mark_unsupported(&mut self, digest: &str) {
+        self.unsupported
+            .insert(digest.to_string(), Local::now().timestamp());
+    }
+
+    /// Writes the support cache to disk.
+    pub fn save(&self) {
+        let result = toml::to_string(self)
+            .map_err(|err| err.to_string())
+            .and_then(|contents| state_file::write(&self.path, &contents, self.compress));
+        if let Err(err) = result {
+            messages::print_warning(
+                format!("Failed to write support cache {}: {}", self.path, err).as_str(),
+            );
+        }
+    }
+}
+
+/// Checks if the cache was written for all the versions reported by the Readyset hosts.
+///
+/// # Arguments
+///
+/// * `cached` - The versions the cache was written for, joined with a comma.
+/// * `current` - The versions reported by the hosts, joined with a comma.
+///
+/// # Returns
+///
+/// true if every current version is a cached version. false if the cache is new or a version changed.
+fn versions_known(cached: &str, current: &str) -> bool {
+    if cached.is_empty() {
+        return current.is_empty();
+    }
+    let cached: Vec<&str> = cached.split(',').collect();
+    current
+        .split(',')
+        .filter(|version| !version.is_empty())
+        .all(|version| cached.contains(&version))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions_known_ignores_hosts_without_a_version() {
+        assert!(versions_known("1.2.0,1.3.0", "1.2.0,1.3.0"));
+        // a host whose version couldn't be fetched is left out of the current versions
+        assert!(versions_known("1.2.0,1.3.0", "1.3.0"));
+        assert!(versions_known("1.2.0", ""));
+        assert!(!versions_known("1.2.0", "1.2.0,1.4.0"));
+        assert!(!versions_known("1.2.0", "1.4.0"));
+        assert!(!versions_known("", "1.2.0"));
+    }
+}
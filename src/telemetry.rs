@@ -0,0 +1,201 @@
+#[cfg(feature = "otel")]
+use opentelemetry::{
+    global,
+    trace::{TraceContextExt, Tracer},
+    Context, KeyValue,
+};
+
+use crate::{config::Config, messages};
+
+/// Name of the tracer and of the service reported in the spans.
+#[cfg(feature = "otel")]
+const SERVICE_NAME: &str = "readyset_proxysql_scheduler";
+
+/// Exporter of the OpenTelemetry spans of the run, via OTLP over HTTP. The spans are flushed when the run finishes.
+/// Without the `otel` feature, spans are not created and every function is a no-op.
+pub struct Telemetry {
+    #[cfg(feature = "otel")]
+    provider: opentelemetry_sdk::trace::SdkTracerProvider,
+}
+
+impl Telemetry {
+    /// Starts exporting spans to otel_endpoint, if configured.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The scheduler configuration.
+    ///
+    /// # Returns
+    ///
+    /// The exporter, None if otel_endpoint is not configured or the exporter can't be created.
+    pub fn init(config: &Config) -> Option<Telemetry> {
+        let endpoint = config.otel_endpoint.as_deref()?;
+        #[cfg(feature = "otel")]
+        {
+            use opentelemetry_otlp::WithExportConfig;
+
+            let exporter = match opentelemetry_otlp::SpanExporter::builder()
+                .with_http()
+                .with_endpoint(endpoint)
+                .build()
+            {
+                Ok(exporter) => exporter,
+                Err(err) => {
+                    messages::print_warning(
+                        format!("Failed to create OpenTelemetry exporter: {}", err).as_str(),
+                    );
+                    return None;
+                }
+            };
+            // spans are exported as they end, the run is short and has no async runtime to batch them
+            let provider = opentelemetry_sdk::trace::SdkTracerProvider::builder()
+                .with_simple_exporter(exporter)
+                .with_resource(
+                    opentelemetry_sdk::Resource::builder()
+                        .with_service_name(SERVICE_NAME)
+                        .build(),
+                )
+                .build();
+            global::set_tracer_provider(provider.clone());
+            Some(Telemetry { provider })
+        }
+        #[cfg(not(feature = "otel"))]
+        {
+            messages::print_warning(
+                format!(
+                    "otel_endpoint is set to {}, but the scheduler was built without the otel feature",
+                    endpoint
+                )
+                .as_str(),
+            );
+            None
+        }
+    }
+
+    /// Exports the remaining spans and stops the exporter.
+    pub fn shutdown(self) {
+        #[cfg(feature = "otel")]
+        if let Err(err) = self.provider.shutdown() {
+            messages::print_warning(
+                format!("Failed to export OpenTelemetry spans: {}", err).as_str(),
+            );
+        }
+    }
+}
+
+/// Span of an operation of the run. Spans that are not ended explicitly are not exported.
+pub struct Span {
+    #[cfg(feature = "otel")]
+    cx: Context,
+}
+
+/// Keeps a span as the parent of the spans started with `Span::start` until dropped.
+pub struct SpanGuard {
+    #[cfg(feature = "otel")]
+    _guard: opentelemetry::ContextGuard,
+}
+
+impl SpanGuard {
+    /// Exits the span, restoring the previous parent of the spans started on this thread.
+    pub fn exit(self) {}
+}
+
+impl Span {
+    /// Starts a span, child of the span currently entered on this thread, if any.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the operation.
+    ///
+    /// # Returns
+    ///
+    /// The started span.
+    pub fn start(name: &'static str) -> Span {
+        #[cfg(feature = "otel")]
+        {
+            Span::start_with_parent(name, &Context::current())
+        }
+        #[cfg(not(feature = "otel"))]
+        {
+            let _ = name;
+            Span {}
+        }
+    }
+
+    /// Starts a child span of this span. Used for operations running on other threads.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the operation.
+    ///
+    /// # Returns
+    ///
+    /// The started span.
+    pub fn child(&self, name: &'static str) -> Span {
+        #[cfg(feature = "otel")]
+        {
+            Span::start_with_parent(name, &self.cx)
+        }
+        #[cfg(not(feature = "otel"))]
+        {
+            let _ = name;
+            Span {}
+        }
+    }
+
+    #[cfg(feature = "otel")]
+    fn start_with_parent(name: &'static str, parent: &Context) -> Span {
+        let span = global::tracer(SERVICE_NAME).start_with_context(name, parent);
+        Span {
+            cx: parent.with_span(span),
+        }
+    }
+
+    /// Enters the span, making it the parent of the spans started with `Span::start` on this thread.
+    ///
+    /// # Returns
+    ///
+    /// A guard that exits the span when dropped.
+    pub fn enter(&self) -> SpanGuard {
+        SpanGuard {
+            #[cfg(feature = "otel")]
+            _guard: self.cx.clone().attach(),
+        }
+    }
+
+    /// Sets a numeric attribute of the span, such as a count.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The name of the attribute.
+    /// * `value` - The value of the attribute.
+    pub fn set_count(&self, key: &'static str, value: u32) {
+        #[cfg(feature = "otel")]
+        self.cx
+            .span()
+            .set_attribute(KeyValue::new(key, value as i64));
+        #[cfg(not(feature = "otel"))]
+        let _ = (key, value);
+    }
+
+    /// Sets a text attribute of the span, such as an outcome.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The name of the attribute.
+    /// * `value` - The value of the attribute.
+    pub fn set_text(&self, key: &'static str, value: &str) {
+        #[cfg(feature = "otel")]
+        self.cx
+            .span()
+            .set_attribute(KeyValue::new(key, value.to_string()));
+        #[cfg(not(feature = "otel"))]
+        let _ = (key, value);
+    }
+
+    /// Ends the span, exporting it.
+    pub fn end(self) {
+        #[cfg(feature = "otel")]
+        self.cx.span().end();
+    }
+}
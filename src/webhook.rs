@@ -0,0 +1,230 @@
+use std::{
+    io::{Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+
+use crate::messages;
+
+/// Time to connect, send the payload and read the response of the webhook, so a slow endpoint
+/// doesn't hold the health check.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Time to post all the status changes of a health check, including the DNS lookup of the webhook host,
+/// which has no timeout of its own.
+const WEBHOOK_DEADLINE: Duration = Duration::from_secs(5);
+
+/// Change of the ProxySQL status of a Readyset server, posted to status_change_webhook.
+#[derive(serde::Serialize, Debug)]
+pub struct StatusChange {
+    pub hostgroup: u16,
+    pub hostname: String,
+    pub port: u16,
+    pub old_status: String,
+    pub new_status: String,
+    /// Status of Readyset seen by the health check: Ready, Snapshot in progress or Unreachable
+    pub readyset_status: String,
+    pub reason: String,
+}
+
+/// Endpoint of status_change_webhook. Only plain HTTP is supported.
+#[derive(Clone)]
+pub struct Webhook {
+    host: String,
+    port: u16,
+    path: String,
+    deadline: Duration,
+}
+
+impl Webhook {
+    /// Parses the URL of the webhook, in the form `http://host[:port][/path]`.
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - The URL of the webhook.
+    ///
+    /// # Returns
+    ///
+    /// The webhook, None with a warning if the URL is not a valid HTTP URL.
+    pub fn new(url: &str) -> Option<Webhook> {
+        let Some(rest) = url.strip_prefix("http://") else {
+            messages::print_warning(
+                format!(
+                    "Ignoring status_change_webhook {}, only http:// URLs are supported",
+                    url
+                )
+                .as_str(),
+            );
+            return None;
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(index) => (&rest[..index], &rest[index..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => match port.parse::<u16>() {
+                Ok(port) => (host, port),
+                Err(_) => {
+                    messages::print_warning(
+                        format!("Ignoring status_change_webhook {}, invalid port", url).as_str(),
+                    );
+                    return None;
+                }
+            },
+            None => (authority, 80),
+        };
+        if host.is_empty() {
+            messages::print_warning(
+                format!("Ignoring status_change_webhook {}, missing host", url).as_str(),
+            );
+            return None;
+        }
+        Some(Webhook {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+            deadline: WEBHOOK_DEADLINE,
+        })
+    }
+
+    /// Posts the status changes of a health check to the webhook from a background thread, so a slow or
+    /// unreachable endpoint doesn't hold the health check. The changes are posted in order, and the thread is
+    /// left behind with a warning if it doesn't finish before the deadline.
+    ///
+    /// # Arguments
+    ///
+    /// * `changes` - The status changes, in the order they were made.
+    pub fn post_all(&self, changes: Vec<StatusChange>) {
+        if changes.is_empty() {
+            return;
+        }
+        let webhook = self.clone();
+        let count = changes.len();
+        let (done, finished) = mpsc::channel();
+        thread::spawn(move || {
+            for change in changes.iter() {
+                webhook.post(change);
+            }
+            let _ = done.send(());
+        });
+        if finished.recv_timeout(self.deadline).is_err() {
+            messages::print_warning(
+                format!(
+                    "Posting {} status changes to status_change_webhook took more than {} seconds, not waiting for it",
+                    count,
+                    self.deadline.as_secs_f64()
+                )
+                .as_str(),
+            );
+        }
+    }
+
+    /// Posts a status change to the webhook as JSON. Failures are logged and otherwise ignored,
+    /// the webhook never fails the health check.
+    ///
+    /// # Arguments
+    ///
+    /// * `change` - The status change.
+    fn post(&self, change: &StatusChange) {
+        if let Err(err) = self.try_post(change) {
+            messages::print_warning(
+                format!(
+                    "Failed to post status change of {}:{} to status_change_webhook: {}",
+                    change.hostname, change.port, err
+                )
+                .as_str(),
+            );
+        }
+    }
+
+    fn try_post(&self, change: &StatusChange) -> Result<(), String> {
+        let body = serde_json::to_string(change).map_err(|err| err.to_string())?;
+        let addr = (self.host.as_str(), self.port)
+            .to_socket_addrs()
+            .map_err(|err| err.to_string())?
+            .next()
+            .ok_or_else(|| format!("{} has no address", self.host))?;
+        let mut stream =
+            TcpStream::connect_timeout(&addr, WEBHOOK_TIMEOUT).map_err(|err| err.to_string())?;
+        stream
+            .set_write_timeout(Some(WEBHOOK_TIMEOUT))
+            .and_then(|_| stream.set_read_timeout(Some(WEBHOOK_TIMEOUT)))
+            .map_err(|err| err.to_string())?;
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.path,
+            self.host,
+            self.port,
+            body.len(),
+            body
+        );
+        stream
+            .write_all(request.as_bytes())
+            .map_err(|err| err.to_string())?;
+
+        // only the status line is needed to know if the webhook accepted the change
+        let mut response = [0; 64];
+        let read = stream.read(&mut response).map_err(|err| err.to_string())?;
+        let status_line = String::from_utf8_lossy(&response[..read]);
+        match status_line.split_whitespace().nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            Some(code) => Err(format!("HTTP status {}", code)),
+            None => Err("invalid HTTP response".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::TcpListener, time::Instant};
+
+    fn change(hostname: &str) -> StatusChange {
+        StatusChange {
+            hostgroup: 99,
+            hostname: hostname.to_string(),
+            port: 3307,
+            old_status: "ONLINE".to_string(),
+            new_status: "SHUNNED".to_string(),
+            readyset_status: "Unreachable".to_string(),
+            reason: "connection refused".to_string(),
+        }
+    }
+
+    #[test]
+    fn post_all_posts_every_change_in_order() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for _ in 0..2 {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = [0; 4096];
+                let read = stream.read(&mut request).unwrap();
+                requests.push(String::from_utf8_lossy(&request[..read]).to_string());
+                stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n").unwrap();
+            }
+            requests
+        });
+        let webhook = Webhook::new(&format!("http://127.0.0.1:{}/hook", port)).unwrap();
+        webhook.post_all(vec![change("readyset1"), change("readyset2")]);
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("POST /hook HTTP/1.1"));
+        assert!(requests[0].contains("\"hostname\":\"readyset1\""));
+        assert!(requests[1].contains("\"hostname\":\"readyset2\""));
+    }
+
+    #[test]
+    fn post_all_stops_waiting_at_the_deadline() {
+        // the connection is accepted by the backlog, but no response is ever sent
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut webhook = Webhook::new(&format!("http://127.0.0.1:{}", port)).unwrap();
+        webhook.deadline = Duration::from_millis(100);
+        let start = Instant::now();
+        webhook.post_all(vec![change("readyset1")]);
+        assert!(start.elapsed() < WEBHOOK_TIMEOUT);
+    }
+}